@@ -0,0 +1,37 @@
+use std::process::Command;
+
+fn main() {
+    // Capture the git revision and compiler version so they can be exported as build info
+    let revision = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=GIT_REVISION={}", revision);
+    println!("cargo:rustc-env=RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // Generate the grpc server, with a vendored protoc so building does not need one installed
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().expect("vendored protoc"));
+        tonic_build::configure()
+            .build_client(false)
+            .compile(&["proto/billing.proto"], &["proto"])
+            .expect("compiling proto/billing.proto");
+    }
+}
@@ -0,0 +1,53 @@
+use axum::{
+    extract::ConnectInfo,
+    http::{header, Request},
+    middleware::Next,
+    response::IntoResponse,
+};
+use chrono::Utc;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::audit::JsonLog;
+
+// Health probes would drown out everything else
+const SKIPPED_PATHS: &[&str] = &["/health", "/healthz"];
+
+// One json line per request served
+#[derive(Serialize, Debug)]
+pub struct AccessEntry {
+    pub timestamp: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub latency_ms: u128,
+    pub remote_addr: Option<String>,
+    pub forwarded_for: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+pub async fn log_access<B>(access_log: JsonLog, req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    if SKIPPED_PATHS.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    let start = Instant::now();
+    let header = |name: header::HeaderName| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let mut entry = AccessEntry {
+        timestamp: Utc::now().to_rfc3339(),
+        method: req.method().to_string(),
+        path: req.uri().path().to_string(),
+        status: 0,
+        latency_ms: 0,
+        remote_addr: req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.to_string()),
+        forwarded_for: header(header::HeaderName::from_static("x-forwarded-for")),
+        user_agent: header(header::USER_AGENT),
+    };
+
+    let response = next.run(req).await;
+    entry.status = response.status().as_u16();
+    entry.latency_ms = start.elapsed().as_millis();
+    access_log.record(&entry);
+    response
+}
@@ -0,0 +1,95 @@
+use serde::Serialize;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+// Lines queued for the writer before new ones are dropped
+const QUEUE: usize = 1024;
+
+// One json line per billing api call
+#[derive(Serialize, Debug)]
+pub struct AuditEntry<'a> {
+    pub timestamp: String,
+    pub org_id: Option<&'a str>,
+    pub endpoint: &'a str,
+    pub request_id: &'a str,
+    pub trace_id: Option<&'a str>,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub latency_ms: u128,
+}
+
+// Append only json lines written to a file or stdout, for the audit log of every request made
+// against the billing api and for the access log of requests served. Each log has its own
+// background thread doing the writing, so a slow disk or a blocked stdout never stalls the runtime
+#[derive(Clone)]
+pub struct JsonLog {
+    target: String,
+    sender: SyncSender<Vec<u8>>,
+    // Counter of the entries dropped or failing to be written
+    errors: &'static str,
+}
+
+impl fmt::Debug for JsonLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JsonLog").field("target", &self.target).finish()
+    }
+}
+
+impl JsonLog {
+    pub fn audit(target: &str) -> io::Result<Self> {
+        Self::new(target, "audit-log", "elastic_billing_audit_log_errors_total")
+    }
+
+    pub fn access(target: &str) -> io::Result<Self> {
+        Self::new(target, "access-log", "elastic_billing_access_log_errors_total")
+    }
+
+    // "-" writes to stdout, anything else is a file opened for appending
+    fn new(target: &str, name: &str, errors: &'static str) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = match target {
+            "-" => Box::new(io::stdout()),
+            path => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        };
+        let (sender, receiver) = mpsc::sync_channel(QUEUE);
+        let thread_target = target.to_string();
+        std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || write_lines(&thread_target, writer, receiver, errors))?;
+        Ok(JsonLog {
+            target: target.to_string(),
+            sender,
+            errors,
+        })
+    }
+
+    pub fn record<T: Serialize>(&self, entry: &T) {
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        line.push(b'\n');
+
+        // Drop the entry rather than wait when the writer has fallen behind
+        if let Err(e) = self.sender.try_send(line) {
+            let reason = match e {
+                TrySendError::Full(_) => "writer is falling behind",
+                TrySendError::Disconnected(_) => "writer has stopped",
+            };
+            log::error!("{{\"fn\": \"record\", \"msg\": \"dropped log entry\", \"target\": \"{}\", \"error\": \"{}\"}}", self.target, reason);
+            metrics::increment_counter!(self.errors);
+        }
+    }
+}
+
+// Write each entry straight through, so little is lost if the exporter dies,
+// until every handle to the log is dropped
+fn write_lines(target: &str, mut writer: Box<dyn Write + Send>, receiver: Receiver<Vec<u8>>, errors: &'static str) {
+    for line in receiver {
+        if let Err(e) = writer.write_all(&line).and_then(|_| writer.flush()) {
+            log::error!("{{\"fn\": \"write_lines\", \"msg\": \"failed writing log\", \"target\": \"{}\", \"error\": \"{}\"}}", target, e);
+            metrics::increment_counter!(errors);
+        }
+    }
+}
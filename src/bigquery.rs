@@ -0,0 +1,202 @@
+use chrono::Utc;
+use metrics::Label;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::sink::Sink;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const ENDPOINT: &str = "https://bigquery.googleapis.com";
+const SCOPE: &str = "https://www.googleapis.com/auth/bigquery.insertdata";
+
+// The parts of a google service account key file needed to get access tokens
+#[derive(Deserialize)]
+struct KeyFile {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+struct ServiceAccount {
+    key: KeyFile,
+    token: Mutex<Option<(String, i64)>>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+impl ServiceAccount {
+    // Reuse the access token until a minute before it expires
+    async fn token(&self, client: &reqwest::Client) -> BoxResult<String> {
+        let now = Utc::now().timestamp();
+        if let Some((token, expires)) = self.token.lock().unwrap().as_ref() {
+            if *expires - 60 > now {
+                return Ok(token.clone());
+            }
+        }
+
+        let response: TokenResponse = client
+            .post(&self.key.token_uri)
+            .form(&[("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"), ("assertion", &self.assertion(now)?)])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.without_url())?
+            .json()
+            .await?;
+        *self.token.lock().unwrap() = Some((response.access_token.clone(), now + response.expires_in));
+        Ok(response.access_token)
+    }
+
+    // Signed jwt exchanged for an access token, as in google's server to server oauth flow
+    fn assertion(&self, now: i64) -> BoxResult<String> {
+        let header = json!({ "alg": "RS256", "typ": "JWT" });
+        let claims = json!({
+            "iss": self.key.client_email,
+            "scope": SCOPE,
+            "aud": self.key.token_uri,
+            "iat": now,
+            "exp": now + 3600,
+        });
+        let unsigned = format!("{}.{}", encode(&serde_json::to_vec(&header)?), encode(&serde_json::to_vec(&claims)?));
+
+        let key = PKey::private_key_from_pem(self.key.private_key.as_bytes())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(unsigned.as_bytes())?;
+        Ok(format!("{}.{}", unsigned, encode(&signer.sign_to_vec()?)))
+    }
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+// Streams every series of a poll into a bigquery table with the columns
+// timestamp TIMESTAMP, name STRING, kind STRING, value FLOAT64 and labels JSON
+pub struct BigQuerySink {
+    url: String,
+    table: String,
+    account: Arc<ServiceAccount>,
+    client: reqwest::Client,
+    rows: Mutex<Vec<Row>>,
+}
+
+impl fmt::Debug for BigQuerySink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BigQuerySink").field("table", &self.table).finish()
+    }
+}
+
+#[derive(Serialize)]
+struct Row {
+    json: RowData,
+}
+
+#[derive(Serialize)]
+struct RowData {
+    timestamp: String,
+    name: &'static str,
+    kind: &'static str,
+    value: f64,
+    labels: String,
+}
+
+#[derive(Deserialize)]
+struct InsertResponse {
+    #[serde(default, rename = "insertErrors")]
+    insert_errors: Vec<Value>,
+}
+
+impl BigQuerySink {
+    // Credentials default to the key file in GOOGLE_APPLICATION_CREDENTIALS, and the endpoint can point at an emulator
+    pub fn new(project: &str, dataset: &str, table: &str, credentials: Option<&str>, endpoint: Option<&str>, timeout: u64) -> BoxResult<Self> {
+        let path = match credentials {
+            Some(path) => path.to_string(),
+            None => std::env::var("GOOGLE_APPLICATION_CREDENTIALS").map_err(|_| "bigquery sink requires credentials or GOOGLE_APPLICATION_CREDENTIALS")?,
+        };
+        let key: KeyFile = serde_json::from_slice(&fs::read(&path)?)?;
+
+        Ok(BigQuerySink {
+            url: format!(
+                "{}/bigquery/v2/projects/{}/datasets/{}/tables/{}/insertAll",
+                endpoint.unwrap_or(ENDPOINT).trim_end_matches('/'),
+                project,
+                dataset,
+                table
+            ),
+            table: format!("{}.{}.{}", project, dataset, table),
+            account: Arc::new(ServiceAccount { key, token: Mutex::new(None) }),
+            client: reqwest::Client::builder().timeout(Duration::from_secs(timeout)).build()?,
+            rows: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn push(&self, name: &'static str, kind: &'static str, value: f64, labels: &[Label]) {
+        let labels: BTreeMap<&str, &str> = labels.iter().map(|l| (l.key(), l.value())).collect();
+        self.rows.lock().unwrap().push(Row {
+            json: RowData {
+                timestamp: Utc::now().to_rfc3339(),
+                name,
+                kind,
+                value,
+                labels: serde_json::to_string(&labels).unwrap_or_default(),
+            },
+        });
+    }
+
+    async fn insert(client: reqwest::Client, account: Arc<ServiceAccount>, url: String, rows: Vec<Row>) -> BoxResult<()> {
+        let token = account.token(&client).await?;
+        let response: InsertResponse = client
+            .post(url)
+            .bearer_auth(token)
+            .json(&json!({ "rows": rows }))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.without_url())?
+            .json()
+            .await?;
+        match response.insert_errors.len() {
+            0 => Ok(()),
+            count => Err(format!("{} rows were rejected", count).into()),
+        }
+    }
+}
+
+impl Sink for BigQuerySink {
+    fn gauge(&self, name: &'static str, value: f64, labels: &[Label]) {
+        self.push(name, "gauge", value, labels);
+    }
+
+    fn counter(&self, name: &'static str, value: u64, labels: &[Label]) {
+        self.push(name, "counter", value as f64, labels);
+    }
+
+    fn flush(&self) {
+        let rows = std::mem::take(&mut *self.rows.lock().unwrap());
+        if rows.is_empty() {
+            return;
+        }
+
+        let (client, account, url, table) = (self.client.clone(), self.account.clone(), self.url.clone(), self.table.clone());
+        tokio::spawn(async move {
+            if let Err(e) = Self::insert(client, account, url, rows).await {
+                log::error!("{{\"fn\": \"flush\", \"sink\": \"bigquery\", \"table\": \"{}\", \"error\": \"{}\"}}", table, e);
+                metrics::increment_counter!("elastic_billing_sink_errors_total", "sink" => "bigquery");
+            }
+        });
+    }
+}
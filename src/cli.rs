@@ -0,0 +1,966 @@
+use axum::{
+    middleware::self,
+    handler::Handler,
+    routing::{get, post},
+    Router,
+    extract::Extension
+};
+use chrono::{Datelike, Local, NaiveDate, Utc};
+use clap::{crate_name, crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use env_logger::{Builder, Target};
+use log::LevelFilter;
+use std::future::Future;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tower_http::auth::RequireAuthorizationLayer;
+use tower_http::trace::TraceLayer;
+
+use crate::metrics::{setup_metrics_recorder, track_metrics};
+use crate::{export, report, systemd};
+use crate::access::log_access;
+use crate::audit::JsonLog;
+use crate::config::Config;
+use crate::cost_centers::CostCenters;
+use crate::dashboard::dashboard;
+use crate::dump::table;
+use crate::error::Error as RestError;
+use crate::events::ws_events;
+use crate::export::Export;
+use crate::handlers::{admin_pause, admin_poll, admin_resume, config, costs, diff, handler_404, health, help, history, openapi, root, metrics, stream};
+use crate::history::{prune, HistoryEntry};
+use crate::leader::LeaderElection;
+use crate::report::Report;
+use crate::rules::{prometheus_rule, rules};
+use crate::settings::Settings;
+use crate::state::State;
+use crate::store::Store;
+
+// Older ELASTIC_BILLING_EXPORTER_* names, for the options whose ECBE_* name is not just the same suffix
+const LEGACY_ENV: &[(&str, &str)] = &[
+    ("LISTEN_PORT", "PORT"),
+    ("FALLBACK_URLS", "FALLBACK_URL"),
+    ("HEADERS", "HEADER"),
+    ("REVERSE_PROXY", "URL"),
+];
+
+// Copy the older ELASTIC_BILLING_EXPORTER_* variables to their ECBE_* names, which win when both are set.
+// This runs before the runtime starts, while the process is still single threaded
+fn legacy_env() {
+    for (key, value) in std::env::vars_os() {
+        let suffix = match key.to_str().and_then(|k| k.strip_prefix("ELASTIC_BILLING_EXPORTER_")) {
+            Some(suffix) => suffix,
+            None => continue,
+        };
+        let suffix = LEGACY_ENV.iter().find(|(old, _)| *old == suffix).map_or(suffix, |(_, new)| new);
+        let name = format!("ECBE_{}", suffix);
+        if std::env::var_os(&name).is_none() {
+            std::env::set_var(name, value);
+        }
+    }
+}
+
+// Every option, switch and subcommand of the exporter
+pub fn app() -> App<'static, 'static> {
+    App::new(crate_name!())
+        .version(crate_version!())
+        .author("")
+        .about(crate_name!())
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .help("Set port to listen on")
+                .env("ECBE_PORT")
+                .default_value("8080")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("grpc_port")
+                .long("grpc-port")
+                .help("Set port to serve the grpc api on, which requires building with the grpc feature")
+                .env("ECBE_GRPC_PORT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .short("t")
+                .long("timeout")
+                .help("Set default global timeout")
+                .default_value("60")
+                .env("ECBE_TIMEOUT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cors_origins")
+                .long("cors-origins")
+                .help("Set comma separated origins allowed to call the /api/v1 endpoints from a browser, or * for any")
+                .env("ECBE_CORS_ORIGINS")
+                .use_delimiter(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cors_methods")
+                .long("cors-methods")
+                .help("Set comma separated methods allowed for cross origin requests")
+                .default_value("GET")
+                .env("ECBE_CORS_METHODS")
+                .use_delimiter(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cost_centers")
+                .long("cost-centers")
+                .help("Set csv or yaml file mapping deployment ids to cost centers")
+                .env("ECBE_COST_CENTERS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cost_centers_reload")
+                .long("cost-centers-reload")
+                .help("Set seconds between checks of the cost centers file for changes")
+                .default_value("60")
+                .env("ECBE_COST_CENTERS_RELOAD")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cost_by_kind")
+                .long("cost-by-kind")
+                .help("Also query the itemized costs of every deployment, to split them by product")
+                .env("ECBE_COST_BY_KIND")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("data_dir")
+                .long("data-dir")
+                .help("Set directory to persist state across restarts")
+                .env("ECBE_DATA_DIR")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fallback_url")
+                .long("fallback-url")
+                .help("Set fallback url to fail over to, in order")
+                .env("ECBE_FALLBACK_URL")
+                .multiple(true)
+                .number_of_values(1)
+                .value_delimiter(",")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("failover_after")
+                .long("failover-after")
+                .help("Set consecutive failed requests before failing over to the next url")
+                .default_value("3")
+                .env("ECBE_FAILOVER_AFTER")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("header")
+                .long("header")
+                .help("Set extra header as \"Name: value\" on every billing api request")
+                .env("ECBE_HEADER")
+                .multiple(true)
+                .number_of_values(1)
+                .value_delimiter(";")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("history_retention")
+                .long("history-retention")
+                .help("Set days of history to keep in the data directory, 0 keeps it forever")
+                .default_value("0")
+                .env("ECBE_HISTORY_RETENTION")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("idle_timeout")
+                .long("idle-timeout")
+                .help("Set seconds after which series that stop being updated are removed, 0 uses twice the interval")
+                .default_value("0")
+                .env("ECBE_IDLE_TIMEOUT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .short("i")
+                .long("interval")
+                .help("Set interval in seconds to poll billing api in the background, 0 polls on every scrape")
+                .default_value("0")
+                .env("ECBE_INTERVAL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stale_intervals")
+                .long("stale-intervals")
+                .help("Set number of poll intervals without a successful poll before reporting unhealthy")
+                .default_value("3")
+                .env("ECBE_STALE_INTERVALS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("warm_up")
+                .long("warm-up")
+                .help("Set seconds to wait for a first background poll before serving, 0 serves right away")
+                .default_value("0")
+                .env("ECBE_WARM_UP")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("access_log")
+                .long("access-log")
+                .help("Append a json line for every request served to this file, or - for stdout")
+                .env("ECBE_ACCESS_LOG")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("admin_token")
+                .long("admin-token")
+                .help("Set bearer token required by the /admin endpoints, which are disabled without one")
+                .env("ECBE_ADMIN_TOKEN")
+                .hide_env_values(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("api_version")
+                .long("api-version")
+                .help("Set billing api version to use")
+                .possible_values(&["auto", "v1", "v2"])
+                .default_value("auto")
+                .env("ECBE_API_VERSION")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("audit_log")
+                .long("audit-log")
+                .help("Append a json line for every billing api call to this file, or - for stdout")
+                .env("ECBE_AUDIT_LOG")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("charts_aggregation")
+                .long("charts-aggregation")
+                .help("Set how the hourly rate is taken from the returned charts buckets")
+                .possible_values(&["latest", "mean", "max"])
+                .default_value("latest")
+                .env("ECBE_CHARTS_AGGREGATION")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("charts_bucket")
+                .long("charts-bucket")
+                .help("Set charts bucketing strategy passed to the billing api, overriding the config file")
+                .possible_values(&["hourly", "daily"])
+                .env("ECBE_CHARTS_BUCKET")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("charts_window")
+                .long("charts-window")
+                .help("Set seconds of charts history to request from the billing api")
+                .default_value("3600")
+                .env("ECBE_CHARTS_WINDOW")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("Set config file with organizations to export")
+                .env("ECBE_CONFIG")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("invoices")
+                .long("invoices")
+                .help("Set number of most recent invoices to export")
+                .default_value("0")
+                .env("ECBE_INVOICES")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("k8s_secret")
+                .long("k8s-secret")
+                .help("Read the billing api key from a kubernetes secret, given as namespace/name/key")
+                .env("ECBE_K8S_SECRET")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("k8s_secret_reload")
+                .long("k8s-secret-reload")
+                .help("Set seconds between reads of the kubernetes secret for a rotated key")
+                .default_value("300")
+                .env("ECBE_K8S_SECRET_RELOAD")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("leader_election")
+                .long("leader-election")
+                .help("Set kubernetes lease as namespace/name, so only the leader replica polls")
+                .env("ECBE_LEADER_ELECTION")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("lease_duration")
+                .long("lease-duration")
+                .help("Set leader election lease duration in seconds")
+                .default_value("15")
+                .env("ECBE_LEASE_DURATION")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("user_agent")
+                .long("user-agent")
+                .help("Set user agent on billing api requests")
+                .default_value(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+                .env("ECBE_USER_AGENT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("week_to_date")
+                .long("week-to-date")
+                .help("Also query costs since monday")
+                .env("ECBE_WEEK_TO_DATE")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("poll_webhook")
+                .long("poll-webhook")
+                .help("Set url to post a json summary of every poll to")
+                .env("ECBE_POLL_WEBHOOK")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("topology")
+                .long("topology")
+                .help("Also query the size of every deployment, for the cost per gb of ram")
+                .env("ECBE_TOPOLOGY")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("quarter_to_date")
+                .long("quarter-to-date")
+                .help("Also query costs since the start of the quarter")
+                .env("ECBE_QUARTER_TO_DATE")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("redis_url")
+                .long("redis-url")
+                .help("Set redis url to share the leader's snapshot with the other replicas, as redis://:password@host:6379/0")
+                .env("ECBE_REDIS_URL")
+                .hide_env_values(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("redis_key")
+                .long("redis-key")
+                .help("Set redis key the shared snapshot is stored under")
+                .default_value("elastic-billing-exporter:snapshot")
+                .env("ECBE_REDIS_KEY")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mask_names")
+                .long("mask-names")
+                .help("Set whether to hash or truncate deployment names in labels, overriding the config file")
+                .possible_values(&["hash", "truncate"])
+                .env("ECBE_MASK_NAMES")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mask_secret")
+                .long("mask-secret")
+                .help("Set secret key hashed names are derived with, overriding the config file")
+                .env("ECBE_MASK_SECRET")
+                .hide_env_values(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("round_costs")
+                .long("round-costs")
+                .help("Set decimal places to round exported costs to, overriding the config file")
+                .env("ECBE_ROUND_COSTS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rounding")
+                .long("rounding")
+                .help("Set how exported costs are rounded, overriding the config file")
+                .possible_values(&["half_up", "half_down", "half_even", "up", "down"])
+                .env("ECBE_ROUNDING")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metadata")
+                .long("metadata")
+                .help("Set csv, json or yaml file mapping deployment ids to extra labels")
+                .env("ECBE_METADATA")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metadata_reload")
+                .long("metadata-reload")
+                .help("Set seconds between checks of the metadata file for changes")
+                .default_value("60")
+                .env("ECBE_METADATA_RELOAD")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("anomaly_ratio")
+                .long("anomaly-ratio")
+                .help("Set ratio of a deployment's hourly rate over the previous poll to send an anomaly event on /ws/events")
+                .default_value("1.5")
+                .env("ECBE_ANOMALY_RATIO")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_body_size")
+                .long("max-body-size")
+                .help("Set largest billing api response body in bytes")
+                .default_value("16777216")
+                .env("ECBE_MAX_BODY_SIZE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_retries")
+                .long("max-retries")
+                .help("Set how many times a throttled or failing billing api call is retried")
+                .default_value("3")
+                .env("ECBE_MAX_RETRIES")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retry_statuses")
+                .long("retry-statuses")
+                .help("Set comma separated response statuses retried besides 429, empty to retry none")
+                .default_value("500,502,503,504")
+                .env("ECBE_RETRY_STATUSES")
+                .use_delimiter(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stable_ids")
+                .long("stable-ids")
+                .help("Leave the name label off deployment series, exporting it on an info metric instead")
+                .env("ECBE_STABLE_IDS")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("startup_check")
+                .long("startup-check")
+                .help("Set whether to fail, retry or skip checking the billing api at startup")
+                .possible_values(&["fail", "retry", "skip"])
+                .default_value("skip")
+                .env("ECBE_STARTUP_CHECK")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fail_on_empty")
+                .long("fail-on-empty")
+                .help("Exit when the startup check finds an organization without deployments")
+                .env("ECBE_FAIL_ON_EMPTY")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("strict_parsing")
+                .long("strict-parsing")
+                .help("Fail polls when billing api responses have unknown fields")
+                .env("ECBE_STRICT_PARSING")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("top_deployments")
+                .long("top-deployments")
+                .help("Set number of most expensive deployments to export by rank")
+                .default_value("0")
+                .env("ECBE_TOP_DEPLOYMENTS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("worker_threads")
+                .long("worker-threads")
+                .help("Set number of runtime worker threads, defaults to one per cpu core")
+                .env("ECBE_WORKER_THREADS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("graphql")
+                .long("graphql")
+                .help("Serve graphql queries over the snapshot and history on /graphql, requires the graphql feature")
+                .env("ECBE_GRAPHQL")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("pprof")
+                .long("pprof")
+                .help("Serve cpu and heap profiles under /debug/pprof behind the admin token, requires the profiling feature")
+                .env("ECBE_PPROF")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("url")
+                .short("u")
+                .long("url")
+                .help("Set elastic reverse proxy")
+                .required_unless("config")
+                .env("ECBE_URL")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("gen-dashboard")
+                .about("Print a grafana dashboard for the exported metrics"),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Poll the billing api once and print the costs")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("Set output format")
+                        .possible_values(&["json", "table"])
+                        .default_value("json")
+                        .env("ECBE_DUMP_FORMAT")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("report")
+                .about("Print monthly spend per group or cost center from the history in the data directory")
+                .arg(
+                    Arg::with_name("month")
+                        .long("month")
+                        .help("Set month to report on as YYYY-MM, defaults to the previous month")
+                        .env("ECBE_REPORT_MONTH")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("by")
+                        .long("by")
+                        .help("Set what to aggregate spend by")
+                        .possible_values(report::REPORT_BY)
+                        .default_value("group")
+                        .env("ECBE_REPORT_BY")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("Set output format")
+                        .possible_values(report::REPORT_FORMATS)
+                        .default_value("json")
+                        .env("ECBE_REPORT_FORMAT")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("webhook")
+                        .long("webhook")
+                        .help("Set url to post the report to")
+                        .env("ECBE_REPORT_WEBHOOK")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Run the exports from the config file for a single day")
+                .arg(
+                    Arg::with_name("date")
+                        .long("date")
+                        .help("Set day to export as YYYY-MM-DD, defaults to yesterday")
+                        .env("ECBE_EXPORT_DATE")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gen-rules")
+                .about("Print prometheus alerting rules for the exported metrics")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("Set output format")
+                        .possible_values(&["rules", "prometheus-rule"])
+                        .default_value("rules")
+                        .env("ECBE_GEN_RULES_FORMAT")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("job")
+                        .long("job")
+                        .help("Set prometheus job scraping the exporter")
+                        .default_value("elastic-cloud-billing-exporter")
+                        .env("ECBE_GEN_RULES_JOB")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("stale_after")
+                        .long("stale-after")
+                        .help("Set seconds without a successful poll before alerting")
+                        .default_value("3600")
+                        .env("ECBE_GEN_RULES_STALE_AFTER")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("spike_ratio")
+                        .long("spike-ratio")
+                        .help("Set ratio over the daily average hourly rate to alert on")
+                        .default_value("1.5")
+                        .env("ECBE_GEN_RULES_SPIKE_RATIO")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("service")
+                .about("Install, uninstall or run the exporter as a windows service")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("install")
+                        .about("Install a service running the exporter with the options given before service"),
+                )
+                .subcommand(SubCommand::with_name("uninstall").about("Stop and remove the service"))
+                .subcommand(
+                    SubCommand::with_name("run")
+                        .about("Run as the service, which is how the service control manager starts the exporter"),
+                ),
+        )
+}
+
+// Parse the command line and run the exporter or one of its subcommands
+pub fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    legacy_env();
+
+    let opts = app().get_matches();
+
+    // Validate every option before doing anything else
+    let settings = match Settings::from_matches(&opts) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // The windows service control manager runs the exporter through service run
+    #[cfg(windows)]
+    if let Some(sub) = opts.subcommand_matches("service") {
+        return match sub.subcommand_name() {
+            Some("install") => crate::service::install(&opts),
+            Some("uninstall") => crate::service::uninstall(),
+            _ => crate::service::run(opts, settings),
+        };
+    }
+    #[cfg(not(windows))]
+    if opts.subcommand_matches("service").is_some() {
+        return Err("the service subcommand is only supported on windows".into());
+    }
+
+    runtime(&settings)?.block_on(run(opts, settings, || (), std::future::pending()))
+}
+
+// Build the runtime ourselves, so small sidecars can run on a single worker thread
+pub(crate) fn runtime(settings: &Settings) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = settings.worker_threads {
+        runtime.worker_threads(threads);
+    }
+    runtime.build()
+}
+
+// Run the subcommand or serve until shutdown completes, calling ready once the port is bound
+pub(crate) async fn run(
+    opts: ArgMatches<'static>,
+    settings: Settings,
+    ready: impl FnOnce(),
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Generate a dashboard and exit
+    if opts.subcommand_matches("gen-dashboard").is_some() {
+        println!("{}", serde_json::to_string_pretty(&dashboard())?);
+        return Ok(());
+    }
+
+    // Generate alerting rules using the budget from the config file and exit
+    if let Some(sub) = opts.subcommand_matches("gen-rules") {
+        let config = match &settings.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        let mut output = rules(
+            sub.value_of("job").unwrap(),
+            config.budget,
+            !config.budgets.is_empty(),
+            settings.rules_stale_after,
+            settings.rules_spike_ratio,
+        );
+        if sub.value_of("format") == Some("prometheus-rule") {
+            output = prometheus_rule(output);
+        }
+        print!("{}", serde_yaml::to_string(&output)?);
+        return Ok(());
+    }
+
+    // Initialize log Builder, unless running as a windows service which logs to the event log
+    if opts.subcommand_name() != Some("service") {
+        Builder::new()
+            .format(|buf, record| {
+                writeln!(
+                    buf,
+                    "{{\"date\": \"{}\", \"level\": \"{}\", \"log\": {}}}",
+                    Local::now().format("%Y-%m-%dT%H:%M:%S:%f"),
+                    record.level(),
+                    record.args()
+                )
+            })
+            .target(match opts.subcommand_name() {
+                Some(_) => Target::Stderr,
+                None => Target::Stdout,
+            })
+            .filter_level(LevelFilter::Info)
+            .parse_default_env()
+            .init();
+    }
+
+    // Poll once and print the costs
+    if let Some(sub) = opts.subcommand_matches("dump") {
+        let state = State::new(&settings).await?;
+        let snapshot = state.get_snapshot().await?;
+        match sub.value_of("format") {
+            Some("table") => print!("{}", table(&snapshot)),
+            _ => println!("{}", serde_json::to_string_pretty(&snapshot)?),
+        }
+        return Ok(());
+    }
+
+    // Aggregate the persisted history into a monthly report
+    if let Some(sub) = opts.subcommand_matches("report") {
+        let store = match &settings.data_dir {
+            Some(dir) => Store::new(dir)?,
+            None => return Err("report requires --data-dir".into()),
+        };
+        let config = match &settings.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        let cost_centers = match &settings.cost_centers {
+            Some(path) => Some(CostCenters::new(path)?),
+            None => None,
+        };
+        let (year, month) = match sub.value_of("month") {
+            Some(month) => {
+                let date = NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")?;
+                (date.year(), date.month())
+            }
+            None => report::previous_month(Utc::now()),
+        };
+
+        let entries: Vec<HistoryEntry> = store.read_lines("history.jsonl", |_: &HistoryEntry| true).await?;
+        let report = Report::new(&entries, year, month, sub.value_of("by").unwrap(), &config, cost_centers.as_ref());
+        let (output, content_type) = report.render(sub.value_of("format").unwrap_or("json"));
+        print!("{}", output);
+
+        if let Some(webhook) = sub.value_of("webhook") {
+            report::post(webhook, output, content_type, settings.timeout).await?;
+        }
+        return Ok(());
+    }
+
+    // Run the exports from the config file for a single day
+    if let Some(sub) = opts.subcommand_matches("export") {
+        let state = State::new(&settings).await?;
+        if state.store.is_none() {
+            return Err("export requires --data-dir".into());
+        }
+        let day = match sub.value_of("date") {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")?,
+            None => (Utc::now() - chrono::Duration::days(1)).date().naive_utc(),
+        };
+        let exports = state.config.exports.iter().map(|job| Export::new(&job.export, state.timeout)).collect::<Result<Vec<_>, _>>()?;
+        export::run(&state, &exports, day).await;
+        return Ok(());
+    }
+
+    // Create state for axum
+    let state = State::new(&settings).await?;
+
+    // Check the billing api before serving, either crashing or retrying with backoff on failure.
+    // An empty organization will not fix itself, so it always crashes
+    let fatal = |e: RestError| {
+        log::error!("{{\"fn\": \"main\", \"msg\": \"startup check failed\", \"error\": {}}}", e.json());
+        e
+    };
+    match settings.startup_check.as_str() {
+        "fail" => state.check().await.map_err(fatal)?,
+        "retry" => {
+            let mut backoff = Duration::from_secs(1);
+            while let Err(e) = state.check().await {
+                if let RestError::Empty(_) = e {
+                    return Err(fatal(e).into());
+                }
+                log::error!("{{\"fn\": \"main\", \"msg\": \"startup check failed, retrying in {}s\", \"error\": {}}}", backoff.as_secs(), e.json());
+                tokio::time::sleep(backoff).await;
+                backoff = std::cmp::min(backoff * 2, Duration::from_secs(60));
+            }
+        }
+        _ if settings.fail_on_empty => state.check().await.map_err(fatal)?,
+        _ => (),
+    }
+
+    // Create prometheus handle, by default keeping series around until the next background poll
+    let idle_timeout = match settings.idle_timeout {
+        0 => Duration::from_secs(std::cmp::max(10, state.interval * 2)),
+        seconds => Duration::from_secs(seconds),
+    };
+    let recorder_handle = setup_metrics_recorder(idle_timeout);
+
+    // Only poll while holding the lease when running as a replica pair. The first round runs
+    // before the warm up, so a replica that takes the lease polls instead of reading redis
+    if let Some(lease) = &settings.leader_election {
+        let election = LeaderElection::new(lease, settings.lease_duration, state.leader.clone(), state.last_success.clone())?;
+        election.elect().await;
+        tokio::spawn(async move { election.run().await });
+    }
+
+    // Reload the cost centers file when it changes
+    if let Some(cost_centers) = state.cost_centers.clone() {
+        let reload = Duration::from_secs(settings.cost_centers_reload);
+        tokio::spawn(async move { cost_centers.watch(reload).await });
+    }
+    if let Some(secret) = state.k8s_secret.clone() {
+        let reload = Duration::from_secs(settings.k8s_secret_reload);
+        tokio::spawn(async move { secret.watch(reload).await });
+    }
+    if let Some(metadata) = state.metadata.clone() {
+        let reload = Duration::from_secs(settings.metadata_reload);
+        tokio::spawn(async move { metadata.watch(reload).await });
+    }
+
+    // Drop history older than the retention once a day
+    if let (Some(store), true) = (state.store.clone(), settings.history_retention > 0) {
+        let retention = settings.history_retention;
+        tokio::spawn(async move { prune(store, retention).await });
+    }
+
+    // Upload the previous day's history and post reports on their schedules
+    if (!state.config.exports.is_empty() || !state.config.reports.is_empty()) && state.store.is_none() {
+        return Err("exports and reports require --data-dir".into());
+    }
+    for job in &state.config.exports {
+        let (exporter, schedule, export) = (state.clone(), job.schedule.clone(), Export::new(&job.export, state.timeout)?);
+        tokio::spawn(async move { export::scheduled(exporter, schedule, export).await });
+    }
+    for job in state.config.reports.clone() {
+        let reporter = state.clone();
+        tokio::spawn(async move { report::scheduled(reporter, job).await });
+    }
+
+    // Poll once before binding, so the first scrape after a deploy is not empty,
+    // and leave the background poller to the next interval when it succeeded
+    let warmed = state.interval > 0 && settings.warm_up > 0 && state.warm_up(Duration::from_secs(settings.warm_up), &recorder_handle).await;
+
+    // Start polling in the background
+    if state.interval > 0 {
+        let poller = state.clone();
+        let handle = recorder_handle.clone();
+        let delay = Duration::from_secs(if warmed { state.interval } else { 0 });
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            poller.poll(handle).await
+        });
+    }
+
+    // Pet the systemd watchdog on its own, so long intervals or slow polls do not get the unit killed
+    if let Some(watchdog) = systemd::watchdog_interval() {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(watchdog);
+            loop {
+                interval.tick().await;
+                systemd::notify_watchdog();
+            }
+        });
+    }
+
+    // The grpc api runs next to the http server on its own port
+    #[cfg(feature = "grpc")]
+    if let Some(port) = settings.grpc_port {
+        tokio::spawn(crate::grpc::serve(state.clone(), port)?);
+    }
+
+    // These should be authenticated
+    let base = Router::new()
+        .route("/", get(root));
+
+    // These should NOT be authenticated
+    let standard = Router::new()
+        .route("/health", get(health))
+        .route("/healthz", get(health))
+        .route("/help", get(help))
+        .route("/metrics", get(metrics))
+        .route("/config", get(config));
+
+    // The json api, which browsers may be allowed to call from other origins
+    let mut api = Router::new()
+        .route("/api/v1/costs", get(costs))
+        .route("/api/v1/history", get(history))
+        .route("/api/v1/diff", get(diff))
+        .route("/api/v1/stream", get(stream))
+        .route("/ws/events", get(ws_events))
+        .route("/api/openapi.json", get(openapi));
+    #[cfg(feature = "graphql")]
+    if settings.graphql {
+        api = api
+            .route("/graphql", post(crate::graphql::graphql))
+            .layer(Extension(crate::graphql::schema(state.clone())));
+    }
+    if !settings.cors_origins.is_empty() {
+        api = api.layer(settings.cors_layer());
+    }
+
+    // Admin endpoints are only served when a bearer token is set
+    let admin = match &settings.admin_token {
+        Some(token) => Router::new()
+            .route("/admin/poll", post(admin_poll))
+            .route("/admin/pause", post(admin_pause))
+            .route("/admin/resume", post(admin_resume))
+            .route_layer(RequireAuthorizationLayer::bearer(token)),
+        None => Router::new(),
+    };
+
+    // Cpu and heap profiles are only served with --pprof
+    #[cfg(feature = "profiling")]
+    let debug = crate::profiling::router(&settings).await;
+    #[cfg(not(feature = "profiling"))]
+    let debug = Router::new();
+
+    let mut app = Router::new()
+        .merge(base)
+        .merge(standard)
+        .merge(api)
+        .merge(admin)
+        .merge(debug)
+        .layer(TraceLayer::new_for_http())
+        .route_layer(middleware::from_fn(track_metrics))
+        // Unknown paths are handled before the access log, so probing them is logged too
+        .fallback(handler_404.into_service());
+
+    // Record who reads the billing data
+    if let Some(target) = &settings.access_log {
+        let access_log = JsonLog::access(target)?;
+        app = app.layer(middleware::from_fn(move |req, next| log_access(access_log.clone(), req, next)));
+    }
+
+    let app = app
+        .layer(Extension(state))
+        .layer(Extension(recorder_handle));
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], settings.port));
+    let server = axum::Server::try_bind(&addr)?;
+    println!("Listening on {}", addr);
+    ready();
+    server
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown)
+        .await?;
+
+    Ok(())
+}
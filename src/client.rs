@@ -0,0 +1,136 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Request};
+use std::sync::Mutex;
+use url::Url;
+
+use crate::config::ApiVersion;
+use crate::error::{Context, Error};
+use crate::https::{create_https_client, HttpsClient};
+use crate::schema::{parse_charts, parse_deployments, Data, DataV2};
+
+// Costs of every deployment over a period, from the deployments endpoint
+pub type Costs = DataV2;
+
+// Hourly rate buckets of every deployment, from the charts endpoint
+pub type Charts = Data;
+
+// Billing api client for use outside the exporter, fetching one organization's costs
+// and normalizing them the same way the exporter does for either api version
+#[derive(Debug)]
+pub struct Client {
+    client: HttpsClient,
+    url: Url,
+    headers: HeaderMap,
+    organization_id: Option<String>,
+    version: Mutex<ApiVersion>,
+    strict: bool,
+}
+
+impl Client {
+    // Client for the api under url, such as https://api.elastic-cloud.com/api/v1, sending the api key
+    pub fn new(url: &str, api_key: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut authorization = HeaderValue::from_str(&format!("ApiKey {}", api_key))?;
+        authorization.set_sensitive(true);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, authorization);
+
+        Ok(Client {
+            client: create_https_client(60)?,
+            url: url.parse()?,
+            headers,
+            organization_id: None,
+            version: Mutex::new(ApiVersion::Auto),
+            strict: false,
+        })
+    }
+
+    // Use the organization costs api of this organization, when the api serves it
+    pub fn organization(mut self, id: &str) -> Self {
+        self.organization_id = Some(id.to_string());
+        self
+    }
+
+    // Skip detecting the api version
+    pub fn api_version(self, version: ApiVersion) -> Self {
+        *self.version.lock().unwrap() = version;
+        self
+    }
+
+    // Fail on fields the parser does not know instead of ignoring them
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    // Costs of every deployment from the given time until now
+    pub async fn costs(&self, from: DateTime<Utc>) -> Result<Costs, Error> {
+        let version = self.version().await?;
+        let path = format!("{}?from={}", endpoint(self.organization_id.as_deref(), version, "deployments"), from.to_rfc3339_opts(SecondsFormat::Secs, true));
+        parse_deployments(version, &self.get(&path).await?, self.strict).map_err(|e| Error::from(e).at(&path))
+    }
+
+    // Hourly rates of every deployment between the given times
+    pub async fn charts(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Charts, Error> {
+        let version = self.version().await?;
+        let path = format!(
+            "{}?from={}&to={}",
+            endpoint(self.organization_id.as_deref(), version, "charts"),
+            from.to_rfc3339_opts(SecondsFormat::Secs, true),
+            to.to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        parse_charts(version, &self.get(&path).await?, self.strict).map_err(|e| Error::from(e).at(&path))
+    }
+
+    // Probe the organization costs endpoint once, as the exporter does
+    async fn version(&self) -> Result<ApiVersion, Error> {
+        let version = *self.version.lock().unwrap();
+        let id = match (version, &self.organization_id) {
+            (ApiVersion::Auto, Some(id)) => id,
+            (ApiVersion::Auto, None) => return Ok(ApiVersion::V1),
+            (version, _) => return Ok(version),
+        };
+
+        let version = match self.get(&format!("billing/costs/{}", id)).await {
+            Ok(_) => ApiVersion::V2,
+            Err(Error::NotFound(_)) => ApiVersion::V1,
+            Err(e) => return Err(e),
+        };
+        *self.version.lock().unwrap() = version;
+        Ok(version)
+    }
+
+    async fn get(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let mut req = Request::builder()
+            .method("GET")
+            .uri(format!("{}/{}", self.url.as_str().trim_end_matches('/'), path))
+            .body(Body::empty())
+            .expect("request builder");
+        req.headers_mut().extend(self.headers.clone());
+
+        let response = self.client.request(req).await.map_err(|e| Error::from(e).at(path))?;
+        let status = response.status().as_u16();
+        if status == 200 {
+            return Ok(hyper::body::to_bytes(response.into_body()).await.map_err(|e| Error::from(e).at(path))?.to_vec());
+        }
+
+        let context = Context { status: Some(status), ..Context::new(path) }.excerpt(response.into_body()).await;
+        let context = Box::new(context);
+        Err(match status {
+            404 => Error::NotFound(context),
+            403 => Error::Forbidden(context),
+            401 => Error::Unauthorized(context),
+            429 => Error::TooManyRequests(context, None),
+            status if status >= 500 => Error::Transient(context, None),
+            _ => Error::UnknownCode(context),
+        })
+    }
+}
+
+// Path to an endpoint for the api version, under the organization costs api when it is served
+pub fn endpoint(organization_id: Option<&str>, version: ApiVersion, endpoint: &str) -> String {
+    match (version, organization_id) {
+        (ApiVersion::V2, Some(id)) => format!("billing/costs/{}/{}", id, endpoint),
+        _ => endpoint.to_string(),
+    }
+}
@@ -0,0 +1,541 @@
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use metrics::Label;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::{Aggregation, ChartsBucket, Config, Organization};
+use crate::cost_centers::CostCenters;
+use crate::metadata::Metadata;
+use crate::money::to_f64;
+use crate::schema::{Deployment, Item};
+use crate::sink::Sinks;
+use crate::snapshot::{OrganizationSnapshot, Snapshot};
+
+// Convert a snapshot into gauges on every sink, this runs at exposition time so every scrape sees a single poll
+pub fn record(sinks: &Sinks, snapshot: &Snapshot, config: &Config, aggregation: Aggregation, bucket: ChartsBucket, cost_centers: Option<&CostCenters>, metadata: Option<&Metadata>) {
+    for organization in &snapshot.organizations {
+        record_organization(sinks, organization, config, aggregation, bucket, cost_centers, metadata);
+    }
+
+    record_groups(sinks, snapshot, config);
+    record_credit_exhaustion(sinks, snapshot);
+
+    log::debug!("Adding metric: elastic_billing_last_success_timestamp_seconds, value: {}", snapshot.timestamp);
+    sinks.gauge("elastic_billing_last_success_timestamp_seconds", snapshot.timestamp as f64, &[]);
+}
+
+// Zero the monthly gauges of the previous month, so deployments without cost in the new month
+// do not keep showing last month's final total until they expire
+pub fn reset_monthly(sinks: &Sinks, snapshot: &Snapshot, config: &Config, cost_centers: Option<&CostCenters>, metadata: Option<&Metadata>) {
+    for organization in &snapshot.organizations {
+        let mut cache = LabelCache::new(&organization.organization, cost_centers, metadata, config);
+        sinks.gauge("elastic_billing_organization_monthly_cost_total", 0.0, &cache.org_labels);
+        sinks.gauge("elastic_billing_organization_discounts_total", 0.0, &cache.org_labels);
+        sinks.gauge("elastic_billing_organization_credits_applied_total", 0.0, &cache.org_labels);
+        for (provider, region) in organization.cost_by_region().into_keys() {
+            let mut labels = cache.org_labels.clone();
+            labels.extend([Label::new("region", region), Label::new("provider", provider)]);
+            sinks.gauge("elastic_billing_monthly_cost_by_region", 0.0, &labels);
+        }
+
+        for deployment in &organization.month.deployments {
+            let labels = cache.get(&deployment.deployment_id, &deployment.deployment_name);
+            sinks.gauge("elastic_billing_monthly_cost_total", 0.0, labels);
+            sinks.gauge("elastic_billing_monthly_hourly_rate", 0.0, labels);
+            sinks.gauge("elastic_billing_cost_rate_dollars_per_hour", 0.0, labels);
+            sinks.gauge("elastic_billing_discounts_total", 0.0, labels);
+            sinks.gauge("elastic_billing_credits_applied_total", 0.0, labels);
+            if config.budget_for(&deployment.deployment_id, &deployment.deployment_name).is_some() {
+                sinks.gauge("elastic_billing_budget_utilization_ratio", 0.0, labels);
+            }
+
+            for item in items(&deployment.costs.dimensions, config).into_keys() {
+                labels.push(Label::new("item", item.to_string()));
+                sinks.gauge("elastic_billing_itemized_monthly_cost_total", 0.0, labels);
+                labels.pop();
+            }
+            for item in negative_items(&deployment.costs.dimensions, config).into_keys() {
+                labels.push(Label::new("item", item.to_string()));
+                sinks.gauge("elastic_billing_monthly_credits", 0.0, labels);
+                labels.pop();
+            }
+            for kind in organization.cost_by_kind.get(&deployment.deployment_id).into_iter().flat_map(|k| k.keys()) {
+                labels.push(Label::new("kind", kind.clone()));
+                sinks.gauge("elastic_billing_monthly_cost_by_kind", 0.0, labels);
+                labels.pop();
+            }
+        }
+    }
+
+    for group in &config.groups {
+        sinks.gauge("elastic_billing_group_monthly_cost", 0.0, &[Label::new("group", group.name.clone())]);
+    }
+}
+
+// Count deployments that started or stopped being billed since the previous poll. A new month
+// starts with only the deployments billed so far, so polls in different months are not compared
+pub fn record_lifecycle(sinks: &Sinks, previous: &Snapshot, snapshot: &Snapshot) {
+    let (before, now) = (Utc.timestamp(previous.timestamp, 0), Utc.timestamp(snapshot.timestamp, 0));
+    if (before.year(), before.month()) != (now.year(), now.month()) {
+        return;
+    }
+    for organization in &snapshot.organizations {
+        let before = previous
+            .organizations
+            .iter()
+            .find(|o| o.organization.id == organization.organization.id && o.organization.url == organization.organization.url)
+            .and_then(|o| o.active_deployments());
+        let (before, now) = match (before, organization.active_deployments()) {
+            (Some(before), Some(now)) => (before, now),
+            _ => continue,
+        };
+
+        let labels: Vec<Label> = organization.organization.labels().iter().map(Label::from).collect();
+        let added: Vec<&&str> = now.difference(&before).collect();
+        let removed: Vec<&&str> = before.difference(&now).collect();
+        if !added.is_empty() {
+            log::info!("{{\"fn\": \"record_lifecycle\", \"msg\": \"deployments added\", \"ids\": {:?}}}", added);
+            sinks.counter("elastic_billing_deployments_added_total", added.len() as u64, &labels);
+        }
+        if !removed.is_empty() {
+            log::info!("{{\"fn\": \"record_lifecycle\", \"msg\": \"deployments removed\", \"ids\": {:?}}}", removed);
+            sinks.counter("elastic_billing_deployments_removed_total", removed.len() as u64, &labels);
+        }
+    }
+}
+
+// Export the most expensive deployments this month keyed by rank alone, so a reshuffled ranking
+// does not leave two values per rank. Which deployment holds a rank is on an info series, and
+// pairs from the previous poll that no longer hold are zeroed
+pub fn record_top(sinks: &Sinks, snapshot: &Snapshot, previous: Option<&Snapshot>, config: &Config, count: usize) {
+    let top = ranking(snapshot, count);
+    for (rank, deployment, org_total) in &top {
+        let labels = [Label::new("rank", rank.to_string())];
+        let share = match org_total.is_sign_positive() && !org_total.is_zero() {
+            true => to_f64(deployment.costs.total / *org_total),
+            false => 0.0,
+        };
+        log::debug!("Adding metric: elastic_billing_top_deployment_cost, labels: {:?}, value: {}", &labels, deployment.costs.total);
+        sinks.gauge("elastic_billing_top_deployment_cost", config.cost(deployment.costs.total), &labels);
+        log::debug!("Adding metric: elastic_billing_top_deployment_share_ratio, labels: {:?}, value: {}", &labels, share);
+        sinks.gauge("elastic_billing_top_deployment_share_ratio", share, &labels);
+    }
+
+    let current: Vec<Vec<Label>> = top.iter().map(|(rank, deployment, _)| top_labels(*rank, deployment, config)).collect();
+    if let Some(previous) = previous {
+        for (rank, deployment, _) in ranking(previous, count) {
+            let labels = top_labels(rank, deployment, config);
+            if !current.contains(&labels) {
+                sinks.gauge("elastic_billing_top_deployment_info", 0.0, &labels);
+            }
+        }
+    }
+    for labels in &current {
+        log::debug!("Adding metric: elastic_billing_top_deployment_info, labels: {:?}, value: 1", labels);
+        sinks.gauge("elastic_billing_top_deployment_info", 1.0, labels);
+    }
+}
+
+// The count most expensive deployments across organizations, ranked from 1, with their organization's total
+fn ranking(snapshot: &Snapshot, count: usize) -> Vec<(usize, &Deployment, Decimal)> {
+    let mut deployments: Vec<_> = snapshot
+        .organizations
+        .iter()
+        .flat_map(|o| o.month.deployments.iter().map(move |d| (d, o.month.total_cost)))
+        .collect();
+    deployments.sort_by_key(|(d, _)| std::cmp::Reverse(d.costs.total));
+    deployments.into_iter().take(count).enumerate().map(|(rank, (d, total))| (rank + 1, d, total)).collect()
+}
+
+// Names are left out with stable ids, like on every other deployment series
+fn top_labels(rank: usize, deployment: &Deployment, config: &Config) -> Vec<Label> {
+    let mut labels = vec![Label::new("rank", rank.to_string()), Label::new("id", deployment.deployment_id.clone())];
+    if !config.stable_ids {
+        labels.push(Label::new("name", config.label_name(&deployment.deployment_id, &deployment.deployment_name)));
+    }
+    labels
+}
+
+// Roll up monthly cost and hourly rate for every configured group
+fn record_groups(sinks: &Sinks, snapshot: &Snapshot, config: &Config) {
+    for group in &config.groups {
+        let (cost, hourly_rate) = snapshot
+            .organizations
+            .iter()
+            .flat_map(|o| &o.month.deployments)
+            .filter(|d| group.matches(&d.deployment_id, &d.deployment_name))
+            .fold((Decimal::ZERO, Decimal::ZERO), |(cost, rate), d| (cost + d.costs.total, rate + d.hourly_rate));
+
+        let labels = [Label::new("group", group.name.clone())];
+        log::debug!("Adding metric: elastic_billing_group_monthly_cost, labels: {:?}, value: {}", &labels, cost);
+        sinks.gauge("elastic_billing_group_monthly_cost", config.cost(cost), &labels);
+        log::debug!("Adding metric: elastic_billing_group_hourly_rate, labels: {:?}, value: {}", &labels, hourly_rate);
+        sinks.gauge("elastic_billing_group_hourly_rate", to_f64(hourly_rate), &labels);
+    }
+}
+
+// Estimate when prepaid credits run out at the current organization wide hourly rate,
+// preferring the rate of the costs overview over the sum of the deployment rates
+fn record_credit_exhaustion(sinks: &Sinks, snapshot: &Snapshot) {
+    for organization in &snapshot.organizations {
+        let overview = match &organization.overview {
+            Some(overview) => overview,
+            None => continue,
+        };
+        let balance = match &overview.balance {
+            Some(balance) => balance,
+            None => continue,
+        };
+        let rate = match overview.hourly_rate {
+            rate if rate > Decimal::ZERO => rate,
+            _ => organization.month.deployments.iter().map(|d| d.hourly_rate).sum(),
+        };
+        if rate <= Decimal::ZERO {
+            continue;
+        }
+
+        let exhaustion = snapshot.timestamp as f64 + to_f64(balance.remaining.max(Decimal::ZERO) / rate) * 3600.0;
+        let labels: Vec<Label> = organization.organization.labels().iter().map(Label::from).collect();
+        log::debug!("Adding metric: elastic_billing_credit_exhaustion_timestamp_seconds, labels: {:?}, value: {}", labels, exhaustion);
+        sinks.gauge("elastic_billing_credit_exhaustion_timestamp_seconds", exhaustion, &labels);
+    }
+}
+
+fn record_organization(
+    sinks: &Sinks,
+    snapshot: &OrganizationSnapshot,
+    config: &Config,
+    aggregation: Aggregation,
+    bucket: ChartsBucket,
+    cost_centers: Option<&CostCenters>,
+    metadata: Option<&Metadata>,
+) {
+    let mut cache = LabelCache::new(&snapshot.organization, cost_centers, metadata, config);
+    let deployments_day = &snapshot.day;
+    let deployments_month = &snapshot.month;
+
+    // Get organization totals
+    let org_labels = &cache.org_labels;
+    log::debug!("Adding metric: elastic_billing_organization_daily_cost_total, labels: {:?}, value: {}", org_labels, deployments_day.total_cost);
+    sinks.gauge("elastic_billing_organization_daily_cost_total", config.cost(deployments_day.total_cost), org_labels);
+    log::debug!("Adding metric: elastic_billing_organization_monthly_cost_total, labels: {:?}, value: {}", org_labels, deployments_month.total_cost);
+    sinks.gauge("elastic_billing_organization_monthly_cost_total", config.cost(deployments_month.total_cost), org_labels);
+
+    if let Some((committed, on_demand)) = snapshot.overview.as_ref().and_then(|o| o.committed_and_on_demand()) {
+        log::debug!("Adding metric: elastic_billing_committed_spend_total, labels: {:?}, value: {}", org_labels, committed);
+        sinks.gauge("elastic_billing_committed_spend_total", config.cost(committed), org_labels);
+        log::debug!("Adding metric: elastic_billing_on_demand_spend_total, labels: {:?}, value: {}", org_labels, on_demand);
+        sinks.gauge("elastic_billing_on_demand_spend_total", config.cost(on_demand), org_labels);
+    }
+    if let Some(balance) = snapshot.overview.as_ref().and_then(|o| o.balance.as_ref()) {
+        log::debug!("Adding metric: elastic_billing_prepaid_balance, labels: {:?}, value: {}", org_labels, balance.available);
+        sinks.gauge("elastic_billing_prepaid_balance", config.cost(balance.available), org_labels);
+        log::debug!("Adding metric: elastic_billing_prepaid_remaining, labels: {:?}, value: {}", org_labels, balance.remaining);
+        sinks.gauge("elastic_billing_prepaid_remaining", config.cost(balance.remaining), org_labels);
+    }
+
+    if let Some(info) = &snapshot.info {
+        let mut labels = org_labels.clone();
+        labels.extend([
+            Label::new("billing_model", info.billing_model.clone().unwrap_or_else(|| "unknown".to_string())),
+            Label::new("currency", info.currency.clone().unwrap_or_else(|| "unknown".to_string())),
+        ]);
+        log::debug!("Adding metric: elastic_billing_org_info, labels: {:?}, value: 1", labels);
+        sinks.gauge("elastic_billing_org_info", 1.0, &labels);
+    }
+
+    for invoice in &snapshot.invoices {
+        let mut labels = org_labels.clone();
+        labels.extend([
+            Label::new("invoice", invoice.id.clone()),
+            Label::new("period_start", invoice.period.start.clone()),
+            Label::new("period_end", invoice.period.end.clone()),
+            Label::new("status", invoice.status.clone()),
+            Label::new("currency", invoice.currency.clone()),
+        ]);
+        log::debug!("Adding metric: elastic_billing_invoice_tax, labels: {:?}, value: {}", labels, invoice.tax);
+        sinks.gauge("elastic_billing_invoice_tax", config.cost(invoice.tax), &labels);
+        log::debug!("Adding metric: elastic_billing_invoice_total, labels: {:?}, value: {}", labels, invoice.total);
+        sinks.gauge("elastic_billing_invoice_total", config.cost(invoice.total), &labels);
+    }
+
+    if let Some(active) = snapshot.active_deployments() {
+        log::debug!("Adding metric: elastic_billing_deployments, labels: {:?}, value: {}", org_labels, active.len());
+        sinks.gauge("elastic_billing_deployments", active.len() as f64, org_labels);
+    }
+
+    let complete = if snapshot.charts_missing == 0 { 1.0 } else { 0.0 };
+    log::debug!("Adding metric: elastic_billing_charts_data_complete, labels: {:?}, value: {}", org_labels, complete);
+    sinks.gauge("elastic_billing_charts_data_complete", complete, org_labels);
+
+    // Get hourly or daily data, named after the buckets the charts api returned
+    let (rate_metric, timestamp_metric) = (bucket.rate_metric(), bucket.timestamp_metric());
+    for rate in snapshot.charts.aggregate(aggregation) {
+        let labels = cache.get(&rate.id, &rate.name);
+        log::debug!("Adding metric: {}, labels: {:?}, value: {}", rate_metric, labels, rate.value);
+        sinks.gauge(rate_metric, to_f64(rate.value), labels);
+        log::debug!("Adding metric: {}, labels: {:?}, value: {}", timestamp_metric, labels, rate.timestamp);
+        sinks.gauge(timestamp_metric, rate.timestamp as f64, labels);
+    }
+
+    // Get daily data
+    for deployment in &deployments_day.deployments {
+        let labels = cache.get(&deployment.deployment_id, &deployment.deployment_name);
+        log::debug!("Adding metric: elastic_billing_daily_cost_total, labels: {:?}, value: {}", labels, deployment.costs.total);
+        sinks.gauge("elastic_billing_daily_cost_total", config.cost(deployment.costs.total), labels);
+
+        log::debug!("Adding metric: elastic_billing_hourly_rate, labels: {:?}, value: {}", labels, deployment.hourly_rate);
+        sinks.gauge("elastic_billing_hourly_rate", to_f64(deployment.hourly_rate), labels);
+
+        for (item, cost) in items(&deployment.costs.dimensions, config) {
+            labels.push(Label::new("item", item.to_string()));
+            log::debug!("Adding metric: elastic_billing_itemized_daily_cost_total, labels: {:?}, value: {}", labels, cost);
+            sinks.gauge("elastic_billing_itemized_daily_cost_total", config.cost(cost), labels);
+            labels.pop();
+        }
+        for (item, credit) in negative_items(&deployment.costs.dimensions, config) {
+            labels.push(Label::new("item", item.to_string()));
+            log::debug!("Adding metric: elastic_billing_daily_credits, labels: {:?}, value: {}", labels, credit);
+            sinks.gauge("elastic_billing_daily_credits", config.cost(credit), labels);
+            labels.pop();
+        }
+    }
+    let rates: Vec<Decimal> = deployments_day.deployments.iter().map(|d| d.hourly_rate).collect();
+    record_rate_distribution(sinks, &cache.org_labels, &rates, config.hourly_rate_buckets());
+
+    // Get week and quarter to date data
+    let windows = [
+        ("elastic_billing_wtd_cost_total", &snapshot.week),
+        ("elastic_billing_qtd_cost_total", &snapshot.quarter),
+    ];
+    for (name, window) in windows {
+        for deployment in window.iter().flat_map(|w| &w.deployments) {
+            let labels = cache.get(&deployment.deployment_id, &deployment.deployment_name);
+            log::debug!("Adding metric: {}, labels: {:?}, value: {}", name, labels, deployment.costs.total);
+            sinks.gauge(name, config.cost(deployment.costs.total), labels);
+        }
+    }
+
+    // Get monthly data
+    let (mut org_discounts, mut org_credits) = (Decimal::ZERO, Decimal::ZERO);
+    for deployment in &deployments_month.deployments {
+        let labels = cache.get(&deployment.deployment_id, &deployment.deployment_name);
+
+        let (discounts, credits) = reductions(&deployment.costs.dimensions, config);
+        org_discounts += discounts;
+        org_credits += credits;
+        log::debug!("Adding metric: elastic_billing_discounts_total, labels: {:?}, value: {}", labels, discounts);
+        sinks.gauge("elastic_billing_discounts_total", config.cost(discounts), labels);
+        log::debug!("Adding metric: elastic_billing_credits_applied_total, labels: {:?}, value: {}", labels, credits);
+        sinks.gauge("elastic_billing_credits_applied_total", config.cost(credits), labels);
+
+        log::debug!("Adding metric: elastic_billing_monthly_cost_total, labels: {:?}, value: {}", labels, deployment.costs.total);
+        sinks.gauge("elastic_billing_monthly_cost_total", config.cost(deployment.costs.total), labels);
+
+        if let Some(budget) = config.budget_for(&deployment.deployment_id, &deployment.deployment_name) {
+            let utilization = budget.utilization(deployment.costs.total);
+            log::debug!("Adding metric: elastic_billing_budget_limit, labels: {:?}, value: {}", labels, budget.limit);
+            sinks.gauge("elastic_billing_budget_limit", to_f64(budget.limit), labels);
+            log::debug!("Adding metric: elastic_billing_budget_utilization_ratio, labels: {:?}, value: {}", labels, utilization);
+            sinks.gauge("elastic_billing_budget_utilization_ratio", to_f64(utilization), labels);
+        }
+
+        // Export the billing period, so cost can be normalized by elapsed time
+        let period = [
+            ("elastic_billing_period_start_timestamp_seconds", &deployment.period.start),
+            ("elastic_billing_period_end_timestamp_seconds", &deployment.period.end),
+        ];
+        for (name, timestamp) in period {
+            match DateTime::parse_from_rfc3339(timestamp) {
+                Ok(timestamp) => {
+                    log::debug!("Adding metric: {}, labels: {:?}, value: {}", name, labels, timestamp.timestamp());
+                    sinks.gauge(name, timestamp.timestamp() as f64, labels);
+                }
+                Err(e) => log::debug!("Skipping metric: {}, labels: {:?}, error: {}", name, labels, e),
+            }
+        }
+
+        log::debug!("Adding metric: elastic_billing_monthly_hourly_rate, labels: {:?}, value: {}", labels, deployment.hourly_rate);
+        sinks.gauge("elastic_billing_monthly_hourly_rate", to_f64(deployment.hourly_rate), labels);
+
+        if let Some(rate) = snapshot.cost_rates.get(&deployment.deployment_id) {
+            log::debug!("Adding metric: elastic_billing_cost_rate_dollars_per_hour, labels: {:?}, value: {}", labels, rate);
+            sinks.gauge("elastic_billing_cost_rate_dollars_per_hour", to_f64(*rate), labels);
+        }
+
+        for (kind, cost) in snapshot.cost_by_kind.get(&deployment.deployment_id).into_iter().flatten() {
+            labels.push(Label::new("kind", kind.clone()));
+            log::debug!("Adding metric: elastic_billing_monthly_cost_by_kind, labels: {:?}, value: {}", labels, cost);
+            sinks.gauge("elastic_billing_monthly_cost_by_kind", config.cost(*cost), labels);
+            labels.pop();
+        }
+
+        // Billing does not split cost by tier, so every tier carries the deployment's cost per gb of ram
+        let tiers = snapshot.topology.get(&deployment.deployment_id).map(Vec::as_slice).unwrap_or_default();
+        let ram_gb: f64 = tiers.iter().map(|t| t.ram_gb).sum();
+        for tier in tiers {
+            labels.push(Label::new("tier", tier.tier.clone()));
+            log::debug!("Adding metric: elastic_billing_ram_gb, labels: {:?}, value: {}", labels, tier.ram_gb);
+            sinks.gauge("elastic_billing_ram_gb", tier.ram_gb, labels);
+            if tier.storage_gb > 0.0 {
+                log::debug!("Adding metric: elastic_billing_storage_gb, labels: {:?}, value: {}", labels, tier.storage_gb);
+                sinks.gauge("elastic_billing_storage_gb", tier.storage_gb, labels);
+            }
+            if ram_gb > 0.0 {
+                let cost = to_f64(deployment.hourly_rate) / ram_gb;
+                log::debug!("Adding metric: elastic_billing_cost_per_gb_ram_hour, labels: {:?}, value: {}", labels, cost);
+                sinks.gauge("elastic_billing_cost_per_gb_ram_hour", cost, labels);
+            }
+            labels.pop();
+        }
+
+        for (item, cost) in items(&deployment.costs.dimensions, config) {
+            labels.push(Label::new("item", item.to_string()));
+            log::debug!("Adding metric: elastic_billing_itemized_monthly_cost_total, labels: {:?}, value: {}", labels, cost);
+            sinks.gauge("elastic_billing_itemized_monthly_cost_total", config.cost(cost), labels);
+            labels.pop();
+        }
+        for (item, credit) in negative_items(&deployment.costs.dimensions, config) {
+            labels.push(Label::new("item", item.to_string()));
+            log::debug!("Adding metric: elastic_billing_monthly_credits, labels: {:?}, value: {}", labels, credit);
+            sinks.gauge("elastic_billing_monthly_credits", config.cost(credit), labels);
+            labels.pop();
+        }
+    }
+
+    log::debug!("Adding metric: elastic_billing_organization_discounts_total, labels: {:?}, value: {}", cache.org_labels, org_discounts);
+    sinks.gauge("elastic_billing_organization_discounts_total", config.cost(org_discounts), &cache.org_labels);
+    log::debug!("Adding metric: elastic_billing_organization_credits_applied_total, labels: {:?}, value: {}", cache.org_labels, org_credits);
+    sinks.gauge("elastic_billing_organization_credits_applied_total", config.cost(org_credits), &cache.org_labels);
+
+    for ((provider, region), cost) in snapshot.cost_by_region() {
+        let mut labels = cache.org_labels.clone();
+        labels.extend([Label::new("region", region), Label::new("provider", provider)]);
+        log::debug!("Adding metric: elastic_billing_monthly_cost_by_region, labels: {:?}, value: {}", labels, cost);
+        sinks.gauge("elastic_billing_monthly_cost_by_region", config.cost(cost), &labels);
+    }
+
+    cache.record_info(sinks);
+}
+
+// Spread of hourly rates across the deployments of an organization, as gauges shaped like a histogram
+// so histogram_quantile works on them. A prometheus histogram would count every deployment again on every scrape
+fn record_rate_distribution(sinks: &Sinks, org_labels: &[Label], rates: &[Decimal], buckets: &[f64]) {
+    let bounds = buckets.iter().map(|b| (*b, b.to_string())).chain(std::iter::once((f64::INFINITY, "+Inf".to_string())));
+    for (bound, le) in bounds {
+        let mut labels = org_labels.to_vec();
+        labels.push(Label::new("le", le));
+        let count = rates.iter().filter(|rate| to_f64(**rate) <= bound).count() as f64;
+        log::debug!("Adding metric: elastic_billing_hourly_rate_distribution_bucket, labels: {:?}, value: {}", labels, count);
+        sinks.gauge("elastic_billing_hourly_rate_distribution_bucket", count, &labels);
+    }
+
+    let sum: Decimal = rates.iter().sum();
+    log::debug!("Adding metric: elastic_billing_hourly_rate_distribution_sum, labels: {:?}, value: {}", org_labels, sum);
+    sinks.gauge("elastic_billing_hourly_rate_distribution_sum", to_f64(sum), org_labels);
+    log::debug!("Adding metric: elastic_billing_hourly_rate_distribution_count, labels: {:?}, value: {}", org_labels, rates.len());
+    sinks.gauge("elastic_billing_hourly_rate_distribution_count", rates.len() as f64, org_labels);
+}
+
+// Labels of every deployment in an organization, built once per poll instead of for every series.
+// The item label is pushed and popped on the cached labels, so itemized series do not copy them first
+struct LabelCache<'a> {
+    org_labels: Vec<Label>,
+    cost_centers: Option<&'a CostCenters>,
+    metadata: Option<&'a Metadata>,
+    config: &'a Config,
+    deployments: HashMap<String, Vec<Label>>,
+    names: BTreeMap<String, String>,
+}
+
+impl<'a> LabelCache<'a> {
+    fn new(organization: &Organization, cost_centers: Option<&'a CostCenters>, metadata: Option<&'a Metadata>, config: &'a Config) -> Self {
+        LabelCache {
+            org_labels: organization.labels().iter().map(Label::from).collect(),
+            cost_centers,
+            metadata,
+            config,
+            deployments: HashMap::new(),
+            names: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, id: &str, name: &str) -> &mut Vec<Label> {
+        if !self.deployments.contains_key(id) {
+            let name = self.config.label_name(id, name);
+            let mut labels = deployment_labels(&self.org_labels, id, &name, self.cost_centers, self.config.stable_ids);
+            if let Some(metadata) = self.metadata {
+                labels.extend(metadata.get(id).into_iter().map(|(key, value)| Label::new(key, value)));
+            }
+            self.deployments.insert(id.to_string(), labels);
+            self.names.insert(id.to_string(), name);
+        }
+        self.deployments.get_mut(id).unwrap()
+    }
+
+    // With stable ids the names are only exported here, to be joined on id
+    fn record_info(&self, sinks: &Sinks) {
+        if !self.config.stable_ids {
+            return;
+        }
+        for (id, name) in &self.names {
+            let mut labels = self.org_labels.clone();
+            labels.extend([Label::new("id", id.clone()), Label::new("name", name.clone())]);
+            log::debug!("Adding metric: elastic_billing_deployment_info, labels: {:?}, value: 1", labels);
+            sinks.gauge("elastic_billing_deployment_info", 1.0, &labels);
+        }
+    }
+}
+
+// Labels of a deployment series, with its cost center when a mapping file is configured
+fn deployment_labels(org_labels: &[Label], id: &str, name: &str, cost_centers: Option<&CostCenters>, stable_ids: bool) -> Vec<Label> {
+    let mut labels = org_labels.to_vec();
+    labels.push(Label::new("id", id.to_string()));
+    if !stable_ids {
+        labels.push(Label::new("name", name.to_string()));
+    }
+    if let Some(cost_centers) = cost_centers {
+        labels.push(Label::new("cost_center", cost_centers.get(id)));
+    }
+    labels
+}
+
+// Discounts and credits applied this month, as positive amounts however the api signs them.
+// The items filter is not applied, so they stay complete when the itemized metrics are trimmed
+fn reductions(dimensions: &[Item], config: &Config) -> (Decimal, Decimal) {
+    let (mut discounts, mut credits) = (Decimal::ZERO, Decimal::ZERO);
+    for dimension in dimensions {
+        match config.dimension(&dimension.r#type) {
+            "discount" => discounts += dimension.cost.abs(),
+            "credit" => credits += dimension.cost.abs(),
+            _ => (),
+        }
+    }
+    (discounts, credits)
+}
+
+// Sum dimension costs per normalized item, since several dimension types may share one item,
+// then drop the items filtered out in the config file. Negative lines are left to credits,
+// so refunds do not pull the itemized costs below zero
+fn items<'a>(dimensions: &'a [Item], config: &'a Config) -> BTreeMap<&'a str, Decimal> {
+    let mut items = BTreeMap::new();
+    for dimension in dimensions.iter().filter(|d| d.cost >= Decimal::ZERO) {
+        *items.entry(config.dimension(&dimension.r#type)).or_insert(Decimal::ZERO) += dimension.cost;
+    }
+    items.retain(|item, cost| config.items.matches(item, to_f64(*cost)));
+    items
+}
+
+// Negative dimension costs per normalized item, such as refunds, as positive amounts.
+// Discount and credit items are left out, since the reductions metrics already export them
+fn negative_items<'a>(dimensions: &'a [Item], config: &'a Config) -> BTreeMap<&'a str, Decimal> {
+    let mut items = BTreeMap::new();
+    for dimension in dimensions.iter().filter(|d| d.cost < Decimal::ZERO) {
+        match config.dimension(&dimension.r#type) {
+            "discount" | "credit" => (),
+            item => *items.entry(item).or_insert(Decimal::ZERO) -= dimension.cost,
+        }
+    }
+    items.retain(|item, cost| config.items.matches(item, to_f64(*cost)));
+    items
+}
@@ -0,0 +1,579 @@
+use hmac::{Hmac, Mac};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use regex::Regex;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use url::Url;
+
+use crate::export::ExportJob;
+use crate::money::to_f64;
+use crate::redact;
+use crate::report::{ReportJob, REPORT_BY, REPORT_FORMATS};
+use crate::sink::SinkConfig;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub organizations: Vec<Organization>,
+    pub budget: Option<f64>,
+    #[serde(default)]
+    pub budgets: Vec<Budget>,
+    #[serde(default)]
+    pub groups: Vec<Group>,
+    #[serde(default)]
+    pub dimensions: HashMap<String, String>,
+    #[serde(default)]
+    pub items: ItemFilter,
+    pub charts_bucket: Option<ChartsBucket>,
+    // Key deployment series on the id alone, with the name only on elastic_billing_deployment_info
+    #[serde(default)]
+    pub stable_ids: bool,
+    pub mask_names: Option<NameMask>,
+    // Key of the hmac behind hashed names, so they cannot be reversed by hashing candidate names
+    #[serde(default, serialize_with = "redact::serialize_secret")]
+    pub mask_secret: Option<String>,
+    // Friendly names by deployment id, replacing generated names on the name label
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    // Decimal places of the exported costs, to line up with invoices, left unrounded when unset
+    pub round_costs: Option<u32>,
+    pub rounding: Option<Rounding>,
+    // Upper bounds of elastic_billing_hourly_rate_distribution, defaulting to HOURLY_RATE_BUCKETS
+    #[serde(default)]
+    pub hourly_rate_buckets: Vec<f64>,
+    pub threshold_command: Option<ThresholdCommand>,
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+    #[serde(default)]
+    pub exports: Vec<ExportJob>,
+    #[serde(default)]
+    pub reports: Vec<ReportJob>,
+}
+
+// Command run when a deployment crosses a ratio of its budget
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThresholdCommand {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default = "default_thresholds")]
+    pub thresholds: Vec<f64>,
+}
+
+fn default_thresholds() -> Vec<f64> {
+    vec![1.0]
+}
+
+// Which normalized items are exported by the itemized metrics, an empty include list keeps every item
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ItemFilter {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub drop_zero: bool,
+}
+
+// More places than any currency or api response carries
+pub const MAX_ROUND_COSTS: u32 = 10;
+
+// Hourly rates from a small dev cluster up to a large production one
+const HOURLY_RATE_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0];
+
+// Canonical item label for the dimension types Elastic reports, after dropping the cloud provider prefix
+const DIMENSIONS: &[(&str, &str)] = &[
+    ("capacity", "capacity"),
+    ("data_in", "data_in"),
+    ("data_transfer_in", "data_in"),
+    ("data_out", "data_out"),
+    ("data_transfer_out", "data_out"),
+    ("data_internode", "data_internode"),
+    ("data_transfer_internode", "data_internode"),
+    ("storage", "storage"),
+    ("storage_bytes", "storage"),
+    ("storage_api", "storage_api"),
+    ("storage_requests", "storage_api"),
+    ("snapshot", "snapshot"),
+    ("snapshot_storage", "snapshot"),
+    ("discount", "discount"),
+    ("discounts", "discount"),
+    ("credit", "credit"),
+    ("credits", "credit"),
+    ("credits_applied", "credit"),
+];
+
+// Monthly budget for deployments matching either an id or a name regex, which is also
+// accepted as name, the key older configs used
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Budget {
+    pub id: Option<String>,
+    #[serde(alias = "name")]
+    pub pattern: Option<String>,
+    pub limit: Decimal,
+    #[serde(skip)]
+    pub regex: Option<Regex>,
+}
+
+// Logical group of deployments, such as a team or product, matched by ids or a name regex
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Group {
+    pub name: String,
+    #[serde(default)]
+    pub ids: Vec<String>,
+    pub pattern: Option<String>,
+    #[serde(skip)]
+    pub regex: Option<Regex>,
+}
+
+// Urls and header values may carry credentials, so they are redacted when serialized or debug printed
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Organization {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    #[serde(serialize_with = "redact::serialize_url")]
+    pub url: Url,
+    #[serde(default, serialize_with = "redact::serialize_urls")]
+    pub fallback_urls: Vec<Url>,
+    #[serde(default, serialize_with = "redact::serialize_headers")]
+    pub headers: HashMap<String, String>,
+    #[serde(skip)]
+    pub header_map: HeaderMap,
+    // Name from the organizations api, used for org_name when the config has none
+    #[serde(skip)]
+    pub account_name: Arc<RwLock<Option<String>>>,
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> BoxResult<Self> {
+        Self::from_reader(File::open(path)?)
+    }
+
+    fn from_reader(reader: impl Read) -> BoxResult<Self> {
+        let mut config: Config = serde_yaml::from_reader(reader)?;
+
+        for budget in config.budgets.iter_mut() {
+            if budget.id.is_none() && budget.pattern.is_none() {
+                return Err("budgets require either an id or a pattern".into());
+            }
+            // Utilization divides by the limit
+            if budget.limit <= Decimal::ZERO {
+                return Err("budget limits must be greater than 0".into());
+            }
+            if let Some(pattern) = &budget.pattern {
+                budget.regex = Some(Regex::new(pattern)?);
+            }
+        }
+
+        // Organizations are told apart by id, in their org labels and when snapshots are compared
+        if config.organizations.len() > 1 {
+            let mut ids = HashSet::new();
+            if !config.organizations.iter().all(|o| o.id.as_ref().is_some_and(|id| ids.insert(id))) {
+                return Err("organizations each require a unique id when there are several".into());
+            }
+        }
+
+        for organization in config.organizations.iter_mut() {
+            for (name, value) in &organization.headers {
+                let mut value = HeaderValue::from_str(value)?;
+                value.set_sensitive(true);
+                organization.header_map.insert(HeaderName::from_str(name)?, value);
+            }
+        }
+
+        for report in &config.reports {
+            if !REPORT_BY.contains(&report.by.as_str()) {
+                return Err(format!("reports can only be by {}", REPORT_BY.join(", ")).into());
+            }
+            if !REPORT_FORMATS.contains(&report.format.as_str()) {
+                return Err(format!("report formats are {}", REPORT_FORMATS.join(", ")).into());
+            }
+        }
+
+        if config.hourly_rate_buckets.windows(2).any(|w| w[0] >= w[1]) || config.hourly_rate_buckets.iter().any(|b| !b.is_finite()) {
+            return Err("hourly_rate_buckets must be finite and increasing".into());
+        }
+
+        if config.round_costs.is_some_and(|places| places > MAX_ROUND_COSTS) {
+            return Err(format!("round_costs can be at most {} decimal places", MAX_ROUND_COSTS).into());
+        }
+
+        for group in config.groups.iter_mut() {
+            if let Some(pattern) = &group.pattern {
+                group.regex = Some(Regex::new(pattern)?);
+            }
+        }
+
+        Ok(config)
+    }
+
+    // Map a dimension type onto a stable item label, the config file takes precedence over the built-in map
+    pub fn dimension<'a>(&'a self, r#type: &'a str) -> &'a str {
+        if let Some(item) = self.dimensions.get(r#type) {
+            return item;
+        }
+        let base = ["aws.", "gcp.", "azure."]
+            .iter()
+            .find(|prefix| r#type.get(..prefix.len()).map_or(false, |p| p.eq_ignore_ascii_case(prefix)))
+            .map_or(r#type, |prefix| &r#type[prefix.len()..]);
+        match DIMENSIONS.iter().find(|(from, _)| from.eq_ignore_ascii_case(base)) {
+            Some((_, to)) => to,
+            None => r#type,
+        }
+    }
+
+    pub fn hourly_rate_buckets(&self) -> &[f64] {
+        match self.hourly_rate_buckets.is_empty() {
+            true => HOURLY_RATE_BUCKETS,
+            false => &self.hourly_rate_buckets,
+        }
+    }
+
+    // Returns the first budget matching the deployment
+    pub fn budget_for(&self, id: &str, name: &str) -> Option<&Budget> {
+        self.budgets.iter().find(|budget| budget.matches(id, name))
+    }
+
+    // The deployment name as exported on labels, its alias when it has one, otherwise masked when mask_names is set
+    pub fn label_name(&self, id: &str, name: &str) -> String {
+        if let Some(alias) = self.aliases.get(id) {
+            return alias.clone();
+        }
+        match self.mask_names {
+            Some(mask) => mask.apply(name, self.mask_secret.as_deref().unwrap_or_default()),
+            None => name.to_string(),
+        }
+    }
+
+    // A cost as exported on the metrics, rounded when round_costs is set. Rates and ratios are left as they are
+    pub fn cost(&self, amount: Decimal) -> f64 {
+        match self.round_costs {
+            Some(places) => to_f64(amount.round_dp_with_strategy(places, self.rounding.unwrap_or_default().strategy())),
+            None => to_f64(amount),
+        }
+    }
+}
+
+impl ItemFilter {
+    pub fn matches(&self, item: &str, cost: f64) -> bool {
+        if self.drop_zero && cost == 0.0 {
+            return false;
+        }
+        if !self.include.is_empty() && !self.include.iter().any(|i| i == item) {
+            return false;
+        }
+        !self.exclude.iter().any(|i| i == item)
+    }
+}
+
+impl Budget {
+    pub fn matches(&self, id: &str, name: &str) -> bool {
+        if let Some(budget_id) = &self.id {
+            return budget_id == id;
+        }
+        match &self.regex {
+            Some(regex) => regex.is_match(name),
+            None => false,
+        }
+    }
+
+    // Share of the limit a monthly cost uses, as a decimal so it matches the summed costs exactly
+    pub fn utilization(&self, cost: Decimal) -> Decimal {
+        cost / self.limit
+    }
+}
+
+impl Group {
+    pub fn matches(&self, id: &str, name: &str) -> bool {
+        if self.ids.iter().any(|group_id| group_id == id) {
+            return true;
+        }
+        match &self.regex {
+            Some(regex) => regex.is_match(name),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for Organization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Organization")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("url", &redact::url(&self.url))
+            .field("fallback_urls", &self.fallback_urls.iter().map(redact::url).collect::<Vec<_>>())
+            .field("headers", &redact::headers(&self.headers))
+            .finish()
+    }
+}
+
+impl Organization {
+    // Name from the config, falling back to the one from the organizations api
+    pub fn display_name(&self) -> Option<String> {
+        self.name.clone().or_else(|| self.account_name.read().unwrap().clone())
+    }
+
+    // The primary url followed by the fallbacks, in failover order
+    pub fn urls(&self) -> Vec<&Url> {
+        std::iter::once(&self.url).chain(self.fallback_urls.iter()).collect()
+    }
+
+    // Organizations without an id come from --url, and do not get org labels.
+    // The name comes from the config, then the organizations api, then the id
+    pub fn labels(&self) -> Vec<(&'static str, String)> {
+        match &self.id {
+            Some(id) => vec![
+                ("org_id", id.clone()),
+                ("org_name", self.display_name().unwrap_or_else(|| id.clone())),
+            ],
+            None => Vec::new(),
+        }
+    }
+}
+
+// Which shape of the billing api to speak, v1 appends endpoints to the configured url,
+// while v2 uses the /billing/costs/{organization_id} endpoints under the api root
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiVersion {
+    Auto,
+    V1,
+    V2,
+}
+
+impl FromStr for ApiVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ApiVersion::Auto),
+            "v1" => Ok(ApiVersion::V1),
+            "v2" => Ok(ApiVersion::V2),
+            _ => Err(format!("unknown api version {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiVersion::Auto => f.write_str("auto"),
+            ApiVersion::V1 => f.write_str("v1"),
+            ApiVersion::V2 => f.write_str("v2"),
+        }
+    }
+}
+
+// How the hourly rate is taken from the buckets returned by the charts api
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Latest,
+    Mean,
+    Max,
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latest" => Ok(Aggregation::Latest),
+            "mean" => Ok(Aggregation::Mean),
+            "max" => Ok(Aggregation::Max),
+            _ => Err(format!("unknown aggregation {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Aggregation::Latest => f.write_str("latest"),
+            Aggregation::Mean => f.write_str("mean"),
+            Aggregation::Max => f.write_str("max"),
+        }
+    }
+}
+
+// Size of the buckets the charts api returns, which also names the rate metrics
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChartsBucket {
+    Hourly,
+    Daily,
+}
+
+impl ChartsBucket {
+    pub fn seconds(&self) -> i64 {
+        match self {
+            ChartsBucket::Hourly => 3600,
+            ChartsBucket::Daily => 86400,
+        }
+    }
+
+    pub fn rate_metric(&self) -> &'static str {
+        match self {
+            ChartsBucket::Hourly => "elastic_billing_charts_hourly_rate",
+            ChartsBucket::Daily => "elastic_billing_charts_daily_rate",
+        }
+    }
+
+    pub fn timestamp_metric(&self) -> &'static str {
+        match self {
+            ChartsBucket::Hourly => "elastic_billing_hourly_rate_timestamp_seconds",
+            ChartsBucket::Daily => "elastic_billing_daily_rate_timestamp_seconds",
+        }
+    }
+}
+
+impl FromStr for ChartsBucket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hourly" => Ok(ChartsBucket::Hourly),
+            "daily" => Ok(ChartsBucket::Daily),
+            _ => Err(format!("unknown charts bucket {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ChartsBucket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChartsBucket::Hourly => f.write_str("hourly"),
+            ChartsBucket::Daily => f.write_str("daily"),
+        }
+    }
+}
+
+// How deployment names are masked in labels, for names carrying customer identifiers
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NameMask {
+    // The first 12 hex digits of the hmac-sha256 of the name under mask_secret, stable across polls and restarts
+    Hash,
+    // Up to the first 4 characters of the name followed by an asterisk, at most half of a short name
+    Truncate,
+}
+
+impl NameMask {
+    pub fn apply(&self, name: &str, secret: &str) -> String {
+        match self {
+            NameMask::Hash => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any length");
+                mac.update(name.as_bytes());
+                mac.finalize().into_bytes().iter().take(6).map(|b| format!("{:02x}", b)).collect()
+            }
+            NameMask::Truncate => format!("{}*", name.chars().take(4.min(name.chars().count() / 2)).collect::<String>()),
+        }
+    }
+}
+
+impl FromStr for NameMask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(NameMask::Hash),
+            "truncate" => Ok(NameMask::Truncate),
+            _ => Err(format!("unknown name mask {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for NameMask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NameMask::Hash => f.write_str("hash"),
+            NameMask::Truncate => f.write_str("truncate"),
+        }
+    }
+}
+
+// How exported costs are rounded, half_up matching most invoices
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    // Halves away from zero, 0.125 becomes 0.13
+    #[default]
+    HalfUp,
+    // Halves towards zero, 0.125 becomes 0.12
+    HalfDown,
+    // Halves to the even neighbour, also known as bankers rounding
+    HalfEven,
+    // Always away from zero
+    Up,
+    // Always towards zero, truncating
+    Down,
+}
+
+impl Rounding {
+    fn strategy(&self) -> RoundingStrategy {
+        match self {
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfDown => RoundingStrategy::MidpointTowardZero,
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::Up => RoundingStrategy::AwayFromZero,
+            Rounding::Down => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half_up" => Ok(Rounding::HalfUp),
+            "half_down" => Ok(Rounding::HalfDown),
+            "half_even" => Ok(Rounding::HalfEven),
+            "up" => Ok(Rounding::Up),
+            "down" => Ok(Rounding::Down),
+            _ => Err(format!("unknown rounding {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Rounding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rounding::HalfUp => f.write_str("half_up"),
+            Rounding::HalfDown => f.write_str("half_down"),
+            Rounding::HalfEven => f.write_str("half_even"),
+            Rounding::Up => f.write_str("up"),
+            Rounding::Down => f.write_str("down"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn organizations_require_unique_ids() {
+        let single = "organizations:\n  - url: http://localhost:9100\n";
+        assert!(Config::from_reader(single.as_bytes()).is_ok());
+
+        let unique = "organizations:\n  - id: \"1\"\n    url: http://localhost:9100\n  - id: \"2\"\n    url: http://localhost:9101\n";
+        assert!(Config::from_reader(unique.as_bytes()).is_ok());
+
+        let missing = "organizations:\n  - id: \"1\"\n    url: http://localhost:9100\n  - url: http://localhost:9101\n";
+        assert!(Config::from_reader(missing.as_bytes()).is_err());
+
+        let duplicate = "organizations:\n  - id: \"1\"\n    url: http://localhost:9100\n  - id: \"1\"\n    url: http://localhost:9101\n";
+        assert!(Config::from_reader(duplicate.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Deployment id to cost center mapping, kept in a csv or yaml file and reloaded when it changes
+#[derive(Clone, Debug)]
+pub struct CostCenters {
+    path: PathBuf,
+    modified: Arc<RwLock<Option<SystemTime>>>,
+    pub map: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl CostCenters {
+    pub fn new(path: &str) -> BoxResult<Self> {
+        let cost_centers = CostCenters {
+            path: PathBuf::from(path),
+            modified: Arc::new(RwLock::new(None)),
+            map: Arc::new(RwLock::new(HashMap::new())),
+        };
+        cost_centers.reload()?;
+        Ok(cost_centers)
+    }
+
+    // Cost center of a deployment, deployments missing from the file are unassigned
+    pub fn get(&self, id: &str) -> String {
+        match self.map.read().unwrap().get(id) {
+            Some(cost_center) => cost_center.clone(),
+            None => "unassigned".to_string(),
+        }
+    }
+
+    // Read the file again if its modification time moved, returning whether it was reloaded
+    pub fn reload(&self) -> BoxResult<bool> {
+        let modified = fs::metadata(&self.path)?.modified()?;
+        if *self.modified.read().unwrap() == Some(modified) {
+            return Ok(false);
+        }
+
+        let contents = fs::read_to_string(&self.path)?;
+        let map = match self.path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => parse_csv(&contents)?,
+            _ => serde_yaml::from_str(&contents)?,
+        };
+
+        *self.map.write().unwrap() = map;
+        *self.modified.write().unwrap() = Some(modified);
+        Ok(true)
+    }
+
+    // Check the file for changes every period, keeping the last good mapping on errors
+    pub async fn watch(&self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match self.reload() {
+                Ok(true) => {
+                    log::info!("{{\"fn\": \"watch\", \"msg\": \"reloaded cost centers\", \"path\": \"{}\"}}", self.path.display());
+                    metrics::increment_counter!("elastic_billing_cost_center_reloads_total");
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    log::error!("{{\"fn\": \"watch\", \"msg\": \"failed reloading cost centers\", \"error\": \"{}\"}}", e);
+                    metrics::increment_counter!("elastic_billing_cost_center_reload_errors_total");
+                }
+            }
+        }
+    }
+}
+
+// Parse id,cost_center lines, skipping blank lines, comments and an optional header
+fn parse_csv(contents: &str) -> BoxResult<HashMap<String, String>> {
+    let mut map = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || (number == 0 && line.starts_with("id,")) {
+            continue;
+        }
+        match line.split_once(',') {
+            Some((id, cost_center)) => map.insert(id.trim().to_string(), cost_center.trim().to_string()),
+            None => return Err(format!("line {} is not id,cost_center", number + 1).into()),
+        };
+    }
+    Ok(map)
+}
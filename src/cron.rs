@@ -0,0 +1,164 @@
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+// Five field cron expression (minute, hour, day of month, month, day of week) evaluated in utc,
+// with lists, ranges, steps and the @hourly, @daily, @weekly, @monthly and @yearly shorthands
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    expression: String,
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    // Restricting both days of month and of week matches either of them, as in crontab
+    any_day: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let expression = match input.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("invalid cron expression \"{}\": expected five fields", input));
+        }
+        let field = |index: usize, min: u32, max: u32| {
+            parse_field(fields[index], min, max).map_err(|e| format!("invalid cron expression \"{}\": {}", input, e))
+        };
+
+        let mut weekdays = field(4, 0, 7)?;
+        // Both 0 and 7 are sunday
+        weekdays[0] |= weekdays[7];
+        weekdays.truncate(7);
+
+        Ok(Schedule {
+            expression: input.trim().to_string(),
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            weekdays,
+            any_day: fields[2] != "*" && fields[4] != "*",
+        })
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> String {
+        schedule.expression
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+// Flags for every value from 0 to max that the field allows
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut allowed = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("bad step in {}", part))?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("bad step in {}", part));
+        }
+
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (number(start, min, max)?, number(end, min, max)?),
+                // A single value with a step runs up to the end, as in 5/15
+                None if part.contains('/') => (number(range, min, max)?, max),
+                None => {
+                    let value = number(range, min, max)?;
+                    (value, value)
+                }
+            },
+        };
+        if start > end {
+            return Err(format!("bad range {}", range));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            allowed[value as usize] = true;
+        }
+    }
+    Ok(allowed)
+}
+
+fn number(value: &str, min: u32, max: u32) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(number) if (min..=max).contains(&number) => Ok(number),
+        _ => Err(format!("{} is not a number from {} to {}", value, min, max)),
+    }
+}
+
+impl Schedule {
+    fn day_matches(&self, time: &DateTime<Utc>) -> bool {
+        let day = self.days[time.day() as usize];
+        let weekday = self.weekdays[time.weekday().num_days_from_sunday() as usize];
+        match self.any_day {
+            true => day || weekday,
+            false => day && weekday,
+        }
+    }
+
+    // The first matching minute after the given time, None when nothing matches within
+    // a few years, as with the 31st of february
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut time = Utc.ymd(after.year(), after.month(), after.day()).and_hms(after.hour(), after.minute(), 0) + Duration::minutes(1);
+        let limit = after + Duration::days(5 * 366);
+
+        while time < limit {
+            if !self.months[time.month() as usize] {
+                let (year, month) = match time.month() {
+                    12 => (time.year() + 1, 1),
+                    month => (time.year(), month + 1),
+                };
+                time = Utc.ymd(year, month, 1).and_hms(0, 0, 0);
+            } else if !self.day_matches(&time) {
+                time = Utc.ymd(time.year(), time.month(), time.day()).and_hms(0, 0, 0) + Duration::days(1);
+            } else if !self.hours[time.hour() as usize] {
+                time = Utc.ymd(time.year(), time.month(), time.day()).and_hms(time.hour(), 0, 0) + Duration::hours(1);
+            } else if !self.minutes[time.minute() as usize] {
+                time = time + Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+
+    // Sleep until the next matching minute, returning it
+    pub async fn tick(&self) -> Option<DateTime<Utc>> {
+        let next = self.next_after(Utc::now())?;
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+        Some(next)
+    }
+}
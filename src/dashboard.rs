@@ -0,0 +1,147 @@
+use serde_json::{json, Value};
+
+// Remaining hours in the current month, used to forecast month end spend
+const REMAINING_HOURS: &str = "((days_in_month() - day_of_month()) * 24 + (23 - hour()))";
+
+fn panel(id: u64, title: &str, kind: &str, unit: &str, grid: Value, targets: Vec<(&str, &str)>) -> Value {
+    let targets: Vec<Value> = targets
+        .iter()
+        .enumerate()
+        .map(|(i, (expr, legend))| {
+            json!({
+                "refId": ((b'A' + i as u8) as char).to_string(),
+                "datasource": { "type": "prometheus", "uid": "${datasource}" },
+                "expr": expr,
+                "legendFormat": legend
+            })
+        })
+        .collect();
+
+    json!({
+        "id": id,
+        "title": title,
+        "type": kind,
+        "gridPos": grid,
+        "datasource": { "type": "prometheus", "uid": "${datasource}" },
+        "fieldConfig": { "defaults": { "unit": unit }, "overrides": [] },
+        "targets": targets
+    })
+}
+
+// Build a grafana dashboard for the metrics exported by this crate
+pub fn dashboard() -> Value {
+    let forecast = format!(
+        "sum(elastic_billing_monthly_cost_total{{name=~\"$name\"}}) + sum(elastic_billing_hourly_rate{{name=~\"$name\"}}) * {}",
+        REMAINING_HOURS
+    );
+    let forecast_by_deployment = format!(
+        "sum by (name) (elastic_billing_monthly_cost_total{{name=~\"$name\"}}) + sum by (name) (elastic_billing_hourly_rate{{name=~\"$name\"}}) * {}",
+        REMAINING_HOURS
+    );
+
+    let panels = vec![
+        panel(
+            1,
+            "Month to date",
+            "stat",
+            "currencyUSD",
+            json!({ "h": 5, "w": 6, "x": 0, "y": 0 }),
+            vec![("sum(elastic_billing_monthly_cost_total{name=~\"$name\"})", "month to date")],
+        ),
+        panel(
+            2,
+            "Forecast month end",
+            "stat",
+            "currencyUSD",
+            json!({ "h": 5, "w": 6, "x": 6, "y": 0 }),
+            vec![(&forecast, "forecast")],
+        ),
+        panel(
+            3,
+            "Today",
+            "stat",
+            "currencyUSD",
+            json!({ "h": 5, "w": 6, "x": 12, "y": 0 }),
+            vec![("sum(elastic_billing_daily_cost_total{name=~\"$name\"})", "today")],
+        ),
+        panel(
+            4,
+            "Hourly rate",
+            "stat",
+            "currencyUSD",
+            json!({ "h": 5, "w": 6, "x": 18, "y": 0 }),
+            vec![("sum(elastic_billing_hourly_rate{name=~\"$name\"})", "hourly rate")],
+        ),
+        panel(
+            5,
+            "Hourly rate by deployment",
+            "timeseries",
+            "currencyUSD",
+            json!({ "h": 8, "w": 12, "x": 0, "y": 5 }),
+            vec![("sum by (name) (elastic_billing_hourly_rate{name=~\"$name\"})", "{{name}}")],
+        ),
+        panel(
+            6,
+            "Monthly cost by deployment",
+            "timeseries",
+            "currencyUSD",
+            json!({ "h": 8, "w": 12, "x": 12, "y": 5 }),
+            vec![("sum by (name) (elastic_billing_monthly_cost_total{name=~\"$name\"})", "{{name}}")],
+        ),
+        panel(
+            7,
+            "Monthly cost by item",
+            "piechart",
+            "currencyUSD",
+            json!({ "h": 8, "w": 12, "x": 0, "y": 13 }),
+            vec![("sum by (item) (elastic_billing_itemized_monthly_cost_total{name=~\"$name\"})", "{{item}}")],
+        ),
+        panel(
+            8,
+            "Forecast month end by deployment",
+            "bargauge",
+            "currencyUSD",
+            json!({ "h": 8, "w": 12, "x": 12, "y": 13 }),
+            vec![(&forecast_by_deployment, "{{name}}")],
+        ),
+        panel(
+            9,
+            "Itemized monthly cost",
+            "table",
+            "currencyUSD",
+            json!({ "h": 10, "w": 24, "x": 0, "y": 21 }),
+            vec![("sum by (name, item) (elastic_billing_itemized_monthly_cost_total{name=~\"$name\"})", "{{name}} {{item}}")],
+        ),
+    ];
+
+    json!({
+        "title": "Elastic Cloud Billing",
+        "uid": "elastic-cloud-billing",
+        "tags": ["elastic", "billing"],
+        "timezone": "utc",
+        "schemaVersion": 36,
+        "refresh": "5m",
+        "time": { "from": "now-7d", "to": "now" },
+        "templating": {
+            "list": [
+                {
+                    "name": "datasource",
+                    "type": "datasource",
+                    "query": "prometheus"
+                },
+                {
+                    "name": "name",
+                    "label": "Deployment",
+                    "type": "query",
+                    "datasource": { "type": "prometheus", "uid": "${datasource}" },
+                    "query": "label_values(elastic_billing_monthly_cost_total, name)",
+                    "includeAll": true,
+                    "multi": true,
+                    "allValue": ".*",
+                    "refresh": 2
+                }
+            ]
+        },
+        "panels": panels
+    })
+}
@@ -0,0 +1,61 @@
+use crate::snapshot::Snapshot;
+
+// Render the snapshot as an aligned table, most expensive deployments first
+pub fn table(snapshot: &Snapshot) -> String {
+    let mut rows: Vec<[String; 4]> = Vec::new();
+    let mut deployments: Vec<_> = snapshot
+        .organizations
+        .iter()
+        .flat_map(|o| &o.month.deployments)
+        .collect();
+    deployments.sort_by_key(|d| std::cmp::Reverse(d.costs.total));
+
+    for deployment in deployments {
+        let top = deployment
+            .costs
+            .dimensions
+            .iter()
+            .max_by(|a, b| a.cost.cmp(&b.cost))
+            .map(|item| format!("{} ({:.2})", item.r#type, item.cost))
+            .unwrap_or_default();
+
+        rows.push([
+            deployment.deployment_name.clone(),
+            format!("{:.2}", deployment.costs.total),
+            format!("{:.2}", deployment.hourly_rate),
+            top,
+        ]);
+    }
+
+    let header = [
+        "DEPLOYMENT".to_string(),
+        "MONTHLY COST".to_string(),
+        "HOURLY RATE".to_string(),
+        "TOP DIMENSION".to_string(),
+    ];
+
+    let mut widths = header.clone().map(|h| h.len());
+    for row in &rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+
+    let mut output = String::new();
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line = format!(
+            "{:<w0$}  {:>w1$}  {:>w2$}  {:<w3$}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3]
+        );
+        output.push_str(line.trim_end());
+        output.push('\n');
+    }
+    output
+}
@@ -4,36 +4,154 @@ use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
-use std::fmt;
+use serde_json::{Map, Value};
 
-#[derive(Debug)]
+use crate::schema::ParseError;
+
+// Characters of a failed response body kept with the error
+const EXCERPT_LENGTH: usize = 256;
+
+// The billing api call an error came from
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub endpoint: String,
+    pub status: Option<u16>,
+    pub request_id: Option<String>,
+    pub trace_id: Option<String>,
+    pub excerpt: Option<String>,
+}
+
+impl Context {
+    // Endpoints are named by their path without the query, which only holds dates
+    pub fn new(path: &str) -> Self {
+        Context {
+            endpoint: path.split('?').next().unwrap_or_default().to_string(),
+            ..Context::default()
+        }
+    }
+
+    // Read the start of a failed response, where the api explains what went wrong
+    pub async fn excerpt(mut self, mut body: hyper::Body) -> Self {
+        use hyper::body::HttpBody;
+
+        let mut bytes = Vec::new();
+        while bytes.len() < EXCERPT_LENGTH * 4 {
+            match body.data().await {
+                Some(Ok(chunk)) => bytes.extend_from_slice(&chunk),
+                _ => break,
+            }
+        }
+        let excerpt: String = String::from_utf8_lossy(&bytes).trim().chars().take(EXCERPT_LENGTH).collect();
+        self.excerpt = Some(excerpt).filter(|e| !e.is_empty());
+        self
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
-    Forbidden,
-    Unauthorized,
-    NotFound,
-    UnknownCode,
-    Hyper(hyper::Error),
-    SerdeJson(serde_json::Error),
-}
-
-impl std::error::Error for Error {}
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Error::Forbidden => f.write_str("{\"error\": \"Status: Forbidden\"}"),
-            Error::UnknownCode=> f.write_str("{\"error\": \"Caught bad status code\"}"),
-            Error::Unauthorized => f.write_str("{\"error\": \"Status: Unauthorized\"}"),
-            Error::NotFound => f.write_str("{\"error\": \"Status: Not found\"}"),
-            Error::Hyper(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
-            Error::SerdeJson(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+    #[error("Status: Forbidden")]
+    Forbidden(Box<Context>),
+    #[error("Status: Unauthorized")]
+    Unauthorized(Box<Context>),
+    #[error("Status: Not found")]
+    NotFound(Box<Context>),
+    #[error("Caught bad status code")]
+    UnknownCode(Box<Context>),
+    #[error("Status: Too many requests")]
+    TooManyRequests(Box<Context>, Option<u64>),
+    #[error("Status: {}", .0.status.unwrap_or_default())]
+    Transient(Box<Context>, Option<u64>),
+    #[error("A poll is already running")]
+    Busy,
+    #[error("No deployments billed this month for organization {}", .0.as_deref().unwrap_or("default"))]
+    Empty(Option<String>),
+    #[error("Response body larger than {1} bytes")]
+    BodyTooLarge(Box<Context>, u64),
+    #[error("{1}")]
+    Hyper(Box<Context>, #[source] hyper::Error),
+    #[error("{1}")]
+    SerdeJson(Box<Context>, #[source] serde_json::Error),
+    #[error("{1}")]
+    Parse(Box<Context>, #[source] ParseError),
+    #[error("{0}")]
+    Store(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    // Short cause of the error, used as a metric label
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::Forbidden(_) => "forbidden",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::NotFound(_) => "not_found",
+            Error::UnknownCode(_) => "unexpected_status",
+            Error::TooManyRequests(..) => "throttled",
+            Error::Transient(..) => "server_error",
+            Error::Busy => "busy",
+            Error::Empty(_) => "empty",
+            Error::BodyTooLarge(..) => "body_too_large",
+            Error::Hyper(_, e) if e.is_timeout() => "timeout",
+            Error::Hyper(_, e) if e.is_connect() => "connect",
+            Error::Hyper(..) => "request",
+            Error::SerdeJson(..) => "decode",
+            Error::Parse(..) => "parse",
+            Error::Store(_) => "store",
+        }
+    }
+
+    pub fn context(&self) -> Option<&Context> {
+        match self {
+            Error::Forbidden(context)
+            | Error::Unauthorized(context)
+            | Error::NotFound(context)
+            | Error::UnknownCode(context)
+            | Error::TooManyRequests(context, _)
+            | Error::Transient(context, _)
+            | Error::BodyTooLarge(context, _)
+            | Error::Hyper(context, _)
+            | Error::SerdeJson(context, _)
+            | Error::Parse(context, _) => Some(context),
+            Error::Busy | Error::Empty(_) | Error::Store(_) => None,
+        }
+    }
+
+    // Name the endpoint of errors raised after the response was received, such as while parsing it
+    pub fn at(mut self, path: &str) -> Self {
+        match &mut self {
+            Error::SerdeJson(context, _) | Error::Parse(context, _) | Error::Hyper(context, _) if context.endpoint.is_empty() => {
+                **context = Context::new(path);
+            }
+            _ => (),
         }
+        self
+    }
+
+    // A json object with the message and whatever is known about the failed call, for logs and responses
+    pub fn json(&self) -> String {
+        let mut object = Map::new();
+        object.insert("error".to_string(), Value::from(self.to_string()));
+        if let Some(context) = self.context().filter(|c| !c.endpoint.is_empty()) {
+            object.insert("endpoint".to_string(), Value::from(context.endpoint.as_str()));
+            if let Some(status) = context.status {
+                object.insert("status".to_string(), Value::from(status));
+            }
+            if let Some(request_id) = &context.request_id {
+                object.insert("request_id".to_string(), Value::from(request_id.as_str()));
+            }
+            if let Some(trace_id) = &context.trace_id {
+                object.insert("trace_id".to_string(), Value::from(trace_id.as_str()));
+            }
+            if let Some(excerpt) = &context.excerpt {
+                object.insert("response".to_string(), Value::from(excerpt.as_str()));
+            }
+        }
+        Value::Object(object).to_string()
     }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        let payload = self.to_string();
+        let payload = self.json();
         let body = body::boxed(body::Full::from(payload));
 
         Response::builder()
@@ -45,12 +163,18 @@ impl IntoResponse for Error {
 
 impl From<hyper::Error> for Error {
     fn from(err: hyper::Error) -> Error {
-        Error::Hyper(err)
+        Error::Hyper(Box::default(), err)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Error {
+        Error::Parse(Box::default(), err)
     }
 }
 
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Error {
-        Error::SerdeJson(err)
+        Error::SerdeJson(Box::default(), err)
     }
 }
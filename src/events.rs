@@ -0,0 +1,149 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::Extension;
+use axum::response::Response;
+use chrono::{Datelike, TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use crate::config::Config;
+use crate::money::to_f64;
+use crate::snapshot::Snapshot;
+use crate::state::State;
+
+// Something worth telling a chat bot about, found by comparing a snapshot with the one before it
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    // The monthly cost of a deployment went over its budget since the previous poll
+    BudgetBreach {
+        timestamp: i64,
+        org_id: Option<String>,
+        id: String,
+        name: String,
+        monthly_cost: Decimal,
+        budget_limit: Decimal,
+        utilization: Decimal,
+    },
+    // The hourly rate of a deployment grew by at least the anomaly ratio since the previous poll
+    Anomaly {
+        timestamp: i64,
+        org_id: Option<String>,
+        id: String,
+        name: String,
+        hourly_rate: Decimal,
+        previous_hourly_rate: Decimal,
+        ratio: f64,
+    },
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::BudgetBreach { .. } => "budget_breach",
+            Event::Anomaly { .. } => "anomaly",
+        }
+    }
+
+    // Events are only found between two polls of the same billing month, so restarts and
+    // month rollovers do not repeat or invent them
+    pub fn detect(snapshot: &Snapshot, previous: Option<&Snapshot>, config: &Config, anomaly_ratio: f64) -> Vec<Event> {
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return Vec::new(),
+        };
+        let (now, then) = (Utc.timestamp(snapshot.timestamp, 0), Utc.timestamp(previous.timestamp, 0));
+        if (now.year(), now.month()) != (then.year(), then.month()) {
+            return Vec::new();
+        }
+
+        let before: HashMap<(Option<&str>, &str), _> = previous
+            .organizations
+            .iter()
+            .flat_map(|o| o.month.deployments.iter().map(move |d| ((o.organization.id.as_deref(), d.deployment_id.as_str()), d)))
+            .collect();
+
+        let mut events = Vec::new();
+        for organization in &snapshot.organizations {
+            let org_id = organization.organization.id.as_deref();
+            for deployment in &organization.month.deployments {
+                let earlier = match before.get(&(org_id, deployment.deployment_id.as_str())) {
+                    Some(earlier) => earlier,
+                    None => continue,
+                };
+
+                if let Some(budget) = config.budget_for(&deployment.deployment_id, &deployment.deployment_name) {
+                    let utilization = budget.utilization(deployment.costs.total);
+                    if utilization >= Decimal::ONE && budget.utilization(earlier.costs.total) < Decimal::ONE {
+                        events.push(Event::BudgetBreach {
+                            timestamp: snapshot.timestamp,
+                            org_id: org_id.map(str::to_string),
+                            id: deployment.deployment_id.clone(),
+                            name: deployment.deployment_name.clone(),
+                            monthly_cost: deployment.costs.total,
+                            budget_limit: budget.limit,
+                            utilization,
+                        });
+                    }
+                }
+
+                if earlier.hourly_rate > Decimal::ZERO {
+                    let ratio = to_f64(deployment.hourly_rate / earlier.hourly_rate);
+                    if ratio >= anomaly_ratio {
+                        events.push(Event::Anomaly {
+                            timestamp: snapshot.timestamp,
+                            org_id: org_id.map(str::to_string),
+                            id: deployment.deployment_id.clone(),
+                            name: deployment.deployment_name.clone(),
+                            hourly_rate: deployment.hourly_rate,
+                            previous_hourly_rate: earlier.hourly_rate,
+                            ratio,
+                        });
+                    }
+                }
+            }
+        }
+        events
+    }
+
+    // Send the event as json to every /ws/events subscriber
+    pub fn publish(&self, state: &State) {
+        log::info!("{{\"fn\": \"publish\", \"msg\": \"detected event\", \"type\": \"{}\"}}", self.name());
+        metrics::increment_counter!("elastic_billing_events_total", "type" => self.name());
+        if let Ok(event) = serde_json::to_string(self) {
+            let _ = state.events.send(Arc::new(event));
+        }
+    }
+}
+
+// Budget breaches and anomalies as json text messages, as they are detected
+pub async fn ws_events(ws: WebSocketUpgrade, Extension(state): Extension<State>) -> Response {
+    log::info!("{{\"fn\": \"ws_events\", \"method\":\"get\"}}");
+    let events = state.events.subscribe();
+    ws.on_upgrade(move |socket| forward(socket, events))
+}
+
+async fn forward(mut socket: WebSocket, mut events: Receiver<Arc<String>>) {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if socket.send(Message::Text(event.to_string())).await.is_err() {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("{{\"fn\": \"forward\", \"msg\": \"websocket client fell behind, skipped {} events\"}}", skipped);
+                }
+                Err(RecvError::Closed) => return,
+            },
+            // Anything sent by the client is ignored, until it closes the connection
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => (),
+            },
+        }
+    }
+}
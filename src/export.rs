@@ -0,0 +1,157 @@
+use chrono::{Duration, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use url::Url;
+
+use crate::cron::Schedule;
+use crate::history::HistoryEntry;
+use crate::redact;
+#[cfg(feature = "aws")]
+use crate::s3::S3;
+use crate::state::State;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Daily dumps of the history, listed under exports in the config file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ExportConfig {
+    S3 {
+        bucket: String,
+        region: String,
+        #[serde(default, serialize_with = "redact::serialize_optional_url")]
+        endpoint: Option<Url>,
+        #[serde(default = "default_key")]
+        key: String,
+    },
+}
+
+// An export with the schedule it runs on, exporting the day before every run
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportJob {
+    #[serde(default = "default_schedule")]
+    pub schedule: Schedule,
+    #[serde(flatten)]
+    pub export: ExportConfig,
+}
+
+fn default_schedule() -> Schedule {
+    "@daily".parse().expect("valid schedule")
+}
+
+fn default_key() -> String {
+    "elastic-billing/{org_id}/{date}.jsonl".to_string()
+}
+
+// Without any export features compiled in this has no variants, and new always fails
+#[derive(Clone, Debug)]
+pub enum Export {
+    #[cfg(feature = "aws")]
+    S3 { s3: S3, key: String },
+}
+
+impl Export {
+    #[cfg_attr(not(feature = "aws"), allow(unused_variables))]
+    pub fn new(config: &ExportConfig, timeout: u64) -> BoxResult<Self> {
+        match config {
+            #[cfg(feature = "aws")]
+            ExportConfig::S3 { bucket, region, endpoint, key } => Ok(Export::S3 {
+                s3: S3::new(bucket, region, endpoint.clone(), timeout)?,
+                key: key.clone(),
+            }),
+            #[cfg(not(feature = "aws"))]
+            ExportConfig::S3 { .. } => Err("the s3 export requires building with the aws feature".into()),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "aws")]
+            Export::S3 { .. } => "s3",
+        }
+    }
+
+    // Write the polls of a day as json lines, one object per organization
+    #[cfg(feature = "aws")]
+    pub async fn export(&self, entries: &[HistoryEntry], day: NaiveDate) -> BoxResult<()> {
+        let mut organizations: std::collections::BTreeMap<&str, Vec<u8>> = Default::default();
+        for entry in entries {
+            let body = organizations.entry(entry.org_id.as_deref().unwrap_or("default")).or_default();
+            serde_json::to_writer(&mut *body, entry)?;
+            body.push(b'\n');
+        }
+
+        for (org_id, body) in organizations {
+            match self {
+                Export::S3 { s3, key } => {
+                    let key = render(key, day, org_id);
+                    s3.put_object(&key, body, "application/x-ndjson").await?;
+                    log::info!("{{\"fn\": \"export\", \"msg\": \"exported history\", \"type\": \"s3\", \"key\": \"{}\"}}", key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "aws"))]
+    pub async fn export(&self, _: &[HistoryEntry], _: NaiveDate) -> BoxResult<()> {
+        match *self {}
+    }
+}
+
+// Fill in {date}, {year}, {month}, {day} and {org_id}
+#[cfg(feature = "aws")]
+fn render(template: &str, day: NaiveDate, org_id: &str) -> String {
+    template
+        .replace("{date}", &day.format("%Y-%m-%d").to_string())
+        .replace("{year}", &day.format("%Y").to_string())
+        .replace("{month}", &day.format("%m").to_string())
+        .replace("{day}", &day.format("%d").to_string())
+        .replace("{org_id}", org_id)
+}
+
+// Export the previous day's history on every run of the schedule, on the leader only
+pub async fn scheduled(state: State, schedule: Schedule, export: Export) {
+    loop {
+        let fired = match schedule.tick().await {
+            Some(fired) => fired,
+            None => {
+                log::error!("{{\"fn\": \"scheduled\", \"msg\": \"schedule never runs\", \"schedule\": \"{}\"}}", schedule);
+                return;
+            }
+        };
+
+        if !state.is_leader() {
+            continue;
+        }
+        let day = (fired - Duration::days(1)).date().naive_utc();
+        run(&state, std::slice::from_ref(&export), day).await;
+    }
+}
+
+pub async fn run(state: &State, exports: &[Export], day: NaiveDate) {
+    let store = match &state.store {
+        Some(store) => store,
+        None => return,
+    };
+    let start = Utc.from_utc_date(&day).and_hms(0, 0, 0).timestamp();
+    let end = start + 86400;
+    let entries = match store.read_lines("history.jsonl", move |e: &HistoryEntry| e.timestamp >= start && e.timestamp < end).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::error!("{{\"fn\": \"run\", \"msg\": \"failed reading history\", \"error\": \"{}\"}}", e);
+            return;
+        }
+    };
+
+    for export in exports {
+        let status = match export.export(&entries, day).await {
+            Ok(()) => "success",
+            Err(e) => {
+                log::error!("{{\"fn\": \"run\", \"msg\": \"export failed\", \"type\": \"{}\", \"error\": \"{}\"}}", export.name(), e);
+                "failure"
+            }
+        };
+        metrics::increment_counter!("elastic_billing_exports_total", "type" => export.name(), "status" => status);
+    }
+}
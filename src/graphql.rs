@@ -0,0 +1,196 @@
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Enum, Object, Schema, SimpleObject};
+use axum::{extract::Extension, Json};
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+
+use crate::history::{self, Downsample, HistoryQuery};
+use crate::money::to_f64;
+use crate::report::Report;
+use crate::state::State;
+
+pub type BillingSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+// Read only queries over the current snapshot and the persisted history, for questions the json api does not answer
+pub fn schema(state: State) -> BillingSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription).data(state).finish()
+}
+
+pub async fn graphql(Extension(schema): Extension<BillingSchema>, Json(request): Json<async_graphql::Request>) -> Json<async_graphql::Response> {
+    log::info!("{{\"fn\": \"graphql\", \"method\":\"post\"}}");
+    Json(schema.execute(request).await)
+}
+
+pub struct Query;
+
+#[derive(SimpleObject)]
+struct Costs {
+    // Unix time of the poll, null before the first one
+    timestamp: Option<i64>,
+    deployments: Vec<DeploymentCosts>,
+}
+
+#[derive(SimpleObject)]
+struct DeploymentCosts {
+    org_id: Option<String>,
+    id: String,
+    name: String,
+    daily_cost: f64,
+    monthly_cost: f64,
+    hourly_rate: f64,
+    forecast: f64,
+}
+
+#[derive(SimpleObject)]
+struct HistoryEntry {
+    timestamp: i64,
+    org_id: Option<String>,
+    id: String,
+    name: String,
+    daily_cost: f64,
+    monthly_cost: f64,
+    hourly_rate: f64,
+}
+
+#[derive(SimpleObject)]
+struct MonthlyCosts {
+    month: String,
+    total: f64,
+    rows: Vec<CostRow>,
+}
+
+#[derive(SimpleObject)]
+struct CostRow {
+    key: String,
+    cost: f64,
+    deployments: usize,
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+enum CostsBy {
+    Group,
+    CostCenter,
+    Deployment,
+}
+
+impl CostsBy {
+    fn report_by(&self) -> &'static str {
+        match self {
+            CostsBy::Group => "group",
+            CostsBy::CostCenter => "cost-center",
+            CostsBy::Deployment => "deployment",
+        }
+    }
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+enum HistoryDownsample {
+    Daily,
+    Weekly,
+}
+
+#[Object]
+impl Query {
+    // Current costs and month end forecast per deployment, as /api/v1/costs
+    async fn costs(&self, ctx: &Context<'_>) -> Costs {
+        let state = ctx.data_unchecked::<State>();
+        let snapshot = state.snapshot.read().unwrap().clone();
+        Costs {
+            timestamp: snapshot.as_ref().map(|s| s.timestamp),
+            deployments: snapshot
+                .iter()
+                .flat_map(|s| s.costs())
+                .map(|costs| DeploymentCosts {
+                    org_id: costs.org_id,
+                    id: costs.id,
+                    name: costs.name,
+                    daily_cost: to_f64(costs.daily_cost),
+                    monthly_cost: to_f64(costs.monthly_cost),
+                    hourly_rate: to_f64(costs.hourly_rate),
+                    forecast: to_f64(costs.forecast),
+                })
+                .collect(),
+        }
+    }
+
+    // Persisted costs of every poll between two unix times, as /api/v1/history
+    async fn history(
+        &self,
+        ctx: &Context<'_>,
+        deployment: Option<String>,
+        from: Option<i64>,
+        to: Option<i64>,
+        downsample: Option<HistoryDownsample>,
+    ) -> async_graphql::Result<Vec<HistoryEntry>> {
+        let state = ctx.data_unchecked::<State>();
+        let query = HistoryQuery {
+            deployment,
+            from: timestamp("from", from)?,
+            to: timestamp("to", to)?,
+            downsample: downsample.map(|d| match d {
+                HistoryDownsample::Daily => Downsample::Daily,
+                HistoryDownsample::Weekly => Downsample::Weekly,
+            }),
+            ..HistoryQuery::default()
+        };
+        let page = state.get_history(&query).await?.ok_or("History requires --data-dir")?;
+        Ok(page
+            .history
+            .into_iter()
+            .map(|entry| HistoryEntry {
+                timestamp: entry.timestamp,
+                org_id: entry.org_id,
+                id: entry.id,
+                name: entry.name,
+                daily_cost: to_f64(entry.daily_cost),
+                monthly_cost: to_f64(entry.monthly_cost),
+                hourly_rate: to_f64(entry.hourly_rate),
+            })
+            .collect())
+    }
+
+    // Spend per group, cost center or deployment over the last months, newest first and
+    // starting with the current month to date, as the monthly reports
+    async fn monthly_costs(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default_with = "CostsBy::Group")] by: CostsBy,
+        #[graphql(default = 1, validator(minimum = 1, maximum = 24))] months: u32,
+    ) -> async_graphql::Result<Vec<MonthlyCosts>> {
+        let state = ctx.data_unchecked::<State>();
+        let store = state.store.as_ref().ok_or("History requires --data-dir")?;
+
+        let mut month = (Utc::now().year(), Utc::now().month());
+        let mut wanted = vec![month];
+        for _ in 1..months {
+            month = match month {
+                (year, 1) => (year - 1, 12),
+                (year, m) => (year, m - 1),
+            };
+            wanted.push(month);
+        }
+
+        let start = Utc.ymd(month.0, month.1, 1).and_hms(0, 0, 0).timestamp();
+        let entries = store.read_lines("history.jsonl", move |e: &history::HistoryEntry| e.timestamp >= start).await?;
+        Ok(wanted
+            .into_iter()
+            .map(|(year, month)| {
+                let report = Report::new(&entries, year, month, by.report_by(), &state.config, state.cost_centers.as_ref());
+                MonthlyCosts {
+                    month: report.month,
+                    total: to_f64(report.total),
+                    rows: report
+                        .rows
+                        .into_iter()
+                        .map(|row| CostRow { key: row.key, cost: to_f64(row.cost), deployments: row.deployments })
+                        .collect(),
+                }
+            })
+            .collect())
+    }
+}
+
+// Unix seconds from the query, which may be out of range
+fn timestamp(field: &str, seconds: Option<i64>) -> async_graphql::Result<Option<DateTime<Utc>>> {
+    seconds
+        .map(|seconds| Utc.timestamp_opt(seconds, 0).single().ok_or_else(|| format!("{} is not a valid unix timestamp", field).into()))
+        .transpose()
+}
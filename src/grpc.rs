@@ -0,0 +1,106 @@
+use chrono::{DateTime, TimeZone, Utc};
+use std::error::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::history::HistoryQuery;
+use crate::money::to_f64;
+use crate::state::State;
+
+mod proto {
+    tonic::include_proto!("elastic_billing.v1");
+}
+
+use proto::billing_server::{Billing, BillingServer};
+use proto::{Costs, DeploymentCosts, GetCostsRequest, GetHistoryRequest, History, HistoryEntry};
+
+// GetCosts and GetHistory answer from the same snapshot and history as the json api
+struct BillingService {
+    state: State,
+}
+
+#[tonic::async_trait]
+impl Billing for BillingService {
+    async fn get_costs(&self, _: Request<GetCostsRequest>) -> Result<Response<Costs>, Status> {
+        log::info!("{{\"fn\": \"get_costs\", \"method\":\"grpc\"}}");
+        let snapshot = self.state.snapshot.read().unwrap().clone();
+        let costs = match snapshot {
+            Some(snapshot) => Costs {
+                timestamp: Some(snapshot.timestamp),
+                deployments: snapshot
+                    .costs()
+                    .into_iter()
+                    .map(|costs| DeploymentCosts {
+                        org_id: costs.org_id,
+                        id: costs.id,
+                        name: costs.name,
+                        daily_cost: to_f64(costs.daily_cost),
+                        monthly_cost: to_f64(costs.monthly_cost),
+                        hourly_rate: to_f64(costs.hourly_rate),
+                        forecast: to_f64(costs.forecast),
+                    })
+                    .collect(),
+            },
+            None => Costs::default(),
+        };
+        Ok(Response::new(costs))
+    }
+
+    async fn get_history(&self, request: Request<GetHistoryRequest>) -> Result<Response<History>, Status> {
+        log::info!("{{\"fn\": \"get_history\", \"method\":\"grpc\"}}");
+        let request = request.into_inner();
+        let query = HistoryQuery {
+            deployment: request.deployment,
+            from: timestamp("from", request.from).map_err(Status::invalid_argument)?,
+            to: timestamp("to", request.to).map_err(Status::invalid_argument)?,
+            downsample: request.downsample.map(|d| d.parse()).transpose().map_err(Status::invalid_argument)?,
+            limit: request.limit.map(|limit| limit as usize),
+            cursor: request.cursor.map(|cursor| cursor as usize),
+        };
+
+        let page = match self.state.get_history(&query).await {
+            Ok(Some(page)) => page,
+            Ok(None) => return Err(Status::failed_precondition("History requires --data-dir")),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+        Ok(Response::new(History {
+            history: page
+                .history
+                .into_iter()
+                .map(|entry| HistoryEntry {
+                    timestamp: entry.timestamp,
+                    org_id: entry.org_id,
+                    id: entry.id,
+                    name: entry.name,
+                    daily_cost: to_f64(entry.daily_cost),
+                    monthly_cost: to_f64(entry.monthly_cost),
+                    hourly_rate: to_f64(entry.hourly_rate),
+                })
+                .collect(),
+            next_cursor: page.next_cursor.map(|cursor| cursor as u64),
+        }))
+    }
+}
+
+// Unix seconds from the client, which may be out of range
+fn timestamp(field: &str, seconds: Option<i64>) -> Result<Option<DateTime<Utc>>, String> {
+    seconds
+        .map(|seconds| Utc.timestamp_opt(seconds, 0).single().ok_or_else(|| format!("{} is not a valid unix timestamp", field)))
+        .transpose()
+}
+
+// Bind the grpc port right away, so startup fails when it is taken, and return the server to run
+// next to the http server
+pub fn serve(state: State, port: u16) -> Result<impl Future<Output = ()>, Box<dyn Error + Send + Sync>> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let incoming = TcpIncoming::new(addr, true, None).map_err(|e| format!("failed binding the grpc port {}: {}", port, e))?;
+    println!("Serving grpc on {}", addr);
+    Ok(async move {
+        if let Err(e) = Server::builder().add_service(BillingServer::new(BillingService { state })).serve_with_incoming(incoming).await {
+            log::error!("{{\"fn\": \"serve\", \"msg\": \"grpc server failed\", \"error\": \"{}\"}}", e);
+        }
+    })
+}
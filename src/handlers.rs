@@ -1,44 +1,193 @@
 use axum::{
-    extract::{OriginalUri, Extension},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{OriginalUri, Extension, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{sse::{Event, KeepAlive, Sse}, Html, IntoResponse, Response},
     Json,
 };
 use clap::{crate_description, crate_name, crate_version};
+use futures::stream::{self, Stream, StreamExt};
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
 use serde_json::json;
 use serde_json::Value;
 use metrics_exporter_prometheus::PrometheusHandle;
 
 use crate::error::Error as RestError;
-use crate::State;
-
-// This is required in order to get the method from the request
-#[derive(Debug)]
-pub struct RequestMethod(pub hyper::Method);
+use crate::history::HistoryQuery;
+use crate::metrics::record_build_info;
+use crate::state::State;
 
 pub async fn metrics(Extension(recorder_handle): Extension<PrometheusHandle>, Extension(state): Extension<State>) -> Result<String, RestError> {
     log::info!("{{\"fn\": \"metrics\", \"method\":\"get\"}}");
-    state.get_metrics().await?;
+    if state.interval == 0 && state.is_leader() && !state.is_paused() {
+        // Concurrent scrapes are served the snapshot of the poll already running
+        match state.get_metrics().await {
+            Ok(()) | Err(RestError::Busy) => (),
+            Err(e) => return Err(e),
+        }
+    } else if state.interval == 0 {
+        state.load_shared_snapshot().await;
+    }
+
+    // Background polls render the metrics once per poll
+    if let Some(rendered) = state.rendered.read().unwrap().as_ref() {
+        return Ok(rendered.clone());
+    }
+
+    state.record_metrics();
+    record_build_info();
     Ok(recorder_handle.render())
 }
 
-pub async fn health() -> Json<Value> {
+pub async fn history(Extension(state): Extension<State>, Query(query): Query<HistoryQuery>) -> Result<Response, RestError> {
+    log::info!("{{\"fn\": \"history\", \"method\":\"get\"}}");
+    match state.get_history(&query).await? {
+        Some(page) => Ok(Json(page).into_response()),
+        None => Ok((
+            StatusCode::NOT_FOUND,
+            "{\"error_code\": 404, \"message\": \"History requires --data-dir\"}",
+        )
+            .into_response()),
+    }
+}
+
+// Poll right away instead of waiting for the next interval, e.g. after changing budgets
+pub async fn admin_poll(Extension(recorder_handle): Extension<PrometheusHandle>, Extension(state): Extension<State>) -> Result<Response, RestError> {
+    log::info!("{{\"fn\": \"admin_poll\", \"method\":\"post\"}}");
+    if !state.is_leader() {
+        return Ok((StatusCode::CONFLICT, Json(json!({ "msg": "Not the leader, poll the leader instead"}))).into_response());
+    }
+
+    state.get_metrics().await?;
+    if state.interval > 0 {
+        state.publish(&recorder_handle);
+    }
+
+    let snapshot = state.snapshot.read().unwrap();
+    let timestamp = snapshot.as_ref().map(|s| s.timestamp);
+    Ok(Json(json!({ "msg": "Polled", "timestamp": timestamp })).into_response())
+}
+
+pub async fn admin_pause(Extension(state): Extension<State>) -> Json<Value> {
+    log::info!("{{\"fn\": \"admin_pause\", \"method\":\"post\"}}");
+    state.set_paused(true);
+    Json(json!({ "msg": "Paused" }))
+}
+
+pub async fn admin_resume(Extension(state): Extension<State>) -> Json<Value> {
+    log::info!("{{\"fn\": \"admin_resume\", \"method\":\"post\"}}");
+    state.set_paused(false);
+    Json(json!({ "msg": "Resumed" }))
+}
+
+pub async fn health(Extension(state): Extension<State>) -> (StatusCode, Json<Value>) {
     log::info!("{{\"fn\": \"health\", \"method\":\"get\"}}");
-    Json(json!({ "msg": "Healthy"}))
+    if state.is_healthy() {
+        (StatusCode::OK, Json(json!({ "msg": "Healthy"})))
+    } else {
+        log::error!("{{\"fn\": \"health\", \"msg\": \"no successful poll within {} intervals\"}}", state.stale_intervals);
+        (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "msg": "Unhealthy"})))
+    }
 }
 
-pub async fn root() -> Json<Value> {
+// Browsers get the web ui, everything else gets the version info
+pub async fn root(headers: HeaderMap) -> Response {
     log::info!("{{\"fn\": \"root\", \"method\":\"get\"}}");
+    let html = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("text/html"))
+        .unwrap_or(false);
+
+    if html {
+        return Html(include_str!("index.html")).into_response();
+    }
+
     Json(
         json!({ "version": crate_version!(), "name": crate_name!(), "description": crate_description!()}),
     )
+    .into_response()
+}
+
+pub async fn costs(Extension(state): Extension<State>) -> Json<Value> {
+    log::info!("{{\"fn\": \"costs\", \"method\":\"get\"}}");
+    let snapshot = state.snapshot.read().unwrap().clone();
+    match snapshot {
+        Some(snapshot) => Json(json!({ "timestamp": snapshot.timestamp, "deployments": snapshot.costs() })),
+        None => Json(json!({ "timestamp": null, "deployments": [] })),
+    }
+}
+
+// A costs event with the current snapshot, then one after every poll, in the format of /api/v1/costs
+pub async fn stream(Extension(state): Extension<State>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    log::info!("{{\"fn\": \"stream\", \"method\":\"get\"}}");
+    let updates = state.updates.subscribe();
+    let current = state
+        .snapshot
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|snapshot| json!({ "timestamp": snapshot.timestamp, "deployments": snapshot.costs() }).to_string());
+
+    let first = stream::iter(current.map(|costs| Ok(Event::default().event("costs").data(costs))));
+    let next = stream::unfold(updates, |mut updates| async move {
+        loop {
+            match updates.recv().await {
+                Ok(costs) => return Some((Ok(Event::default().event("costs").data(costs.as_str())), updates)),
+                // Slow clients skip ahead to the newest snapshot
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(first.chain(next)).keep_alive(KeepAlive::default())
+}
+
+// The effective configuration, to check what a running instance actually loaded
+pub async fn config(Extension(state): Extension<State>) -> Json<Value> {
+    log::info!("{{\"fn\": \"config\", \"method\":\"get\"}}");
+    Json(state.effective_config())
+}
+
+// Cost changes per deployment between the last two polls, for chasing down a spike
+pub async fn diff(Extension(state): Extension<State>) -> Json<Value> {
+    log::info!("{{\"fn\": \"diff\", \"method\":\"get\"}}");
+    let snapshot = state.snapshot.read().unwrap().clone();
+    let previous = state.previous.read().unwrap().clone();
+    match snapshot {
+        Some(snapshot) => Json(json!({
+            "timestamp": snapshot.timestamp,
+            "previous_timestamp": previous.as_ref().map(|p| p.timestamp),
+            "deployments": snapshot.diff(previous.as_ref()),
+        })),
+        None => Json(json!({ "timestamp": null, "previous_timestamp": null, "deployments": [] })),
+    }
+}
+
+pub async fn openapi() -> Json<Value> {
+    log::info!("{{\"fn\": \"openapi\", \"method\":\"get\"}}");
+    Json(crate::openapi::openapi())
 }
 
 pub async fn help() -> Json<Value> {
     log::info!("{{\"fn\": \"help\", \"method\":\"get\"}}");
     let payload = json!({"paths": {
-            "/health": "Get the health of the api",
+            "/health": "Get the health of the api, failing when background polls are stale",
+            "/healthz": "Alias of /health for kubernetes probes",
             "/metrics": "Get Elastic Billing Metrics",
+            "/api/v1/costs": "Get current costs and month end forecast per deployment",
+            "/api/v1/history": "Get persisted costs, filtered by deployment, from and to",
+            "/api/v1/diff": "Get cost changes per deployment since the previous poll, midnight and the start of the month",
+            "/api/openapi.json": "Get the OpenAPI description of the json endpoints",
+            "/config": "Get the effective configuration, with secrets redacted",
+            "/admin/poll": "Start a poll now, served with --admin-token",
+            "/admin/pause": "Pause background polls, served with --admin-token",
+            "/admin/resume": "Resume background polls, served with --admin-token",
+            "/api/v1/stream": "Stream costs as server sent events after every poll",
+            "/graphql": "Query costs with graphql, served with --graphql",
+            "/ws/events": "Stream budget breaches and cost anomalies over a websocket",
+            "/debug/pprof/profile": "Get a cpu profile, served with --pprof",
+            "/debug/pprof/heap": "Get a heap profile, served with --pprof",
             "/help": "Show this help message"
         }
     });
@@ -0,0 +1,175 @@
+use chrono::{DateTime, TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::snapshot::Snapshot;
+use crate::store::Store;
+
+// Per deployment costs from a single poll, appended to the history file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub timestamp: i64,
+    pub org_id: Option<String>,
+    pub id: String,
+    pub name: String,
+    pub daily_cost: Decimal,
+    pub monthly_cost: Decimal,
+    pub hourly_rate: Decimal,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct HistoryQuery {
+    pub deployment: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub downsample: Option<Downsample>,
+    pub limit: Option<usize>,
+    pub cursor: Option<usize>,
+}
+
+// Keep one entry per deployment and utc day or week, weeks starting on monday
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Downsample {
+    Daily,
+    Weekly,
+}
+
+impl FromStr for Downsample {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Downsample::Daily),
+            "weekly" => Ok(Downsample::Weekly),
+            _ => Err(format!("unknown downsample {}", s)),
+        }
+    }
+}
+
+impl Downsample {
+    fn bucket(&self, timestamp: i64) -> i64 {
+        let day = timestamp - timestamp.rem_euclid(86400);
+        match self {
+            Downsample::Daily => day,
+            // The unix epoch was a thursday
+            Downsample::Weekly => day - (day / 86400 + 3).rem_euclid(7) * 86400,
+        }
+    }
+}
+
+// Drop entries older than the retention at startup and every day after
+pub async fn prune(store: Store, days: u64) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(86400));
+    loop {
+        interval.tick().await;
+        let cutoff = Utc::now().timestamp() - days as i64 * 86400;
+        let pruner = store.clone();
+        let result = tokio::task::spawn_blocking(move || pruner.prune("history.jsonl", |entry: &HistoryEntry| entry.timestamp >= cutoff)).await;
+        match result {
+            Ok(Ok(dropped)) => log::info!("{{\"fn\": \"prune\", \"msg\": \"pruned history\", \"dropped\": {}}}", dropped),
+            Ok(Err(e)) => log::error!("{{\"fn\": \"prune\", \"msg\": \"failed pruning history\", \"error\": \"{}\"}}", e),
+            Err(e) => log::error!("{{\"fn\": \"prune\", \"msg\": \"failed pruning history\", \"error\": \"{}\"}}", e),
+        }
+    }
+}
+
+// One page of history, the cursor is passed back to get the next one
+#[derive(Serialize, Debug)]
+pub struct HistoryPage {
+    pub history: Vec<HistoryEntry>,
+    pub next_cursor: Option<usize>,
+}
+
+impl HistoryPage {
+    // The history file is only appended to between prunes, so an offset into the matching entries stays valid between pages
+    pub fn new(entries: Vec<HistoryEntry>, query: &HistoryQuery) -> Self {
+        let entries = match query.downsample {
+            Some(downsample) => downsample_entries(entries, downsample),
+            None => entries,
+        };
+
+        let start = query.cursor.unwrap_or(0).min(entries.len());
+        let end = match query.limit {
+            Some(limit) => start.saturating_add(limit).min(entries.len()),
+            None => entries.len(),
+        };
+        HistoryPage {
+            next_cursor: if end < entries.len() { Some(end) } else { None },
+            history: entries.into_iter().skip(start).take(end - start).collect(),
+        }
+    }
+}
+
+// Bucket start, organization and deployment
+type BucketKey = (i64, Option<String>, String);
+
+// The last poll of every bucket carries its final daily and monthly costs, the hourly rate is averaged
+fn downsample_entries(entries: Vec<HistoryEntry>, downsample: Downsample) -> Vec<HistoryEntry> {
+    let mut buckets: BTreeMap<BucketKey, (HistoryEntry, Decimal, usize)> = BTreeMap::new();
+    for entry in entries {
+        let key = (downsample.bucket(entry.timestamp), entry.org_id.clone(), entry.id.clone());
+        let rate = entry.hourly_rate;
+        match buckets.get_mut(&key) {
+            Some((last, sum, count)) => {
+                *sum += rate;
+                *count += 1;
+                if entry.timestamp >= last.timestamp {
+                    *last = entry;
+                }
+            }
+            None => {
+                buckets.insert(key, (entry, rate, 1));
+            }
+        }
+    }
+
+    buckets
+        .into_values()
+        .map(|(mut entry, sum, count)| {
+            entry.hourly_rate = sum / Decimal::from(count);
+            entry
+        })
+        .collect()
+}
+
+impl HistoryEntry {
+    pub fn from_snapshot(snapshot: &Snapshot) -> Vec<HistoryEntry> {
+        snapshot
+            .costs()
+            .into_iter()
+            .map(|costs| HistoryEntry {
+                timestamp: snapshot.timestamp,
+                org_id: costs.org_id,
+                id: costs.id,
+                name: costs.name,
+                daily_cost: costs.daily_cost,
+                monthly_cost: costs.monthly_cost,
+                hourly_rate: costs.hourly_rate,
+            })
+            .collect()
+    }
+
+    // Deployments can be queried by either id or name
+    pub fn matches(&self, query: &HistoryQuery) -> bool {
+        if let Some(deployment) = &query.deployment {
+            if &self.id != deployment && &self.name != deployment {
+                return false;
+            }
+        }
+        let timestamp = Utc.timestamp(self.timestamp, 0);
+        if let Some(from) = query.from {
+            if timestamp < from {
+                return false;
+            }
+        }
+        if let Some(to) = query.to {
+            if timestamp > to {
+                return false;
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,91 @@
+use chrono::{Datelike, TimeZone, Utc};
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::process::Command;
+
+use crate::config::{Config, ThresholdCommand};
+use crate::snapshot::Snapshot;
+
+// Runs the configured command once per deployment, threshold and month when budget utilization crosses it
+#[derive(Clone, Debug)]
+pub struct ThresholdHook {
+    command: ThresholdCommand,
+    fired: Arc<Mutex<HashSet<String>>>,
+}
+
+impl ThresholdHook {
+    pub fn new(command: ThresholdCommand) -> Self {
+        ThresholdHook {
+            command,
+            fired: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn check(&self, snapshot: &Snapshot, config: &Config) {
+        let now = Utc.timestamp(snapshot.timestamp, 0);
+        let month = format!("{:04}-{:02}", now.year(), now.month());
+
+        for deployment in snapshot.organizations.iter().flat_map(|o| &o.month.deployments) {
+            let budget = match config.budget_for(&deployment.deployment_id, &deployment.deployment_name) {
+                Some(budget) => budget,
+                None => continue,
+            };
+            let utilization = budget.utilization(deployment.costs.total);
+
+            // Only the highest crossed threshold runs, so a deployment jumping past several fires once
+            let threshold = self
+                .command
+                .thresholds
+                .iter()
+                .copied()
+                .filter(|t| Decimal::from_f64(*t).is_some_and(|t| utilization >= t))
+                .max_by(|a, b| a.total_cmp(b));
+            let threshold = match threshold {
+                Some(threshold) => threshold,
+                None => continue,
+            };
+
+            let key = format!("{}/{}/{}", month, deployment.deployment_id, threshold);
+            {
+                let mut fired = self.fired.lock().unwrap();
+                if fired.contains(&key) {
+                    continue;
+                }
+                // Mark every lower threshold too, so they do not fire after the highest one
+                for t in self.command.thresholds.iter().filter(|t| **t <= threshold) {
+                    fired.insert(format!("{}/{}/{}", month, deployment.deployment_id, t));
+                }
+            }
+
+            let mut command = Command::new(&self.command.command);
+            command
+                .args(&self.command.args)
+                .env("ELASTIC_BILLING_DEPLOYMENT_ID", &deployment.deployment_id)
+                .env("ELASTIC_BILLING_DEPLOYMENT_NAME", &deployment.deployment_name)
+                .env("ELASTIC_BILLING_MONTHLY_COST", deployment.costs.total.to_string())
+                .env("ELASTIC_BILLING_BUDGET_LIMIT", budget.limit.to_string())
+                .env("ELASTIC_BILLING_UTILIZATION", utilization.to_string())
+                .env("ELASTIC_BILLING_THRESHOLD", threshold.to_string())
+                .env("ELASTIC_BILLING_MONTH", &month);
+
+            let name = deployment.deployment_name.clone();
+            tokio::spawn(async move {
+                let status = match command.status().await {
+                    Ok(status) if status.success() => "success",
+                    Ok(status) => {
+                        log::error!("{{\"fn\": \"check\", \"msg\": \"threshold command failed\", \"deployment\": \"{}\", \"status\": \"{}\"}}", name, status);
+                        "failure"
+                    }
+                    Err(e) => {
+                        log::error!("{{\"fn\": \"check\", \"msg\": \"threshold command did not run\", \"deployment\": \"{}\", \"error\": \"{}\"}}", name, e);
+                        "failure"
+                    }
+                };
+                metrics::increment_counter!("elastic_billing_threshold_commands_total", "status" => status);
+            });
+            log::info!("{{\"fn\": \"check\", \"msg\": \"budget threshold crossed\", \"deployment\": \"{}\", \"threshold\": {}}}", deployment.deployment_name, threshold);
+        }
+    }
+}
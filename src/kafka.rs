@@ -0,0 +1,125 @@
+use chrono::Utc;
+use futures::future::join_all;
+use metrics::Label;
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::sink::{KafkaAuth, Sink};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Publishes a json record per deployment and poll to a kafka topic, straight to the brokers
+pub struct KafkaSink {
+    brokers: String,
+    topic: String,
+    producer: FutureProducer,
+    records: Mutex<BTreeMap<(String, String), DeploymentRecord>>,
+}
+
+impl fmt::Debug for KafkaSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KafkaSink").field("brokers", &self.brokers).field("topic", &self.topic).finish()
+    }
+}
+
+// Every series of a deployment, itemized ones keyed by item
+#[derive(Serialize, Default)]
+struct DeploymentRecord {
+    timestamp: i64,
+    labels: BTreeMap<String, String>,
+    metrics: BTreeMap<&'static str, f64>,
+    items: BTreeMap<&'static str, BTreeMap<String, f64>>,
+}
+
+impl KafkaSink {
+    // Records not delivered within the timeout are given up on, so they never pile up behind a dead cluster
+    pub fn new(brokers: &str, topic: &str, auth: Option<&KafkaAuth>, timeout: u64) -> BoxResult<Self> {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", (timeout * 1000).to_string());
+        if let Some(auth) = auth {
+            config
+                .set("security.protocol", auth.security_protocol.as_deref().unwrap_or("sasl_ssl"))
+                .set("sasl.mechanism", auth.mechanism.as_deref().unwrap_or("PLAIN"))
+                .set("sasl.username", &auth.username)
+                .set("sasl.password", &auth.password);
+        }
+        Ok(KafkaSink {
+            brokers: brokers.to_string(),
+            topic: topic.to_string(),
+            producer: config.create()?,
+            records: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    // Series without a deployment id are organization or group wide, and left out
+    fn push(&self, name: &'static str, value: f64, labels: &[Label]) {
+        let value_of = |key: &str| labels.iter().find(|l| l.key() == key).map(|l| l.value().to_string());
+        let id = match value_of("id") {
+            Some(id) => id,
+            None => return,
+        };
+
+        let mut records = self.records.lock().unwrap();
+        let record = records.entry((value_of("org_id").unwrap_or_default(), id)).or_default();
+        record.timestamp = Utc::now().timestamp();
+        for label in labels.iter().filter(|l| l.key() != "item") {
+            record.labels.insert(label.key().to_string(), label.value().to_string());
+        }
+        match value_of("item") {
+            Some(item) => {
+                record.items.entry(name).or_default().insert(item, value);
+            }
+            None => {
+                record.metrics.insert(name, value);
+            }
+        }
+    }
+
+    async fn produce(producer: FutureProducer, topic: &str, records: Vec<(String, String)>) -> BoxResult<()> {
+        let deliveries = records
+            .iter()
+            .map(|(key, payload)| producer.send(FutureRecord::to(topic).key(key).payload(payload), Timeout::Never));
+        for delivery in join_all(deliveries).await {
+            delivery.map_err(|(e, _)| e)?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for KafkaSink {
+    fn gauge(&self, name: &'static str, value: f64, labels: &[Label]) {
+        self.push(name, value, labels);
+    }
+
+    fn counter(&self, name: &'static str, value: u64, labels: &[Label]) {
+        self.push(name, value as f64, labels);
+    }
+
+    // Keyed by deployment id, so a deployment's records stay in order on one partition
+    fn flush(&self) {
+        let records = std::mem::take(&mut *self.records.lock().unwrap());
+        if records.is_empty() {
+            return;
+        }
+        let records: Vec<(String, String)> = records
+            .into_iter()
+            .filter_map(|((_, id), record)| Some((id, serde_json::to_string(&record).ok()?)))
+            .collect();
+
+        let (producer, topic) = (self.producer.clone(), self.topic.clone());
+        tokio::spawn(async move {
+            if let Err(e) = Self::produce(producer, &topic, records).await {
+                log::error!("{{\"fn\": \"flush\", \"sink\": \"kafka\", \"topic\": \"{}\", \"error\": \"{}\"}}", topic, e);
+                metrics::increment_counter!("elastic_billing_sink_errors_total", "sink" => "kafka");
+            }
+        });
+    }
+}
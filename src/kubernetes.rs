@@ -0,0 +1,39 @@
+use std::error::Error;
+use std::fs;
+use std::time::Duration;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+// Client for the api server of the cluster the exporter runs in, authenticated as the pod's service account
+#[derive(Clone, Debug)]
+pub struct InCluster {
+    pub client: reqwest::Client,
+    pub url: String,
+    pub token: String,
+}
+
+impl InCluster {
+    pub fn new() -> BoxResult<Self> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT")?;
+        let token = fs::read_to_string(format!("{}/token", SERVICE_ACCOUNT))?;
+        let ca = fs::read(format!("{}/ca.crt", SERVICE_ACCOUNT))?;
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(&ca)?)
+            .timeout(Duration::from_secs(10))
+            .build()?;
+
+        Ok(InCluster {
+            client,
+            url: format!("https://{}:{}", host, port),
+            token: token.trim().to_string(),
+        })
+    }
+
+    pub fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client.get(format!("{}{}", self.url, path)).bearer_auth(&self.token)
+    }
+}
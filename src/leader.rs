@@ -0,0 +1,147 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::kubernetes::InCluster;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Kubernetes lease based leader election, so only one replica polls the billing api
+#[derive(Clone, Debug)]
+pub struct LeaderElection {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+    name: String,
+    identity: String,
+    lease_duration: u64,
+    is_leader: Arc<AtomicBool>,
+    last_success: Arc<AtomicI64>,
+}
+
+impl LeaderElection {
+    // Build an elector from the in-cluster service account, lease is given as namespace/name.
+    // Taking over the lease restarts the staleness clock of the health check at last_success
+    pub fn new(lease: &str, lease_duration: u64, is_leader: Arc<AtomicBool>, last_success: Arc<AtomicI64>) -> BoxResult<Self> {
+        let (namespace, name) = lease
+            .split_once('/')
+            .ok_or("leader election lease must be given as namespace/name")?;
+
+        let cluster = InCluster::new()?;
+        let identity = std::env::var("HOSTNAME")?;
+
+        // Start as a standby until the lease is acquired
+        is_leader.store(false, Ordering::Relaxed);
+
+        Ok(LeaderElection {
+            url: format!("{}/apis/coordination.k8s.io/v1/namespaces/{}/leases", cluster.url, namespace),
+            client: cluster.client,
+            token: cluster.token,
+            name: name.to_string(),
+            identity,
+            lease_duration,
+            is_leader,
+            last_success,
+        })
+    }
+
+    // Keep acquiring or renewing the lease, starting one period after the first round from elect
+    pub async fn run(&self) {
+        let period = Duration::from_secs(std::cmp::max(1, self.lease_duration / 3));
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            self.elect().await;
+        }
+    }
+
+    // One round of the election, so startup can tell whether this replica leads before warming up
+    pub async fn elect(&self) {
+        let leader = match self.try_acquire_or_renew().await {
+            Ok(leader) => leader,
+            Err(e) => {
+                log::error!("{{\"fn\": \"leader_election\", \"error\": \"{}\"}}", e);
+                false
+            }
+        };
+
+        let was_leader = self.is_leader.swap(leader, Ordering::Relaxed);
+        if leader && !was_leader {
+            self.last_success.store(Utc::now().timestamp(), Ordering::Relaxed);
+        }
+        if leader != was_leader {
+            log::info!("{{\"fn\": \"leader_election\", \"identity\": \"{}\", \"leader\": {}}}", self.identity, leader);
+        }
+        metrics::gauge!("elastic_billing_exporter_leader", if leader { 1.0 } else { 0.0 });
+    }
+
+    fn spec(&self, acquire_time: &str) -> Value {
+        json!({
+            "holderIdentity": self.identity,
+            "leaseDurationSeconds": self.lease_duration,
+            "acquireTime": acquire_time,
+            "renewTime": Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true)
+        })
+    }
+
+    async fn try_acquire_or_renew(&self) -> BoxResult<bool> {
+        let now = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+        let response = self
+            .client
+            .get(format!("{}/{}", self.url, self.name))
+            .bearer_auth(&self.token)
+            .send()
+            .await?;
+
+        // Create the lease if nobody has yet
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            let body = json!({
+                "apiVersion": "coordination.k8s.io/v1",
+                "kind": "Lease",
+                "metadata": { "name": self.name },
+                "spec": self.spec(&now)
+            });
+            let response = self
+                .client
+                .post(&self.url)
+                .bearer_auth(&self.token)
+                .json(&body)
+                .send()
+                .await?;
+            return Ok(response.status().is_success());
+        }
+
+        let mut lease: Value = response.error_for_status()?.json().await?;
+        let spec = &lease["spec"];
+        let holder = spec["holderIdentity"].as_str().unwrap_or_default();
+        let duration = spec["leaseDurationSeconds"].as_i64().unwrap_or(self.lease_duration as i64);
+        let expired = match spec["renewTime"].as_str().map(DateTime::parse_from_rfc3339) {
+            Some(Ok(renewed)) => renewed.with_timezone(&Utc) + chrono::Duration::seconds(duration) < Utc::now(),
+            _ => true,
+        };
+
+        if holder != self.identity && !holder.is_empty() && !expired {
+            return Ok(false);
+        }
+
+        // Keep the original acquire time when renewing our own lease
+        let acquire_time = match holder == self.identity {
+            true => spec["acquireTime"].as_str().unwrap_or(&now).to_string(),
+            false => now.clone(),
+        };
+        lease["spec"] = self.spec(&acquire_time);
+
+        // The resourceVersion in the lease makes this fail with a conflict if another replica won
+        let response = self
+            .client
+            .put(format!("{}/{}", self.url, self.name))
+            .bearer_auth(&self.token)
+            .json(&lease)
+            .send()
+            .await?;
+        Ok(response.status().is_success())
+    }
+}
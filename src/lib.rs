@@ -0,0 +1,62 @@
+//! Elastic Cloud billing api client and the exporter built on it.
+//!
+//! [`Client`] fetches an organization's [`Costs`] and [`Charts`], normalized the same way
+//! for the legacy and the organization costs api.
+
+mod access;
+mod audit;
+#[cfg(feature = "bigquery")]
+mod bigquery;
+pub mod cli;
+mod client;
+mod collector;
+pub mod config;
+mod cost_centers;
+mod cron;
+mod dashboard;
+mod dump;
+pub mod error;
+mod events;
+mod export;
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod handlers;
+mod history;
+mod hooks;
+mod https;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod kubernetes;
+mod leader;
+mod metadata;
+mod metrics;
+mod money;
+mod openapi;
+#[cfg(feature = "profiling")]
+mod profiling;
+mod redact;
+mod redis;
+mod report;
+mod rules;
+#[cfg(feature = "aws")]
+mod s3;
+pub mod schema;
+mod secret;
+#[cfg(windows)]
+mod service;
+pub mod settings;
+mod sink;
+mod snapshot;
+pub mod state;
+mod store;
+mod systemd;
+mod topology;
+mod webhook;
+mod ytd;
+
+pub use client::{Charts, Client, Costs};
+pub use config::ApiVersion;
+pub use error::Error;
+pub use state::{State, StateBuilder};
@@ -0,0 +1,134 @@
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Labels the exporter sets itself, which the file may not override
+const RESERVED: &[&str] = &["id", "name", "org_id", "org_name", "cost_center", "item", "kind", "tier", "rank", "le", "region", "provider"];
+
+type Labels = BTreeMap<String, String>;
+
+// Extra labels per deployment id, such as owner or service tier, kept in a csv, json or yaml file
+// and reloaded when it changes
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    path: PathBuf,
+    modified: Arc<RwLock<Option<SystemTime>>>,
+    map: Arc<RwLock<HashMap<String, Labels>>>,
+    keys: Arc<RwLock<BTreeSet<String>>>,
+}
+
+impl Metadata {
+    pub fn new(path: &str) -> BoxResult<Self> {
+        let metadata = Metadata {
+            path: PathBuf::from(path),
+            modified: Arc::new(RwLock::new(None)),
+            map: Arc::new(RwLock::new(HashMap::new())),
+            keys: Arc::new(RwLock::new(BTreeSet::new())),
+        };
+        metadata.reload()?;
+        Ok(metadata)
+    }
+
+    // Every key in the file for every deployment, empty where the deployment has no value,
+    // so all series of a metric carry the same label names
+    pub fn get(&self, id: &str) -> Vec<(String, String)> {
+        let map = self.map.read().unwrap();
+        let labels = map.get(id);
+        self.keys
+            .read()
+            .unwrap()
+            .iter()
+            .map(|key| (key.clone(), labels.and_then(|l| l.get(key)).cloned().unwrap_or_default()))
+            .collect()
+    }
+
+    // Read the file again if its modification time moved, returning whether it was reloaded
+    pub fn reload(&self) -> BoxResult<bool> {
+        let modified = fs::metadata(&self.path)?.modified()?;
+        if *self.modified.read().unwrap() == Some(modified) {
+            return Ok(false);
+        }
+
+        let contents = fs::read_to_string(&self.path)?;
+        let map: HashMap<String, Labels> = match self.path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => parse_csv(&contents)?,
+            Some("json") => serde_json::from_str(&contents)?,
+            _ => serde_yaml::from_str(&contents)?,
+        };
+
+        let valid = Regex::new("^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap();
+        let keys: BTreeSet<String> = map.values().flat_map(|labels| labels.keys().cloned()).collect();
+        for key in &keys {
+            if !valid.is_match(key) || key.starts_with("__") {
+                return Err(format!("metadata key {} is not a valid label name", key).into());
+            }
+            if RESERVED.contains(&key.as_str()) {
+                return Err(format!("metadata key {} is already a label of the exporter", key).into());
+            }
+        }
+
+        *self.map.write().unwrap() = map;
+        *self.keys.write().unwrap() = keys;
+        *self.modified.write().unwrap() = Some(modified);
+        Ok(true)
+    }
+
+    // Check the file for changes every period, keeping the last good metadata on errors
+    pub async fn watch(&self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match self.reload() {
+                Ok(true) => {
+                    log::info!("{{\"fn\": \"watch\", \"msg\": \"reloaded metadata\", \"path\": \"{}\"}}", self.path.display());
+                    metrics::increment_counter!("elastic_billing_metadata_reloads_total");
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    log::error!("{{\"fn\": \"watch\", \"msg\": \"failed reloading metadata\", \"error\": \"{}\"}}", e);
+                    metrics::increment_counter!("elastic_billing_metadata_reload_errors_total");
+                }
+            }
+        }
+    }
+}
+
+// Parse a header of id followed by the label names, then one line per deployment,
+// skipping blank lines and comments. Empty cells leave the label unset
+fn parse_csv(contents: &str) -> BoxResult<HashMap<String, Labels>> {
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .map(|(number, line)| (number, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+    let header: Vec<&str> = match lines.next() {
+        Some((_, header)) => header.split(',').map(str::trim).collect(),
+        None => return Ok(HashMap::new()),
+    };
+    if header.first() != Some(&"id") {
+        return Err("the metadata csv header must start with id".into());
+    }
+
+    let mut map = HashMap::new();
+    for (number, line) in lines {
+        let cells: Vec<&str> = line.split(',').map(str::trim).collect();
+        if cells.len() != header.len() {
+            return Err(format!("line {} has {} columns, the header has {}", number + 1, cells.len(), header.len()).into());
+        }
+        let labels = header[1..]
+            .iter()
+            .zip(&cells[1..])
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        map.insert(cells[0].to_string(), labels);
+    }
+    Ok(map)
+}
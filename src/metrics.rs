@@ -1,18 +1,19 @@
 use axum::{http::Request, response::IntoResponse, middleware::Next};
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use metrics::Unit;
 use metrics_util::MetricKindMask;
 use std::time::Instant;
 use core::time::Duration;
 
-pub fn setup_metrics_recorder() -> PrometheusHandle {
+pub fn setup_metrics_recorder(idle_timeout: Duration) -> PrometheusHandle {
     const EXPONENTIAL_SECONDS: &[f64] = &[
         0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
     ];
 
-    PrometheusBuilder::new()
+    let handle = PrometheusBuilder::new()
         .idle_timeout(
             MetricKindMask::COUNTER | MetricKindMask::GAUGE,
-            Some(Duration::from_secs(10)),
+            Some(idle_timeout),
         )
         .set_buckets_for_metric(
             Matcher::Full("http_requests_duration_seconds".to_string()),
@@ -20,7 +21,102 @@ pub fn setup_metrics_recorder() -> PrometheusHandle {
         )
         .unwrap()
         .install_recorder()
-        .unwrap()
+        .unwrap();
+
+    describe_metrics();
+    handle
+}
+
+// Register HELP text and units, costs are in the organization's billing currency
+pub fn describe_metrics() {
+    metrics::describe_counter!("http_requests_total", Unit::Count, "Requests served by the exporter");
+    metrics::describe_histogram!("http_requests_duration_seconds", Unit::Seconds, "Latency of requests served by the exporter");
+    metrics::describe_gauge!("elastic_billing_exporter_build_info", "Exporter build information, always 1");
+    metrics::describe_gauge!("elastic_billing_exporter_leader", "Whether this replica holds the leader election lease");
+    metrics::describe_gauge!("elastic_billing_last_success_timestamp_seconds", Unit::Seconds, "Unix time of the last successful poll of the billing api");
+    metrics::describe_gauge!("elastic_billing_active_endpoint", "Whether the url is the one currently used for the organization");
+    metrics::describe_counter!("elastic_billing_events_total", Unit::Count, "Budget breaches and anomalies detected between polls, by type");
+    metrics::describe_counter!("elastic_billing_endpoint_failovers_total", Unit::Count, "Times the exporter failed over to the next url");
+    metrics::describe_counter!("elastic_billing_response_too_large_total", Unit::Count, "Billing api responses dropped for being over the body size limit");
+    metrics::describe_counter!("elastic_billing_cost_center_reloads_total", Unit::Count, "Reloads of the cost centers file after it changed");
+    metrics::describe_counter!("elastic_billing_cost_center_reload_errors_total", Unit::Count, "Failed reloads of the cost centers file");
+    metrics::describe_counter!("elastic_billing_metadata_reloads_total", Unit::Count, "Reloads of the metadata file after it changed");
+    metrics::describe_counter!("elastic_billing_metadata_reload_errors_total", Unit::Count, "Failed reloads of the metadata file");
+    metrics::describe_counter!("elastic_billing_secret_reloads_total", Unit::Count, "Times a rotated api key was read from the kubernetes secret");
+    metrics::describe_counter!("elastic_billing_secret_reload_errors_total", Unit::Count, "Failed reads of the kubernetes secret");
+    metrics::describe_counter!("elastic_billing_threshold_commands_total", Unit::Count, "Threshold commands run after a budget threshold was crossed, by exit status");
+    metrics::describe_counter!("elastic_billing_sink_errors_total", Unit::Count, "Failed writes to a sink, by sink");
+    metrics::describe_counter!("elastic_billing_webhook_errors_total", Unit::Count, "Poll summaries that failed to post to the webhook");
+    metrics::describe_counter!("elastic_billing_api_errors_total", Unit::Count, "Failed billing api calls, by endpoint, status and reason");
+    metrics::describe_gauge!("elastic_billing_api_last_error_timestamp_seconds", Unit::Seconds, "Time of the last failed billing api call, by endpoint and reason");
+    metrics::describe_counter!("elastic_billing_api_retries_total", Unit::Count, "Billing api calls retried after being throttled, by endpoint");
+    metrics::describe_gauge!("elastic_billing_api_backoff_seconds", Unit::Seconds, "Delay before the pending retry of a throttled billing api call, 0 when none is pending");
+    metrics::describe_counter!("elastic_billing_polls_skipped_total", Unit::Count, "Polls skipped because the previous one was still running");
+    metrics::describe_counter!("elastic_billing_deployments_added_total", Unit::Count, "Deployments that started being billed since the previous poll");
+    metrics::describe_counter!("elastic_billing_deployments_removed_total", Unit::Count, "Deployments that stopped being billed since the previous poll");
+    metrics::describe_counter!("elastic_billing_parse_failures_total", Unit::Count, "Billing api responses that could not be parsed, by endpoint");
+    metrics::describe_counter!("elastic_billing_schema_drift_fields_total", Unit::Count, "Unknown fields seen in billing api responses, by endpoint and field");
+    metrics::describe_counter!("elastic_billing_reports_total", Unit::Count, "Scheduled reports posted to their webhook, by status");
+    metrics::describe_counter!("elastic_billing_exports_total", Unit::Count, "Daily history exports, by type and status");
+    metrics::describe_counter!("elastic_billing_redis_errors_total", Unit::Count, "Failed reads and writes of the snapshot shared through redis");
+    metrics::describe_gauge!("elastic_billing_snapshot_stale", "Whether the metrics come from a snapshot saved before the last restart, until the first poll succeeds");
+    metrics::describe_gauge!("elastic_billing_polling_paused", "Whether polling was paused through /admin/pause");
+    metrics::describe_counter!("elastic_billing_access_log_errors_total", Unit::Count, "Requests served that were dropped or could not be written to the access log");
+    metrics::describe_counter!("elastic_billing_audit_log_errors_total", Unit::Count, "Billing api calls that were dropped or could not be written to the audit log");
+    metrics::describe_counter!("elastic_billing_chart_gaps_total", Unit::Count, "Charts buckets found missing, each counted once however many polls cover it");
+    metrics::describe_gauge!("elastic_billing_charts_data_complete", "Whether the charts api returned every bucket of the last poll");
+    metrics::describe_counter!("elastic_billing_charts_empty_total", Unit::Count, "Polls where the charts api returned no data");
+    metrics::describe_counter!("elastic_billing_month_rollovers_total", Unit::Count, "Times the billing month changed between polls");
+
+    metrics::describe_gauge!("elastic_billing_organization_daily_cost_total", "Organization cost since the start of the day");
+    metrics::describe_gauge!("elastic_billing_organization_monthly_cost_total", "Organization cost since the start of the month");
+    metrics::describe_gauge!("elastic_billing_charts_hourly_rate", "Deployment cost over the last hour from the charts api");
+    metrics::describe_gauge!("elastic_billing_hourly_rate_timestamp_seconds", Unit::Seconds, "Timestamp of the charts bucket the hourly rate was taken from");
+    metrics::describe_gauge!("elastic_billing_charts_daily_rate", "Deployment cost over the last day from the charts api, with daily buckets");
+    metrics::describe_gauge!("elastic_billing_daily_rate_timestamp_seconds", Unit::Seconds, "Timestamp of the charts bucket the daily rate was taken from");
+    metrics::describe_gauge!("elastic_billing_daily_cost_total", "Deployment cost since the start of the day");
+    metrics::describe_gauge!("elastic_billing_hourly_rate", "Deployment hourly rate reported for the current day");
+    metrics::describe_gauge!("elastic_billing_hourly_rate_distribution_bucket", "Deployments of the organization with an hourly rate at or below le, as of the last poll");
+    metrics::describe_gauge!("elastic_billing_hourly_rate_distribution_sum", "Sum of the hourly rates of the organization's deployments, as of the last poll");
+    metrics::describe_gauge!("elastic_billing_hourly_rate_distribution_count", "Deployments of the organization with an hourly rate, as of the last poll");
+    metrics::describe_gauge!("elastic_billing_itemized_daily_cost_total", "Deployment cost since the start of the day by billing item");
+    metrics::describe_gauge!("elastic_billing_monthly_cost_total", "Deployment cost since the start of the month");
+    metrics::describe_gauge!("elastic_billing_wtd_cost_total", "Deployment cost since monday");
+    metrics::describe_gauge!("elastic_billing_qtd_cost_total", "Deployment cost since the start of the quarter");
+    metrics::describe_gauge!("elastic_billing_ytd_cost_total", "Deployment cost since the start of the year, accumulated from monthly totals");
+    metrics::describe_gauge!("elastic_billing_monthly_hourly_rate", "Deployment hourly rate reported for the current month");
+    metrics::describe_gauge!("elastic_billing_discounts_total", "Discounts applied to the deployment this month");
+    metrics::describe_gauge!("elastic_billing_credits_applied_total", "Credits applied to the deployment this month");
+    metrics::describe_gauge!("elastic_billing_organization_discounts_total", "Discounts applied to the organization this month");
+    metrics::describe_gauge!("elastic_billing_organization_credits_applied_total", "Credits applied to the organization this month");
+    metrics::describe_gauge!("elastic_billing_deployment_info", "Deployment names by id with --stable-ids, always 1");
+    metrics::describe_gauge!("elastic_billing_org_info", "Organization details as labels, always 1");
+    metrics::describe_gauge!("elastic_billing_invoice_tax", "Tax on an issued invoice, with --invoices");
+    metrics::describe_gauge!("elastic_billing_invoice_total", "Grand total of an issued invoice including tax, with --invoices");
+    metrics::describe_gauge!("elastic_billing_prepaid_balance", "Credits bought with a prepaid commitment");
+    metrics::describe_gauge!("elastic_billing_prepaid_remaining", "Prepaid credits not spent yet");
+    metrics::describe_gauge!("elastic_billing_credit_exhaustion_timestamp_seconds", Unit::Seconds, "Estimated time the prepaid credits run out at the current hourly rate");
+    metrics::describe_gauge!("elastic_billing_committed_spend_total", "Spend drawn from prepaid credits");
+    metrics::describe_gauge!("elastic_billing_on_demand_spend_total", "Spend beyond the prepaid credits, billed on demand");
+    metrics::describe_gauge!("elastic_billing_deployments", "Deployments billed this month with a running hourly rate");
+    metrics::describe_gauge!("elastic_billing_cost_rate_dollars_per_hour", "Growth of the deployment monthly cost per hour between the last two polls");
+    metrics::describe_gauge!("elastic_billing_monthly_cost_by_region", "Organization cost since the start of the month by cloud provider and region, with --topology");
+    metrics::describe_gauge!("elastic_billing_monthly_cost_by_kind", "Deployment cost this month by product kind, with --cost-by-kind");
+    metrics::describe_gauge!("elastic_billing_ram_gb", "Memory of a deployment tier across its zones, with --topology");
+    metrics::describe_gauge!("elastic_billing_storage_gb", "Storage of a deployment tier across its zones, with --topology");
+    metrics::describe_gauge!("elastic_billing_cost_per_gb_ram_hour", "Deployment hourly rate divided by its memory, with --topology");
+    metrics::describe_gauge!("elastic_billing_itemized_monthly_cost_total", "Deployment cost since the start of the month by billing item, leaving out negative line items");
+    metrics::describe_gauge!("elastic_billing_monthly_credits", "Negative line items of the deployment since the start of the month, such as refunds, by billing item");
+    metrics::describe_gauge!("elastic_billing_daily_credits", "Negative line items of the deployment since the start of the day, such as refunds, by billing item");
+    metrics::describe_gauge!("elastic_billing_period_start_timestamp_seconds", Unit::Seconds, "Unix time the deployment's monthly billing period starts");
+    metrics::describe_gauge!("elastic_billing_period_end_timestamp_seconds", Unit::Seconds, "Unix time the deployment's monthly billing period ends");
+    metrics::describe_gauge!("elastic_billing_budget_limit", "Monthly budget configured for the deployment");
+    metrics::describe_gauge!("elastic_billing_budget_utilization_ratio", "Ratio of monthly cost to the configured budget");
+    metrics::describe_gauge!("elastic_billing_top_deployment_cost", "Monthly cost of the most expensive deployments by rank");
+    metrics::describe_gauge!("elastic_billing_top_deployment_share_ratio", "Share of the organization's monthly cost for the most expensive deployments by rank");
+    metrics::describe_gauge!("elastic_billing_top_deployment_info", "Deployment holding each rank, 1 for this poll and 0 for pairs from the previous poll");
+    metrics::describe_gauge!("elastic_billing_group_monthly_cost", "Monthly cost of all deployments in the group");
+    metrics::describe_gauge!("elastic_billing_group_hourly_rate", "Hourly rate of all deployments in the group");
 }
 
 pub async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
@@ -42,3 +138,13 @@ pub async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> impl IntoRespon
 
     response
 }
+
+pub fn record_build_info() {
+    let labels = [
+        ("version", env!("CARGO_PKG_VERSION").to_string()),
+        ("revision", env!("GIT_REVISION").to_string()),
+        ("rustc", env!("RUSTC_VERSION").to_string()),
+    ];
+
+    metrics::gauge!("elastic_billing_exporter_build_info", 1.0, &labels);
+}
@@ -0,0 +1,13 @@
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+
+// Costs are parsed, summed and forecast as decimals so totals do not drift,
+// and only turned into floats where they leave for the metrics
+pub fn to_f64(amount: Decimal) -> f64 {
+    amount.to_f64().unwrap_or_default()
+}
+
+// Hours in a number of seconds, exact for whole hours and otherwise rounded to the 28 digits a decimal keeps
+pub fn hours(seconds: i64) -> Decimal {
+    Decimal::from(seconds) / Decimal::from(3600)
+}
@@ -0,0 +1,256 @@
+use clap::{crate_description, crate_name, crate_version};
+use serde_json::{json, Value};
+
+// OpenAPI 3 description of the json endpoints, so clients can be generated instead of hand written.
+// The admin endpoints are listed whether or not --admin-token enables them
+pub fn openapi() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": crate_name!(),
+            "description": crate_description!(),
+            "version": crate_version!()
+        },
+        "paths": {
+            "/api/v1/costs": {
+                "get": {
+                    "operationId": "getCosts",
+                    "summary": "Current costs and month end forecast per deployment",
+                    "responses": {
+                        "200": {
+                            "description": "Costs from the latest poll, with a null timestamp before the first one",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Costs" } } }
+                        },
+                        "500": { "$ref": "#/components/responses/Error" }
+                    }
+                }
+            },
+            "/api/v1/history": {
+                "get": {
+                    "operationId": "getHistory",
+                    "summary": "Persisted costs of every poll",
+                    "parameters": [
+                        {
+                            "name": "deployment",
+                            "in": "query",
+                            "description": "Deployment id or name",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "from",
+                            "in": "query",
+                            "description": "Only polls at or after this time",
+                            "schema": { "type": "string", "format": "date-time" }
+                        },
+                        {
+                            "name": "to",
+                            "in": "query",
+                            "description": "Only polls at or before this time",
+                            "schema": { "type": "string", "format": "date-time" }
+                        },
+                        {
+                            "name": "downsample",
+                            "in": "query",
+                            "description": "Keep the last poll of every deployment per utc day or week, with the hourly rate averaged",
+                            "schema": { "type": "string", "enum": ["daily", "weekly"] }
+                        },
+                        {
+                            "name": "limit",
+                            "in": "query",
+                            "description": "Entries per page, every matching entry when left out",
+                            "schema": { "type": "integer", "minimum": 0 }
+                        },
+                        {
+                            "name": "cursor",
+                            "in": "query",
+                            "description": "next_cursor of the previous page",
+                            "schema": { "type": "integer", "minimum": 0 }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Matching history entries, oldest first",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/History" } } }
+                        },
+                        "404": {
+                            "description": "History requires --data-dir",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/NotFound" } } }
+                        },
+                        "500": { "$ref": "#/components/responses/Error" }
+                    }
+                }
+            },
+            "/api/v1/diff": {
+                "get": {
+                    "operationId": "getDiff",
+                    "summary": "Cost changes per deployment since the previous poll, midnight and the start of the month",
+                    "responses": {
+                        "200": {
+                            "description": "Deployments with the biggest increase since the previous poll first",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Diff" } } }
+                        }
+                    }
+                }
+            },
+            "/api/v1/stream": {
+                "get": {
+                    "operationId": "streamCosts",
+                    "summary": "Server sent events with the costs of the current snapshot and of every poll after it",
+                    "responses": {
+                        "200": {
+                            "description": "costs events, each holding the Costs object as its data",
+                            "content": { "text/event-stream": { "schema": { "type": "string" } } }
+                        }
+                    }
+                }
+            },
+            "/admin/poll": {
+                "post": {
+                    "operationId": "poll",
+                    "summary": "Poll the billing api right away",
+                    "security": [{ "adminToken": [] }],
+                    "responses": {
+                        "200": {
+                            "description": "Polled, with the timestamp of the new snapshot",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Polled" } } }
+                        },
+                        "401": { "description": "Missing or wrong admin token" },
+                        "409": {
+                            "description": "Not the leader, poll the leader instead",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Message" } } }
+                        },
+                        "500": { "$ref": "#/components/responses/Error" }
+                    }
+                }
+            },
+            "/admin/pause": {
+                "post": {
+                    "operationId": "pause",
+                    "summary": "Stop polling, while the last snapshot keeps being served",
+                    "security": [{ "adminToken": [] }],
+                    "responses": {
+                        "200": {
+                            "description": "Paused",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Message" } } }
+                        },
+                        "401": { "description": "Missing or wrong admin token" }
+                    }
+                }
+            },
+            "/admin/resume": {
+                "post": {
+                    "operationId": "resume",
+                    "summary": "Resume polling after a pause",
+                    "security": [{ "adminToken": [] }],
+                    "responses": {
+                        "200": {
+                            "description": "Resumed",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Message" } } }
+                        },
+                        "401": { "description": "Missing or wrong admin token" }
+                    }
+                }
+            }
+        },
+        "components": {
+            "securitySchemes": {
+                "adminToken": { "type": "http", "scheme": "bearer" }
+            },
+            "responses": {
+                "Error": {
+                    "description": "The billing api or the data directory failed",
+                    "content": { "text/plain": { "schema": { "type": "string" } } }
+                }
+            },
+            "schemas": {
+                "Costs": {
+                    "type": "object",
+                    "required": ["timestamp", "deployments"],
+                    "properties": {
+                        "timestamp": { "type": "integer", "format": "int64", "nullable": true, "description": "Unix time of the poll" },
+                        "deployments": { "type": "array", "items": { "$ref": "#/components/schemas/DeploymentCosts" } }
+                    }
+                },
+                "DeploymentCosts": {
+                    "type": "object",
+                    "required": ["id", "name", "daily_cost", "monthly_cost", "hourly_rate", "forecast"],
+                    "properties": {
+                        "org_id": { "type": "string", "nullable": true },
+                        "id": { "type": "string" },
+                        "name": { "type": "string" },
+                        "daily_cost": { "type": "number" },
+                        "monthly_cost": { "type": "number" },
+                        "hourly_rate": { "type": "number" },
+                        "forecast": { "type": "number", "description": "Monthly cost at the current hourly rate by the end of the month" }
+                    }
+                },
+                "History": {
+                    "type": "object",
+                    "required": ["history"],
+                    "properties": {
+                        "history": { "type": "array", "items": { "$ref": "#/components/schemas/HistoryEntry" } },
+                        "next_cursor": { "type": "integer", "nullable": true, "description": "Cursor of the next page, null on the last one" }
+                    }
+                },
+                "HistoryEntry": {
+                    "type": "object",
+                    "required": ["timestamp", "id", "name", "daily_cost", "monthly_cost", "hourly_rate"],
+                    "properties": {
+                        "timestamp": { "type": "integer", "format": "int64", "description": "Unix time of the poll" },
+                        "org_id": { "type": "string", "nullable": true },
+                        "id": { "type": "string" },
+                        "name": { "type": "string" },
+                        "daily_cost": { "type": "number" },
+                        "monthly_cost": { "type": "number" },
+                        "hourly_rate": { "type": "number" }
+                    }
+                },
+                "Diff": {
+                    "type": "object",
+                    "required": ["timestamp", "previous_timestamp", "deployments"],
+                    "properties": {
+                        "timestamp": { "type": "integer", "format": "int64", "nullable": true, "description": "Unix time of the latest poll" },
+                        "previous_timestamp": { "type": "integer", "format": "int64", "nullable": true, "description": "Unix time of the poll before it" },
+                        "deployments": { "type": "array", "items": { "$ref": "#/components/schemas/DeploymentDiff" } }
+                    }
+                },
+                "DeploymentDiff": {
+                    "type": "object",
+                    "required": ["id", "name", "since_midnight", "since_month_start", "hourly_rate"],
+                    "properties": {
+                        "org_id": { "type": "string", "nullable": true },
+                        "id": { "type": "string" },
+                        "name": { "type": "string" },
+                        "since_last_poll": { "type": "number", "nullable": true, "description": "Null when the previous poll is missing or from another month" },
+                        "since_midnight": { "type": "number" },
+                        "since_month_start": { "type": "number" },
+                        "hourly_rate": { "type": "number" },
+                        "hourly_rate_change": { "type": "number", "nullable": true }
+                    }
+                },
+                "Polled": {
+                    "type": "object",
+                    "required": ["msg"],
+                    "properties": {
+                        "msg": { "type": "string" },
+                        "timestamp": { "type": "integer", "format": "int64", "nullable": true }
+                    }
+                },
+                "Message": {
+                    "type": "object",
+                    "required": ["msg"],
+                    "properties": {
+                        "msg": { "type": "string" }
+                    }
+                },
+                "NotFound": {
+                    "type": "object",
+                    "properties": {
+                        "error_code": { "type": "integer" },
+                        "message": { "type": "string" }
+                    }
+                }
+            }
+        }
+    })
+}
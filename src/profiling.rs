@@ -0,0 +1,110 @@
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use pprof::protos::Message;
+use pprof::ProfilerGuardBuilder;
+use serde::Deserialize;
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::time::Duration;
+use tower_http::auth::RequireAuthorizationLayer;
+
+use crate::settings::Settings;
+
+// Same defaults as go's net/http/pprof
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+const FREQUENCY: i32 = 100;
+
+// Only one cpu profile can run at a time
+static PROFILING: Mutex<()> = Mutex::new(());
+
+#[derive(Deserialize)]
+pub struct ProfileQuery {
+    seconds: Option<u64>,
+}
+
+// The profile endpoints behind the admin token, which settings require with --pprof, or nothing without it
+pub async fn router(settings: &Settings) -> Router {
+    let token = match (settings.pprof, &settings.admin_token) {
+        (true, Some(token)) => token,
+        _ => return Router::new(),
+    };
+
+    jemalloc_pprof::activate_jemalloc_profiling().await;
+    Router::new()
+        .route("/debug/pprof/profile", get(profile))
+        .route("/debug/pprof/heap", get(heap))
+        .route_layer(RequireAuthorizationLayer::bearer(token))
+}
+
+// Sample the cpu for a number of seconds, returning a pprof protobuf for go tool pprof
+pub async fn profile(Query(query): Query<ProfileQuery>) -> Response {
+    log::info!("{{\"fn\": \"profile\", \"method\":\"get\"}}");
+    let seconds = query.seconds.unwrap_or(DEFAULT_SECONDS);
+    if !(1..=MAX_SECONDS).contains(&seconds) {
+        return error(StatusCode::BAD_REQUEST, format!("seconds must be between 1 and {}", MAX_SECONDS));
+    }
+
+    // The profiler is driven by signals, so the guard is held on a blocking thread
+    let result = tokio::task::spawn_blocking(move || {
+        let _running = PROFILING.try_lock().map_err(|_| (StatusCode::CONFLICT, "A cpu profile is already running".to_string()))?;
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(internal)?;
+        std::thread::sleep(Duration::from_secs(seconds));
+        let profile = guard.report().build().map_err(internal)?.pprof().map_err(internal)?;
+        Ok(profile.encode_to_vec())
+    })
+    .await
+    .unwrap_or_else(|e| Err(internal(e)));
+
+    match result {
+        Ok(body) => attachment("profile.pb", body),
+        Err((status, message)) => error(status, message),
+    }
+}
+
+// Dump the sampled live allocations as a gzipped pprof protobuf
+pub async fn heap() -> Response {
+    log::info!("{{\"fn\": \"heap\", \"method\":\"get\"}}");
+    let prof_ctl = match jemalloc_pprof::PROF_CTL.as_ref() {
+        Some(prof_ctl) => prof_ctl,
+        None => return error(StatusCode::INTERNAL_SERVER_ERROR, "Jemalloc profiling is not available"),
+    };
+
+    let mut prof_ctl = prof_ctl.lock().await;
+    if !prof_ctl.activated() {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, "Jemalloc profiling is not active");
+    }
+    match prof_ctl.dump_pprof() {
+        Ok(body) => attachment("heap.pb.gz", body),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+fn attachment(filename: &str, body: Vec<u8>) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+fn internal(e: impl Display) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+fn error(status: StatusCode, message: impl Display) -> Response {
+    let body = serde_json::json!({ "error_code": status.as_u16(), "message": message.to_string() });
+    (status, axum::Json(body)).into_response()
+}
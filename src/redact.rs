@@ -0,0 +1,83 @@
+use serde::Serializer;
+use std::collections::{BTreeMap, HashMap};
+use url::Url;
+
+const REDACTED: &str = "redacted";
+
+// Query parameters that carry credentials in some billing proxies and webhook urls
+const SENSITIVE_PARAMS: &[&str] = &["key", "api_key", "apikey", "token", "access_token", "secret", "password", "signature", "sig", "auth"];
+
+// Mask the password and credential query parameters of a url, so it can be logged or exported as a label
+pub fn url(url: &Url) -> String {
+    let mut url = url.clone();
+    if url.password().is_some() {
+        let _ = url.set_password(Some(REDACTED));
+    }
+
+    if url.query().is_some() {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(k, v)| match SENSITIVE_PARAMS.iter().any(|p| k.eq_ignore_ascii_case(p)) {
+                true => (k.into_owned(), REDACTED.to_string()),
+                false => (k.into_owned(), v.into_owned()),
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url.to_string()
+}
+
+// Same as url, for strings that may not parse, which are then dropped entirely
+pub fn url_str(value: &str) -> String {
+    match Url::parse(value) {
+        Ok(parsed) => url(&parsed),
+        Err(_) => REDACTED.to_string(),
+    }
+}
+
+// Webhook urls such as slack's carry the secret in the path, so only the origin is kept
+pub fn webhook(value: &str) -> String {
+    match Url::parse(value) {
+        Ok(parsed) => format!("{}/{}", parsed.origin().ascii_serialization(), REDACTED),
+        Err(_) => REDACTED.to_string(),
+    }
+}
+
+// Header names are kept, while every value is masked
+pub fn headers(headers: &HashMap<String, String>) -> BTreeMap<&str, &str> {
+    headers.keys().map(|k| (k.as_str(), REDACTED)).collect()
+}
+
+pub fn serialize_url<S: Serializer>(value: &Url, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&url(value))
+}
+
+pub fn serialize_optional_url<S: Serializer>(value: &Option<Url>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.serialize_some(&url(value)),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn serialize_urls<S: Serializer>(values: &[Url], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(values.iter().map(url))
+}
+
+pub fn serialize_headers<S: Serializer>(values: &HashMap<String, String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(headers(values))
+}
+
+pub fn serialize_secret<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_some(REDACTED),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn serialize_password<S: Serializer>(_: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+}
+
+pub fn serialize_webhook<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&webhook(value))
+}
@@ -0,0 +1,211 @@
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::cron::Schedule;
+use crate::cost_centers::CostCenters;
+use crate::history::HistoryEntry;
+use crate::redact;
+use crate::state::State;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+pub const REPORT_BY: &[&str] = &["group", "cost-center", "deployment"];
+pub const REPORT_FORMATS: &[&str] = &["json", "csv", "markdown"];
+
+// Previous month's report, posted to a webhook on every run of the schedule
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReportJob {
+    pub schedule: Schedule,
+    #[serde(default = "default_by")]
+    pub by: String,
+    #[serde(default = "default_format")]
+    pub format: String,
+    #[serde(serialize_with = "redact::serialize_webhook")]
+    pub webhook: String,
+}
+
+fn default_by() -> String {
+    "group".to_string()
+}
+
+fn default_format() -> String {
+    "json".to_string()
+}
+
+// Monthly spend per group or cost center, built from the persisted history
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    pub month: String,
+    pub by: String,
+    pub total: Decimal,
+    pub rows: Vec<ReportRow>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ReportRow {
+    pub key: String,
+    pub cost: Decimal,
+    pub deployments: usize,
+}
+
+impl Report {
+    // The last poll of the month holds the final month to date cost of every deployment
+    pub fn new(entries: &[HistoryEntry], year: i32, month: u32, by: &str, config: &Config, cost_centers: Option<&CostCenters>) -> Self {
+        let mut latest: HashMap<(Option<&str>, &str), &HistoryEntry> = HashMap::new();
+        for entry in entries {
+            let timestamp = Utc.timestamp(entry.timestamp, 0);
+            if timestamp.year() != year || timestamp.month() != month {
+                continue;
+            }
+            let key = (entry.org_id.as_deref(), entry.id.as_str());
+            match latest.get(&key) {
+                Some(previous) if previous.timestamp >= entry.timestamp => (),
+                _ => {
+                    latest.insert(key, entry);
+                }
+            }
+        }
+
+        let mut rows: BTreeMap<String, ReportRow> = BTreeMap::new();
+        for entry in latest.values() {
+            for key in keys(entry, by, config, cost_centers) {
+                let row = rows.entry(key.clone()).or_insert(ReportRow { key, cost: Decimal::ZERO, deployments: 0 });
+                row.cost += entry.monthly_cost;
+                row.deployments += 1;
+            }
+        }
+
+        let mut rows: Vec<ReportRow> = rows.into_values().collect();
+        rows.sort_by_key(|r| std::cmp::Reverse(r.cost));
+
+        Report {
+            month: format!("{:04}-{:02}", year, month),
+            by: by.to_string(),
+            total: latest.values().map(|e| e.monthly_cost).sum(),
+            rows,
+        }
+    }
+
+    // The report in the given format, with its content type
+    pub fn render(&self, format: &str) -> (String, &'static str) {
+        match format {
+            "csv" => (self.csv(), "text/csv"),
+            "markdown" => (self.markdown(), "text/markdown"),
+            _ => (serde_json::to_string_pretty(self).unwrap_or_default() + "\n", "application/json"),
+        }
+    }
+
+    pub fn csv(&self) -> String {
+        let mut output = format!("{},cost,deployments\n", self.by);
+        for row in &self.rows {
+            output.push_str(&format!("{},{:.2},{}\n", escape_csv(&row.key), row.cost, row.deployments));
+        }
+        output
+    }
+
+    pub fn markdown(&self) -> String {
+        let mut output = format!("# Elastic Cloud spend for {}\n\n", self.month);
+        output.push_str(&format!("| {} | Cost | Deployments |\n|---|---:|---:|\n", self.by));
+        for row in &self.rows {
+            output.push_str(&format!("| {} | {:.2} | {} |\n", row.key.replace('|', "\\|"), row.cost, row.deployments));
+        }
+        output.push_str(&format!("\nTotal: {:.2}\n", self.total));
+        output
+    }
+}
+
+// Groups may overlap, so a deployment can count towards several of them
+fn keys(entry: &HistoryEntry, by: &str, config: &Config, cost_centers: Option<&CostCenters>) -> Vec<String> {
+    match by {
+        "cost-center" => vec![match cost_centers {
+            Some(cost_centers) => cost_centers.get(&entry.id),
+            None => "unassigned".to_string(),
+        }],
+        "deployment" => vec![entry.name.clone()],
+        _ => {
+            let groups: Vec<String> = config
+                .groups
+                .iter()
+                .filter(|g| g.matches(&entry.id, &entry.name))
+                .map(|g| g.name.clone())
+                .collect();
+            match groups.is_empty() {
+                true => vec!["ungrouped".to_string()],
+                false => groups,
+            }
+        }
+    }
+}
+
+fn escape_csv(value: &str) -> String {
+    match value.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+pub fn previous_month(now: DateTime<Utc>) -> (i32, u32) {
+    match now.month() {
+        1 => (now.year() - 1, 12),
+        m => (now.year(), m - 1),
+    }
+}
+
+pub async fn post(webhook: &str, output: String, content_type: &str, timeout: u64) -> BoxResult<()> {
+    let response = reqwest::Client::builder()
+        .timeout(Duration::from_secs(timeout))
+        .build()?
+        .post(webhook)
+        .header("Content-Type", content_type)
+        .body(output)
+        .send()
+        .await
+        .map_err(|e| e.without_url())?;
+    if !response.status().is_success() {
+        return Err(format!("webhook returned {}", response.status()).into());
+    }
+    Ok(())
+}
+
+// Post the previous month's report on every run of the schedule, on the leader only
+pub async fn scheduled(state: State, job: ReportJob) {
+    loop {
+        let fired = match job.schedule.tick().await {
+            Some(fired) => fired,
+            None => {
+                log::error!("{{\"fn\": \"scheduled\", \"msg\": \"schedule never runs\", \"schedule\": \"{}\"}}", job.schedule);
+                return;
+            }
+        };
+        if !state.is_leader() {
+            continue;
+        }
+
+        let (year, month) = previous_month(fired);
+        let result = match &state.store {
+            Some(store) => match store.read_lines("history.jsonl", |_: &HistoryEntry| true).await {
+                Ok(entries) => {
+                    let report = Report::new(&entries, year, month, &job.by, &state.config, state.cost_centers.as_ref());
+                    let (output, content_type) = report.render(&job.format);
+                    post(&job.webhook, output, content_type, state.timeout).await
+                }
+                Err(e) => Err(e),
+            },
+            None => Err("reports require --data-dir".into()),
+        };
+
+        let status = match result {
+            Ok(()) => "success",
+            Err(e) => {
+                log::error!("{{\"fn\": \"scheduled\", \"msg\": \"report failed\", \"error\": \"{}\"}}", e);
+                "failure"
+            }
+        };
+        metrics::increment_counter!("elastic_billing_reports_total", "status" => status);
+    }
+}
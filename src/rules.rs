@@ -0,0 +1,89 @@
+use serde_json::{json, Value};
+
+// Build prometheus alerting rules for the metrics exported by this crate
+pub fn rules(job: &str, budget: Option<f64>, deployment_budgets: bool, stale_after: u64, spike_ratio: f64) -> Value {
+    let mut alerts = vec![
+        json!({
+            "alert": "ElasticBillingExporterDown",
+            "expr": format!("up{{job=\"{}\"}} == 0", job),
+            "for": "10m",
+            "labels": { "severity": "warning" },
+            "annotations": {
+                "summary": "Elastic billing exporter is down",
+                "description": "The elastic billing exporter has not been scraped successfully for 10 minutes."
+            }
+        }),
+        json!({
+            "alert": "ElasticBillingDataStale",
+            "expr": format!(
+                "time() - max(elastic_billing_last_success_timestamp_seconds) > {} or absent(elastic_billing_last_success_timestamp_seconds)",
+                stale_after
+            ),
+            "for": "10m",
+            "labels": { "severity": "warning" },
+            "annotations": {
+                "summary": "Elastic billing data is stale",
+                "description": format!("The billing api has not been polled successfully for more than {} seconds.", stale_after)
+            }
+        }),
+        json!({
+            "alert": "ElasticBillingHourlyRateSpike",
+            "expr": format!(
+                "sum(elastic_billing_hourly_rate) > {} * avg_over_time(sum(elastic_billing_hourly_rate)[1d:1h])",
+                spike_ratio
+            ),
+            "for": "30m",
+            "labels": { "severity": "warning" },
+            "annotations": {
+                "summary": "Elastic cloud hourly rate spiked",
+                "description": format!("The hourly rate is more than {} times the average over the last day, currently {{{{ $value }}}}.", spike_ratio)
+            }
+        }),
+    ];
+
+    if let Some(budget) = budget {
+        alerts.push(json!({
+            "alert": "ElasticBillingOverBudget",
+            "expr": format!("sum(elastic_billing_monthly_cost_total) > {}", budget),
+            "labels": { "severity": "critical" },
+            "annotations": {
+                "summary": "Elastic cloud spend is over budget",
+                "description": format!("Month to date spend of {{{{ $value }}}} is over the monthly budget of {}.", budget)
+            }
+        }));
+    }
+
+    if deployment_budgets {
+        alerts.push(json!({
+            "alert": "ElasticBillingDeploymentOverBudget",
+            "expr": "elastic_billing_budget_utilization_ratio > 1",
+            "labels": { "severity": "warning" },
+            "annotations": {
+                "summary": "Elastic cloud deployment {{ $labels.name }} is over budget",
+                "description": "Deployment {{ $labels.name }} has used {{ $value | humanizePercentage }} of its monthly budget."
+            }
+        }));
+    }
+
+    json!({
+        "groups": [
+            {
+                "name": "elastic-cloud-billing",
+                "rules": alerts
+            }
+        ]
+    })
+}
+
+// Wrap the rules in a PrometheusRule resource for the prometheus operator
+pub fn prometheus_rule(rules: Value) -> Value {
+    json!({
+        "apiVersion": "monitoring.coreos.com/v1",
+        "kind": "PrometheusRule",
+        "metadata": {
+            "name": "elastic-cloud-billing",
+            "labels": { "app": "elastic-cloud-billing-exporter" }
+        },
+        "spec": rules
+    })
+}
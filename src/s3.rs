@@ -0,0 +1,139 @@
+use chrono::Utc;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+use std::env;
+use std::error::Error;
+use std::time::Duration;
+use url::Url;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Characters left alone when encoding an object key, besides alphanumerics
+const UNRESERVED: &[u8] = b"-_.~/";
+
+// Uploads objects with aws signature version 4, using the credentials from the standard aws environment variables
+#[derive(Clone, Debug)]
+pub struct S3 {
+    bucket: String,
+    region: String,
+    endpoint: Option<Url>,
+    client: reqwest::Client,
+}
+
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    fn from_env() -> BoxResult<Self> {
+        Ok(Credentials {
+            access_key: env::var("AWS_ACCESS_KEY_ID").map_err(|_| "AWS_ACCESS_KEY_ID is not set")?,
+            secret_key: env::var("AWS_SECRET_ACCESS_KEY").map_err(|_| "AWS_SECRET_ACCESS_KEY is not set")?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+impl S3 {
+    pub fn new(bucket: &str, region: &str, endpoint: Option<Url>, timeout: u64) -> BoxResult<Self> {
+        Ok(S3 {
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            endpoint,
+            client: reqwest::Client::builder().timeout(Duration::from_secs(timeout)).build()?,
+        })
+    }
+
+    pub async fn put_object(&self, key: &str, body: Vec<u8>, content_type: &str) -> BoxResult<()> {
+        let credentials = Credentials::from_env()?;
+
+        // Custom endpoints such as minio get path style urls, aws gets virtual hosted ones.
+        // The endpoint's own path, as behind a reverse proxy, is signed along with the key
+        let key = encode(key.trim_start_matches('/'));
+        let (base, path) = match &self.endpoint {
+            Some(endpoint) => (
+                endpoint.origin().ascii_serialization(),
+                format!("{}/{}/{}", endpoint.path().trim_end_matches('/'), self.bucket, key),
+            ),
+            None => (format!("https://{}.s3.{}.amazonaws.com", self.bucket, self.region), format!("/{}", key)),
+        };
+        let url = Url::parse(&format!("{}{}", base, path))?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex(&sha256(&body));
+
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers.iter().map(|(k, _)| *k).collect::<Vec<_>>().join(";");
+        let canonical_headers: String = headers.iter().map(|(k, v)| format!("{}:{}\n", k, v.trim())).collect();
+
+        let canonical_request = format!("PUT\n{}\n\n{}\n{}\n{}", path, canonical_headers, signed_headers, payload_hash);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex(&sha256(canonical_request.as_bytes())));
+
+        let key_date = hmac(format!("AWS4{}", credentials.secret_key).as_bytes(), date.as_bytes())?;
+        let key_region = hmac(&key_date, self.region.as_bytes())?;
+        let key_service = hmac(&key_region, b"s3")?;
+        let key_signing = hmac(&key_service, b"aws4_request")?;
+        let signature = hex(&hmac(&key_signing, string_to_sign.as_bytes())?);
+
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key, scope, signed_headers, signature
+        );
+
+        let mut request = self
+            .client
+            .put(url)
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .header(reqwest::header::CONTENT_TYPE, content_type);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+
+        let response = request.body(body).send().await.map_err(|e| e.without_url())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("s3 returned {}: {}", status, body.trim()).into());
+        }
+        Ok(())
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> BoxResult<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Percent encode an object key the way aws expects it in the canonical request
+fn encode(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b.is_ascii_alphanumeric() || UNRESERVED.contains(&b) {
+            true => (b as char).to_string(),
+            false => format!("%{:02X}", b),
+        })
+        .collect()
+}
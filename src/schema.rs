@@ -0,0 +1,734 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use rust_decimal::Decimal;
+use serde_json::Value;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::config::{Aggregation, ApiVersion};
+
+// Normalized billing data, every api version is converted into these structs
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DataV2 {
+    pub total_cost: Decimal,
+    pub deployments: Vec<Deployment>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Deployment {
+    pub deployment_id: String,
+    pub deployment_name: String,
+    pub costs: Cost,
+    pub hourly_rate: Decimal,
+    pub period: Period
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Cost {
+    pub total: Decimal,
+    pub dimensions: Vec<Item>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Item {
+    pub r#type: String,
+    pub cost: Decimal
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Period {
+    pub start: String,
+    pub end: String
+}
+
+// Organization wide costs, with the prepaid balance of organizations on a commitment.
+// Only the organization costs api serves it, and every field may be missing
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Overview {
+    #[serde(default)]
+    pub costs: OverviewCosts,
+    #[serde(default)]
+    pub hourly_rate: Decimal,
+    #[serde(default)]
+    pub balance: Option<Balance>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct OverviewCosts {
+    #[serde(default)]
+    pub total: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Balance {
+    #[serde(default)]
+    pub available: Decimal,
+    #[serde(default)]
+    pub remaining: Decimal,
+}
+
+impl Overview {
+    // Spend drawn from prepaid credits, and spend beyond them billed on demand
+    pub fn committed_and_on_demand(&self) -> Option<(Decimal, Decimal)> {
+        let balance = self.balance.as_ref()?;
+        let committed = (balance.available - balance.remaining).max(Decimal::ZERO);
+        Some((committed, (self.costs.total - committed).max(Decimal::ZERO)))
+    }
+}
+
+// Itemized costs of a deployment, with each instance tagged by the product it runs
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeploymentItems {
+    #[serde(default)]
+    pub resources: Vec<Resource>,
+    #[serde(default)]
+    pub data_transfer_and_storage: Vec<Item>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Resource {
+    pub kind: String,
+    #[serde(default)]
+    pub cost: Decimal,
+}
+
+impl DeploymentItems {
+    // Sum the costs per product kind, with data transfer and storage as a kind of their own
+    pub fn cost_by_kind(&self) -> BTreeMap<String, Decimal> {
+        let mut kinds = BTreeMap::new();
+        for resource in &self.resources {
+            *kinds.entry(resource.kind.to_lowercase()).or_insert(Decimal::ZERO) += resource.cost;
+        }
+        if !self.data_transfer_and_storage.is_empty() {
+            let cost: Decimal = self.data_transfer_and_storage.iter().map(|i| i.cost).sum();
+            *kinds.entry("data_transfer_and_storage".to_string()).or_insert(Decimal::ZERO) += cost;
+        }
+        kinds
+    }
+}
+
+// Account details of an organization from the organizations api
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct OrganizationInfo {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub billing_model: Option<String>,
+    #[serde(default)]
+    pub currency: Option<String>,
+}
+
+// An issued invoice, amounts are in the invoice currency
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Invoice {
+    #[serde(alias = "number")]
+    pub id: String,
+    #[serde(default)]
+    pub period: Period,
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub currency: String,
+    #[serde(default)]
+    pub tax: Decimal,
+    #[serde(default, alias = "grand_total")]
+    pub total: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Invoices {
+    #[serde(default)]
+    pub invoices: Vec<Invoice>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Data {
+    pub data: Vec<Inner>
+}
+
+impl Data {
+    // Count the buckets between start and end that have no data
+    pub fn missing_buckets(&self, start: i64, end: i64, bucket: i64) -> Vec<i64> {
+        let expected = (end - start) / bucket;
+        let returned: BTreeSet<i64> = self
+            .data
+            .iter()
+            .map(|b| (b.timestamp as i64 - start).div_euclid(bucket))
+            .filter(|i| (0..expected).contains(i))
+            .collect();
+        (0..expected).filter(|i| !returned.contains(i)).map(|i| start + i * bucket).collect()
+    }
+
+    // Reduce every returned bucket into a single rate per deployment
+    pub fn aggregate(&self, aggregation: Aggregation) -> Vec<Rate> {
+        if aggregation == Aggregation::Latest {
+            return match self.data.iter().max_by_key(|b| b.timestamp) {
+                Some(bucket) => bucket
+                    .values
+                    .iter()
+                    .map(|c| Rate {
+                        id: c.id.clone(),
+                        name: c.name.clone(),
+                        value: c.value,
+                        timestamp: bucket.timestamp,
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+        }
+
+        // Walk buckets oldest first, so the most recent name and timestamp win
+        let mut buckets: Vec<&Inner> = self.data.iter().collect();
+        buckets.sort_by_key(|b| b.timestamp);
+
+        let mut rates: BTreeMap<&str, (Rate, usize)> = BTreeMap::new();
+        for bucket in buckets {
+            for cluster in &bucket.values {
+                let (rate, count) = rates.entry(&cluster.id).or_insert((
+                    Rate {
+                        id: cluster.id.clone(),
+                        name: String::new(),
+                        value: Decimal::MIN,
+                        timestamp: 0,
+                    },
+                    0,
+                ));
+                *count += 1;
+                rate.name = cluster.name.clone();
+                rate.timestamp = bucket.timestamp;
+                rate.value = match aggregation {
+                    Aggregation::Max => rate.value.max(cluster.value),
+                    _ if *count == 1 => cluster.value,
+                    _ => rate.value + cluster.value,
+                };
+            }
+        }
+
+        rates
+            .into_values()
+            .map(|(mut rate, count)| {
+                if aggregation == Aggregation::Mean {
+                    rate.value /= Decimal::from(count);
+                }
+                rate
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Inner {
+    pub timestamp: u64,
+    pub values: Vec<Cluster>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Cluster {
+    pub id: String,
+    pub name: String,
+    pub value: Decimal
+}
+
+// Hourly rate of one deployment, taken from the bucket timestamped at the start of its sample
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rate {
+    pub id: String,
+    pub name: String,
+    pub value: Decimal,
+    pub timestamp: u64
+}
+
+// Legacy responses from the reverse proxy, every field is always present
+pub mod v1 {
+    use rust_decimal::Decimal;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Deployments {
+        pub total_cost: Decimal,
+        pub deployments: Vec<Deployment>
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Deployment {
+        pub deployment_id: String,
+        pub deployment_name: String,
+        pub costs: Cost,
+        pub hourly_rate: Decimal,
+        pub period: Period
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Cost {
+        pub total: Decimal,
+        pub dimensions: Vec<Item>
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Item {
+        pub r#type: String,
+        pub cost: Decimal
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Period {
+        pub start: String,
+        pub end: String
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Charts {
+        pub data: Vec<Bucket>
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Bucket {
+        pub timestamp: u64,
+        pub values: Vec<Value>
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Value {
+        pub id: String,
+        pub name: String,
+        pub value: Decimal
+    }
+}
+
+// Responses from the organization costs endpoints, which omit fields for deployments without usage
+pub mod v2 {
+    use rust_decimal::Decimal;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Deployments {
+        #[serde(default)]
+        pub total_cost: Decimal,
+        #[serde(default)]
+        pub deployments: Vec<Deployment>
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Deployment {
+        pub deployment_id: String,
+        pub deployment_name: String,
+        pub costs: Cost,
+        #[serde(default)]
+        pub hourly_rate: Decimal,
+        pub period: Option<Period>
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Cost {
+        pub total: Decimal,
+        #[serde(default)]
+        pub dimensions: Vec<Item>
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Item {
+        pub r#type: String,
+        pub cost: Decimal
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Period {
+        pub start: String,
+        pub end: String
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Charts {
+        #[serde(default)]
+        pub data: Vec<Bucket>
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Bucket {
+        pub timestamp: u64,
+        #[serde(default)]
+        pub values: Vec<Value>
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Value {
+        pub id: String,
+        pub name: String,
+        pub value: Decimal
+    }
+}
+
+impl From<v1::Deployments> for DataV2 {
+    fn from(data: v1::Deployments) -> Self {
+        DataV2 {
+            total_cost: data.total_cost,
+            deployments: data
+                .deployments
+                .into_iter()
+                .map(|d| Deployment {
+                    deployment_id: d.deployment_id,
+                    deployment_name: d.deployment_name,
+                    costs: Cost {
+                        total: d.costs.total,
+                        dimensions: d
+                            .costs
+                            .dimensions
+                            .into_iter()
+                            .map(|i| Item { r#type: i.r#type, cost: i.cost })
+                            .collect(),
+                    },
+                    hourly_rate: d.hourly_rate,
+                    period: Period { start: d.period.start, end: d.period.end },
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<v2::Deployments> for DataV2 {
+    fn from(data: v2::Deployments) -> Self {
+        DataV2 {
+            total_cost: data.total_cost,
+            deployments: data
+                .deployments
+                .into_iter()
+                .map(|d| Deployment {
+                    deployment_id: d.deployment_id,
+                    deployment_name: d.deployment_name,
+                    costs: Cost {
+                        total: d.costs.total,
+                        dimensions: d
+                            .costs
+                            .dimensions
+                            .into_iter()
+                            .map(|i| Item { r#type: i.r#type, cost: i.cost })
+                            .collect(),
+                    },
+                    hourly_rate: d.hourly_rate,
+                    period: d
+                        .period
+                        .map(|p| Period { start: p.start, end: p.end })
+                        .unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<v1::Charts> for Data {
+    fn from(charts: v1::Charts) -> Self {
+        Data {
+            data: charts
+                .data
+                .into_iter()
+                .map(|b| Inner {
+                    timestamp: b.timestamp,
+                    values: b
+                        .values
+                        .into_iter()
+                        .map(|v| Cluster { id: v.id, name: v.name, value: v.value })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<v2::Charts> for Data {
+    fn from(charts: v2::Charts) -> Self {
+        Data {
+            data: charts
+                .data
+                .into_iter()
+                .map(|b| Inner {
+                    timestamp: b.timestamp,
+                    values: b
+                        .values
+                        .into_iter()
+                        .map(|v| Cluster { id: v.id, name: v.name, value: v.value })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+// Deserialize a deployments response with the structs for the given api version
+pub fn parse_deployments(version: ApiVersion, bytes: &[u8], strict: bool) -> Result<DataV2, ParseError> {
+    match version {
+        ApiVersion::V2 => Ok(parse::<v2::Deployments>("deployments", bytes, strict)?.into()),
+        _ => Ok(parse::<v1::Deployments>("deployments", bytes, strict)?.into()),
+    }
+}
+
+// Deserialize a charts response with the structs for the given api version
+pub fn parse_charts(version: ApiVersion, bytes: &[u8], strict: bool) -> Result<Data, ParseError> {
+    match version {
+        ApiVersion::V2 => Ok(parse::<v2::Charts>("charts", bytes, strict)?.into()),
+        _ => Ok(parse::<v1::Charts>("charts", bytes, strict)?.into()),
+    }
+}
+
+// Bytes of the response shown on either side of where parsing failed
+const EXCERPT_BYTES: usize = 80;
+
+// A response that could not be parsed, with where it failed and the payload around it
+#[derive(Debug)]
+pub struct ParseError {
+    pub endpoint: String,
+    pub path: String,
+    pub excerpt: String,
+    pub message: String,
+}
+
+impl std::error::Error for ParseError {}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed parsing {} response at {}: {}", self.endpoint, if self.path.is_empty() { "." } else { &self.path }, self.message)?;
+        if !self.excerpt.is_empty() {
+            write!(f, ", near {}", self.excerpt)?;
+        }
+        Ok(())
+    }
+}
+
+impl ParseError {
+    fn new(endpoint: &str, path: String, excerpt: String, message: String) -> Self {
+        let error = ParseError {
+            endpoint: endpoint.to_string(),
+            path,
+            excerpt,
+            message,
+        };
+        log::error!(
+            "{{\"fn\": \"parse\", \"endpoint\": \"{}\", \"path\": {}, \"error\": {}, \"excerpt\": {}}}",
+            error.endpoint,
+            Value::from(error.path.as_str()),
+            Value::from(error.message.as_str()),
+            Value::from(error.excerpt.as_str())
+        );
+        metrics::increment_counter!("elastic_billing_parse_failures_total", "endpoint" => endpoint.to_string());
+        error
+    }
+
+    // Locate a serde error in the payload, serde_json counts lines and columns from 1
+    fn from_json(endpoint: &str, bytes: &[u8], error: serde_json::Error) -> Self {
+        let line_start: usize = bytes.split(|b| *b == b'\n').take(error.line().saturating_sub(1)).map(|l| l.len() + 1).sum();
+        let offset = (line_start + error.column().saturating_sub(1)).min(bytes.len());
+        let (start, end) = (offset.saturating_sub(EXCERPT_BYTES), (offset + EXCERPT_BYTES).min(bytes.len()));
+        let excerpt = format!(
+            "{}{}{}",
+            if start > 0 { "..." } else { "" },
+            String::from_utf8_lossy(&bytes[start..end]),
+            if end < bytes.len() { "..." } else { "" }
+        );
+        ParseError::new(endpoint, json_path(bytes, offset), excerpt, error.to_string())
+    }
+}
+
+// Fields of the response the structs do not know about are logged and counted, so schema drift
+// gets noticed. Strict parsing also fails on them, instead of silently dropping them
+fn parse<T: DeserializeOwned + Serialize>(endpoint: &str, bytes: &[u8], strict: bool) -> Result<T, ParseError> {
+    let parsed: T = serde_json::from_slice(bytes).map_err(|e| ParseError::from_json(endpoint, bytes, e))?;
+    let raw: Value = serde_json::from_slice(bytes).map_err(|e| ParseError::from_json(endpoint, bytes, e))?;
+    let serialized = serde_json::to_value(&parsed).map_err(|e| ParseError::new(endpoint, String::new(), String::new(), e.to_string()))?;
+
+    let mut unknown = BTreeSet::new();
+    unknown_fields(&raw, &serialized, "", &mut unknown);
+    for field in &unknown {
+        log::warn!("{{\"fn\": \"parse\", \"msg\": \"unknown field\", \"endpoint\": \"{}\", \"field\": \"{}\"}}", endpoint, field);
+        metrics::increment_counter!("elastic_billing_schema_drift_fields_total", "endpoint" => endpoint.to_string(), "field" => field.clone());
+    }
+
+    match unknown.into_iter().next() {
+        Some(field) if strict => Err(ParseError::new(endpoint, field, String::new(), "unknown field".to_string())),
+        _ => Ok(parsed),
+    }
+}
+
+// Paths of the keys in the response that did not survive deserializing, such as deployments[].costs.credits
+fn unknown_fields(raw: &Value, parsed: &Value, path: &str, unknown: &mut BTreeSet<String>) {
+    match (raw, parsed) {
+        (Value::Object(raw), Value::Object(parsed)) => {
+            for (key, value) in raw {
+                let path = match path {
+                    "" => key.clone(),
+                    path => format!("{}.{}", path, key),
+                };
+                match parsed.get(key) {
+                    Some(parsed) => unknown_fields(value, parsed, &path, unknown),
+                    None => {
+                        unknown.insert(path);
+                    }
+                }
+            }
+        }
+        (Value::Array(raw), Value::Array(parsed)) => {
+            for (raw, parsed) in raw.iter().zip(parsed) {
+                unknown_fields(raw, parsed, &format!("{}[]", path), unknown);
+            }
+        }
+        _ => (),
+    }
+}
+
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+// Path to whatever is at an offset of a json document, such as deployments[1].costs.total
+fn json_path(bytes: &[u8], offset: usize) -> String {
+    let mut stack: Vec<Segment> = Vec::new();
+    let mut expect_key = false;
+    let mut i = 0;
+    while i < offset {
+        match bytes[i] {
+            b'"' => {
+                let start = i + 1;
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    if bytes[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                if expect_key {
+                    if let Some(Segment::Key(key)) = stack.last_mut() {
+                        *key = String::from_utf8_lossy(&bytes[start..i.min(bytes.len())]).into_owned();
+                    }
+                    expect_key = false;
+                }
+            }
+            b'{' => {
+                stack.push(Segment::Key(String::new()));
+                expect_key = true;
+            }
+            b'[' => stack.push(Segment::Index(0)),
+            b'}' | b']' => {
+                stack.pop();
+            }
+            b',' => match stack.last_mut() {
+                Some(Segment::Index(index)) => *index += 1,
+                Some(Segment::Key(_)) => expect_key = true,
+                None => (),
+            },
+            _ => (),
+        }
+        i += 1;
+    }
+
+    let mut path = String::new();
+    for segment in stack {
+        match segment {
+            Segment::Key(key) if key.is_empty() => (),
+            Segment::Key(key) if path.is_empty() => path = key,
+            Segment::Key(key) => path = format!("{}.{}", path, key),
+            Segment::Index(index) => path = format!("{}[{}]", path, index),
+        }
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn v1_deployments() {
+        let bytes = br#"{"total_cost": 12.5, "deployments": [{"deployment_id": "abc", "deployment_name": "prod",
+            "costs": {"total": 12.5, "dimensions": [{"type": "capacity", "cost": 10.0}, {"type": "data_out", "cost": 2.5}]},
+            "hourly_rate": 0.6, "period": {"start": "2026-10-01T00:00:00Z", "end": "2026-10-15T00:00:00Z"}}]}"#;
+        let data = parse_deployments(ApiVersion::V1, bytes, true).unwrap();
+        assert_eq!(data.total_cost, decimal("12.5"));
+        assert_eq!(data.deployments.len(), 1);
+        let deployment = &data.deployments[0];
+        assert_eq!(deployment.deployment_id, "abc");
+        assert_eq!(deployment.deployment_name, "prod");
+        assert_eq!(deployment.costs.dimensions.len(), 2);
+        assert_eq!(deployment.costs.dimensions[1].r#type, "data_out");
+        assert_eq!(deployment.hourly_rate, decimal("0.6"));
+        assert_eq!(deployment.period.start, "2026-10-01T00:00:00Z");
+    }
+
+    #[test]
+    fn v1_deployments_require_every_field() {
+        let bytes = br#"{"total_cost": 1.0, "deployments": [{"deployment_id": "abc", "deployment_name": "prod",
+            "costs": {"total": 1.0, "dimensions": []}, "period": {"start": "", "end": ""}}]}"#;
+        let error = parse_deployments(ApiVersion::V1, bytes, false).unwrap_err();
+        assert!(error.message.contains("hourly_rate"), "{}", error);
+    }
+
+    #[test]
+    fn v2_deployments() {
+        let bytes = br#"{"total_cost": 3.0, "deployments": [{"deployment_id": "abc", "deployment_name": "prod",
+            "costs": {"total": 3.0, "dimensions": [{"type": "capacity", "cost": 3.0}]},
+            "hourly_rate": 0.1, "period": {"start": "2026-10-01T00:00:00Z", "end": "2026-10-15T00:00:00Z"}}]}"#;
+        let data = parse_deployments(ApiVersion::V2, bytes, true).unwrap();
+        assert_eq!(data.total_cost, decimal("3"));
+        assert_eq!(data.deployments[0].costs.dimensions[0].cost, decimal("3"));
+        assert_eq!(data.deployments[0].period.end, "2026-10-15T00:00:00Z");
+    }
+
+    #[test]
+    fn v2_deployments_default_omitted_fields() {
+        let bytes = br#"{"deployments": [{"deployment_id": "abc", "deployment_name": "idle", "costs": {"total": 0}}]}"#;
+        let data = parse_deployments(ApiVersion::V2, bytes, true).unwrap();
+        assert_eq!(data.total_cost, Decimal::ZERO);
+        let deployment = &data.deployments[0];
+        assert!(deployment.costs.dimensions.is_empty());
+        assert_eq!(deployment.hourly_rate, Decimal::ZERO);
+        assert_eq!(deployment.period.start, "");
+        assert_eq!(deployment.period.end, "");
+
+        let data = parse_deployments(ApiVersion::V2, b"{}", true).unwrap();
+        assert!(data.deployments.is_empty());
+    }
+
+    #[test]
+    fn strict_rejects_unknown_fields() {
+        let bytes = br#"{"total_cost": 1.0, "currency": "EUR", "deployments": []}"#;
+        assert!(parse_deployments(ApiVersion::V2, bytes, false).is_ok());
+        let error = parse_deployments(ApiVersion::V2, bytes, true).unwrap_err();
+        assert_eq!(error.path, "currency");
+
+        let bytes = br#"{"data": [{"timestamp": 1, "values": [{"id": "abc", "name": "prod", "value": 0.5, "sku": "x"}]}]}"#;
+        assert!(parse_charts(ApiVersion::V1, bytes, false).is_ok());
+        let error = parse_charts(ApiVersion::V1, bytes, true).unwrap_err();
+        assert_eq!(error.path, "data[].values[].sku");
+    }
+
+    #[test]
+    fn v1_charts() {
+        let bytes = br#"{"data": [{"timestamp": 1760000000, "values": [{"id": "abc", "name": "prod", "value": 0.5}]}]}"#;
+        let data = parse_charts(ApiVersion::V1, bytes, true).unwrap();
+        assert_eq!(data.data[0].timestamp, 1760000000);
+        assert_eq!(data.data[0].values[0].value, decimal("0.5"));
+
+        assert!(parse_charts(ApiVersion::V1, br#"{"data": [{"timestamp": 1}]}"#, false).is_err());
+    }
+
+    #[test]
+    fn v2_charts_default_omitted_fields() {
+        let data = parse_charts(ApiVersion::V2, br#"{"data": [{"timestamp": 1}]}"#, true).unwrap();
+        assert!(data.data[0].values.is_empty());
+
+        let data = parse_charts(ApiVersion::V2, b"{}", true).unwrap();
+        assert!(data.data.is_empty());
+    }
+
+    #[test]
+    fn missing_buckets() {
+        let bytes = br#"{"data": [{"timestamp": 3600, "values": []}, {"timestamp": 10800, "values": []}]}"#;
+        let data = parse_charts(ApiVersion::V2, bytes, true).unwrap();
+        assert_eq!(data.missing_buckets(0, 14400, 3600), vec![0, 7200]);
+        assert!(data.missing_buckets(3600, 7200, 3600).is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+use hyper::header::HeaderValue;
+use serde_json::Value;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::kubernetes::InCluster;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Billing api key read from a key of a kubernetes secret through the api server,
+// and read again periodically so a rotated key is picked up without a restart
+#[derive(Clone, Debug)]
+pub struct K8sSecret {
+    cluster: InCluster,
+    path: String,
+    key: String,
+    authorization: Arc<RwLock<Option<HeaderValue>>>,
+    resource_version: Arc<RwLock<Option<String>>>,
+}
+
+impl K8sSecret {
+    // The secret is given as namespace/name/key
+    pub async fn new(secret: &str) -> BoxResult<Self> {
+        let (namespace, name, key) = match secret.splitn(3, '/').collect::<Vec<_>>()[..] {
+            [namespace, name, key] if !namespace.is_empty() && !name.is_empty() && !key.is_empty() => (namespace, name, key),
+            _ => return Err("kubernetes secret must be given as namespace/name/key".into()),
+        };
+
+        let secret = K8sSecret {
+            cluster: InCluster::new()?,
+            path: format!("/api/v1/namespaces/{}/secrets/{}", namespace, name),
+            key: key.to_string(),
+            authorization: Arc::new(RwLock::new(None)),
+            resource_version: Arc::new(RwLock::new(None)),
+        };
+        secret.reload().await?;
+        Ok(secret)
+    }
+
+    // Authorization header for the billing api
+    pub fn authorization(&self) -> Option<HeaderValue> {
+        self.authorization.read().unwrap().clone()
+    }
+
+    // Fetch the secret, returning whether the key changed since the last read
+    pub async fn reload(&self) -> BoxResult<bool> {
+        let secret: Value = self.cluster.get(&self.path).send().await?.error_for_status()?.json().await?;
+        let version = secret["metadata"]["resourceVersion"].as_str().map(String::from);
+        if version.is_some() && *self.resource_version.read().unwrap() == version {
+            return Ok(false);
+        }
+
+        let encoded = secret["data"][&self.key].as_str().ok_or_else(|| format!("secret has no key {}", self.key))?;
+        let api_key = String::from_utf8(base64::decode(encoded)?)?;
+        let api_key = api_key.trim();
+
+        // Take either the bare key or the whole header value
+        let mut value = match api_key.starts_with("ApiKey ") {
+            true => HeaderValue::from_str(api_key)?,
+            false => HeaderValue::from_str(&format!("ApiKey {}", api_key))?,
+        };
+        value.set_sensitive(true);
+
+        *self.authorization.write().unwrap() = Some(value);
+        *self.resource_version.write().unwrap() = version;
+        Ok(true)
+    }
+
+    // Read the secret every period, keeping the last key when the api server can not be reached
+    pub async fn watch(&self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match self.reload().await {
+                Ok(true) => {
+                    log::info!("{{\"fn\": \"watch\", \"msg\": \"reloaded api key from kubernetes secret\", \"path\": \"{}\"}}", self.path);
+                    metrics::increment_counter!("elastic_billing_secret_reloads_total");
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    log::error!("{{\"fn\": \"watch\", \"msg\": \"failed reading kubernetes secret\", \"error\": \"{}\"}}", e);
+                    metrics::increment_counter!("elastic_billing_secret_reload_errors_total");
+                }
+            }
+        }
+    }
+}
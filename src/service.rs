@@ -0,0 +1,158 @@
+use clap::ArgMatches;
+use std::ffi::OsString;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo, ServiceStartType,
+    ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+use crate::cli;
+use crate::settings::Settings;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// Name of the service and of its event log source
+const NAME: &str = "elastic-cloud-billing-exporter";
+
+// The options parsed in main, taken by the service main the dispatcher calls on its own thread
+static OPTIONS: Mutex<Option<(ArgMatches<'static>, Settings)>> = Mutex::new(None);
+
+// Register a service starting this executable with the options given before the service subcommand
+pub fn install(opts: &ArgMatches) -> Result<()> {
+    // Only the command line is kept for the service, so options set through ECBE_* variables do not count
+    if opts.occurrences_of("url") == 0 && opts.occurrences_of("config") == 0 {
+        return Err("service install requires --url or --config on the command line".into());
+    }
+
+    let mut launch_arguments: Vec<OsString> = std::env::args_os().skip(1).take_while(|arg| arg != "service").collect();
+    launch_arguments.extend(["service".into(), "run".into()]);
+
+    let info = ServiceInfo {
+        name: NAME.into(),
+        display_name: "Elastic Cloud Billing Exporter".into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments,
+        dependencies: vec![],
+        // Run as LocalSystem
+        account_name: None,
+        account_password: None,
+    };
+
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)?;
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description("Exports Elastic Cloud billing as prometheus metrics")?;
+    eventlog::register(NAME).map_err(|e| format!("failed to register the event log source: {}", e))?;
+
+    println!("Installed the {} service", NAME);
+    Ok(())
+}
+
+// Stop and remove the service and its event log source
+pub fn uninstall() -> Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(NAME, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)?;
+
+    // Deleting only marks the service, which is removed once it has stopped
+    service.delete()?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    eventlog::deregister(NAME).map_err(|e| format!("failed to remove the event log source: {}", e))?;
+
+    println!("Uninstalled the {} service", NAME);
+    Ok(())
+}
+
+// Hand the process to the service control manager, blocking until the service has stopped
+pub fn run(opts: ArgMatches<'static>, settings: Settings) -> Result<()> {
+    *OPTIONS.lock().unwrap() = Some((opts, settings));
+    service_dispatcher::start(NAME, ffi_service_main)?;
+    Ok(())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    // A service has no console, so everything goes to the application event log
+    if let Err(e) = eventlog::init(NAME, log::Level::Info) {
+        eprintln!("failed to log to the event log: {}", e);
+    }
+
+    // Stop and shutdown let the http server drain before the service reports stopped
+    let stop = Arc::new(Notify::new());
+    let handler = {
+        let stop = stop.clone();
+        move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                stop.notify_one();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+    let handle = match service_control_handler::register(NAME, handler) {
+        Ok(handle) => handle,
+        Err(e) => {
+            log::error!("{{\"fn\": \"service_main\", \"error\": \"{}\"}}", e);
+            return;
+        }
+    };
+
+    // Startup checks and the warm up run before the port is bound, so the service is pending until then
+    if let Err(e) = handle.set_service_status(status(ServiceState::StartPending, ServiceExitCode::NO_ERROR)) {
+        log::error!("{{\"fn\": \"service_main\", \"error\": \"{}\"}}", e);
+    }
+    let ready = move || {
+        if let Err(e) = handle.set_service_status(status(ServiceState::Running, ServiceExitCode::NO_ERROR)) {
+            log::error!("{{\"fn\": \"service_main\", \"error\": \"{}\"}}", e);
+        }
+    };
+
+    let exit_code = match serve(ready, stop) {
+        Ok(()) => ServiceExitCode::NO_ERROR,
+        Err(e) => {
+            log::error!("{{\"fn\": \"service_main\", \"msg\": \"exporter failed\", \"error\": \"{}\"}}", e);
+            ServiceExitCode::ServiceSpecific(1)
+        }
+    };
+
+    if let Err(e) = handle.set_service_status(status(ServiceState::Stopped, exit_code)) {
+        log::error!("{{\"fn\": \"service_main\", \"error\": \"{}\"}}", e);
+    }
+}
+
+// Run the exporter on its own runtime until the service is told to stop
+fn serve(ready: impl FnOnce(), stop: Arc<Notify>) -> Result<()> {
+    let (opts, settings) = OPTIONS.lock().unwrap().take().ok_or("the service options were not set")?;
+    cli::runtime(&settings)?.block_on(cli::run(opts, settings, ready, async move { stop.notified().await }))
+}
+
+fn status(current_state: ServiceState, exit_code: ServiceExitCode) -> ServiceStatus {
+    let controls_accepted = match current_state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+    };
+    // The startup check may retry with backoff, which waits up to a minute between attempts
+    let wait_hint = match current_state {
+        ServiceState::StartPending => Duration::from_secs(90),
+        _ => Duration::default(),
+    };
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state,
+        controls_accepted,
+        exit_code,
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    }
+}
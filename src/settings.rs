@@ -0,0 +1,412 @@
+use clap::ArgMatches;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::Method;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use tower_http::cors::{self, CorsLayer, Origin};
+use url::Url;
+
+use crate::config::{Aggregation, ApiVersion, ChartsBucket, NameMask, Rounding, MAX_ROUND_COSTS};
+
+// Command line options, parsed and validated up front so every bad flag is reported at once
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub port: u16,
+    pub grpc_port: Option<u16>,
+    pub timeout: u64,
+    pub url: Option<Url>,
+    pub fallback_urls: Vec<Url>,
+    pub failover_after: u32,
+    pub headers: HeaderMap,
+    pub user_agent: HeaderValue,
+    pub idle_timeout: u64,
+    pub interval: u64,
+    pub stale_intervals: u64,
+    pub warm_up: u64,
+    pub access_log: Option<String>,
+    pub admin_token: Option<String>,
+    pub api_version: ApiVersion,
+    pub audit_log: Option<String>,
+    pub charts_aggregation: Aggregation,
+    pub charts_bucket: Option<ChartsBucket>,
+    pub charts_window: i64,
+    pub config: Option<String>,
+    pub cors_origins: Vec<String>,
+    pub cors_methods: Vec<Method>,
+    pub cost_centers: Option<String>,
+    pub cost_centers_reload: u64,
+    pub data_dir: Option<String>,
+    pub history_retention: u64,
+    pub k8s_secret: Option<String>,
+    pub k8s_secret_reload: u64,
+    pub leader_election: Option<String>,
+    pub lease_duration: u64,
+    pub max_body_size: u64,
+    pub anomaly_ratio: f64,
+    pub metadata: Option<String>,
+    pub metadata_reload: u64,
+    pub max_retries: u32,
+    pub retry_statuses: Vec<u16>,
+    pub poll_webhook: Option<String>,
+    pub stable_ids: bool,
+    pub mask_names: Option<NameMask>,
+    pub mask_secret: Option<String>,
+    pub round_costs: Option<u32>,
+    pub rounding: Option<Rounding>,
+    pub startup_check: String,
+    pub fail_on_empty: bool,
+    pub strict_parsing: bool,
+    pub top_deployments: usize,
+    pub week_to_date: bool,
+    pub quarter_to_date: bool,
+    pub topology: bool,
+    pub cost_by_kind: bool,
+    pub invoices: usize,
+    pub redis_url: Option<Url>,
+    pub redis_key: String,
+    pub worker_threads: Option<usize>,
+    pub graphql: bool,
+    pub pprof: bool,
+    // Options of the gen-rules subcommand
+    pub rules_stale_after: u64,
+    pub rules_spike_ratio: f64,
+}
+
+// Every invalid option, one per line
+#[derive(Debug)]
+pub struct SettingsError(pub Vec<String>);
+
+impl std::error::Error for SettingsError {}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "invalid options:")?;
+        for error in &self.0 {
+            writeln!(f, "  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+// The defaults of every command line option, for building a State without parsing a command line
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            port: 8080,
+            grpc_port: None,
+            timeout: 60,
+            url: None,
+            fallback_urls: Vec::new(),
+            failover_after: 3,
+            headers: HeaderMap::new(),
+            user_agent: HeaderValue::from_static(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"))),
+            idle_timeout: 0,
+            interval: 0,
+            stale_intervals: 3,
+            warm_up: 0,
+            access_log: None,
+            admin_token: None,
+            api_version: ApiVersion::Auto,
+            audit_log: None,
+            charts_aggregation: Aggregation::Latest,
+            charts_bucket: None,
+            charts_window: 3600,
+            config: None,
+            cors_origins: Vec::new(),
+            cors_methods: vec![Method::GET],
+            cost_centers: None,
+            cost_centers_reload: 60,
+            data_dir: None,
+            history_retention: 0,
+            k8s_secret: None,
+            k8s_secret_reload: 300,
+            leader_election: None,
+            lease_duration: 15,
+            max_body_size: 16777216,
+            anomaly_ratio: 1.5,
+            metadata: None,
+            metadata_reload: 60,
+            max_retries: 3,
+            retry_statuses: vec![500, 502, 503, 504],
+            poll_webhook: None,
+            stable_ids: false,
+            mask_names: None,
+            mask_secret: None,
+            round_costs: None,
+            rounding: None,
+            startup_check: "skip".to_string(),
+            fail_on_empty: false,
+            strict_parsing: false,
+            top_deployments: 0,
+            week_to_date: false,
+            quarter_to_date: false,
+            topology: false,
+            cost_by_kind: false,
+            invoices: 0,
+            redis_url: None,
+            redis_key: "elastic-billing-exporter:snapshot".to_string(),
+            worker_threads: None,
+            graphql: false,
+            pprof: false,
+            rules_stale_after: 3600,
+            rules_spike_ratio: 1.5,
+        }
+    }
+}
+
+impl Settings {
+    pub fn from_matches(opts: &ArgMatches) -> Result<Self, SettingsError> {
+        let mut errors = Vec::new();
+
+        let mut headers = HeaderMap::new();
+        for header in opts.values_of("header").unwrap_or_default() {
+            match header.split_once(':') {
+                Some((name, value)) => match (HeaderName::from_str(name.trim()), HeaderValue::from_str(value.trim())) {
+                    (Ok(name), Ok(mut value)) => {
+                        // Header values usually carry the api key, keep them out of debug output
+                        value.set_sensitive(true);
+                        headers.insert(name, value);
+                    }
+                    _ => errors.push(format!("--header \"{}\": not a valid header", header)),
+                },
+                None => errors.push(format!("--header \"{}\": expected \"Name: value\"", header)),
+            }
+        }
+
+        // Throttling is always retried, other statuses only when listed
+        let mut retry_statuses = Vec::new();
+        for status in opts.values_of("retry_statuses").unwrap_or_default().filter(|s| !s.trim().is_empty()) {
+            match status.trim().parse::<u16>() {
+                Ok(status) if (400..=599).contains(&status) && status != 429 => retry_statuses.push(status),
+                _ => errors.push(format!("--retry-statuses \"{}\": expected a status from 400 to 599 other than 429", status)),
+            }
+        }
+
+        // Origins are matched exactly by browsers, so they must be a bare scheme and host
+        let mut cors_origins = Vec::new();
+        for origin in opts.values_of("cors_origins").unwrap_or_default().map(str::trim).filter(|o| !o.is_empty()) {
+            match origin == "*" || Url::parse(origin).map(|url| url.origin().ascii_serialization() == origin).unwrap_or(false) {
+                true => cors_origins.push(origin.to_string()),
+                false => errors.push(format!("--cors-origins \"{}\": expected * or an origin such as https://example.com", origin)),
+            }
+        }
+        let mut cors_methods = Vec::new();
+        for method in opts.values_of("cors_methods").unwrap_or_default().map(str::trim).filter(|m| !m.is_empty()) {
+            match Method::from_str(&method.to_ascii_uppercase()) {
+                Ok(method) => cors_methods.push(method),
+                Err(_) => errors.push(format!("--cors-methods \"{}\": not a valid method", method)),
+            }
+        }
+
+        let user_agent = match HeaderValue::from_str(opts.value_of("user_agent").unwrap_or_default()) {
+            Ok(user_agent) => user_agent,
+            Err(_) => {
+                errors.push("--user-agent: not a valid header value".to_string());
+                HeaderValue::from_static("")
+            }
+        };
+
+        let mut settings = Settings {
+            port: number(opts, "port", 1..=65535, &mut errors),
+            grpc_port: match opts.is_present("grpc_port") {
+                true => Some(number(opts, "grpc_port", 1..=65535, &mut errors)),
+                false => None,
+            },
+            timeout: number(opts, "timeout", 1..=3600, &mut errors),
+            url: opts.value_of("url").and_then(|url| parse_url("--url", url, &mut errors)),
+            fallback_urls: opts
+                .values_of("fallback_url")
+                .unwrap_or_default()
+                .filter_map(|url| parse_url("--fallback-url", url, &mut errors))
+                .collect(),
+            failover_after: number(opts, "failover_after", 1..=100, &mut errors),
+            headers,
+            user_agent,
+            idle_timeout: number(opts, "idle_timeout", 0..=2678400, &mut errors),
+            interval: number(opts, "interval", 0..=86400, &mut errors),
+            stale_intervals: number(opts, "stale_intervals", 1..=1000, &mut errors),
+            warm_up: number(opts, "warm_up", 0..=600, &mut errors),
+            access_log: opts.value_of("access_log").map(String::from),
+            admin_token: opts.value_of("admin_token").map(String::from),
+            api_version: opts.value_of("api_version").unwrap_or("auto").parse().unwrap_or(ApiVersion::Auto),
+            audit_log: opts.value_of("audit_log").map(String::from),
+            charts_aggregation: opts.value_of("charts_aggregation").unwrap_or("latest").parse().unwrap_or(Aggregation::Latest),
+            charts_bucket: opts.value_of("charts_bucket").and_then(|bucket| bucket.parse().ok()),
+            charts_window: number(opts, "charts_window", 60..=2678400, &mut errors),
+            config: opts.value_of("config").map(String::from),
+            cors_origins,
+            cors_methods,
+            cost_centers: opts.value_of("cost_centers").map(String::from),
+            cost_centers_reload: number(opts, "cost_centers_reload", 1..=86400, &mut errors),
+            data_dir: opts.value_of("data_dir").map(String::from),
+            history_retention: number(opts, "history_retention", 0..=36500, &mut errors),
+            k8s_secret: opts.value_of("k8s_secret").map(String::from),
+            k8s_secret_reload: number(opts, "k8s_secret_reload", 10..=86400, &mut errors),
+            leader_election: opts.value_of("leader_election").map(String::from),
+            lease_duration: number(opts, "lease_duration", 3..=3600, &mut errors),
+            metadata: opts.value_of("metadata").map(String::from),
+            metadata_reload: number(opts, "metadata_reload", 1..=86400, &mut errors),
+            max_body_size: number(opts, "max_body_size", 1024..=1073741824, &mut errors),
+            anomaly_ratio: ratio(opts, "anomaly_ratio", 1.0..=1000.0, &mut errors),
+            max_retries: number(opts, "max_retries", 0..=10, &mut errors),
+            retry_statuses,
+            poll_webhook: opts
+                .value_of("poll_webhook")
+                .filter(|url| parse_url("--poll-webhook", url, &mut errors).is_some())
+                .map(String::from),
+            stable_ids: flag(opts, "stable_ids", &mut errors),
+            mask_names: opts.value_of("mask_names").and_then(|mask| mask.parse().ok()),
+            mask_secret: opts.value_of("mask_secret").map(String::from),
+            round_costs: match opts.is_present("round_costs") {
+                true => Some(number(opts, "round_costs", 0..=MAX_ROUND_COSTS, &mut errors)),
+                false => None,
+            },
+            rounding: opts.value_of("rounding").and_then(|rounding| rounding.parse().ok()),
+            startup_check: opts.value_of("startup_check").unwrap_or("skip").to_string(),
+            fail_on_empty: flag(opts, "fail_on_empty", &mut errors),
+            strict_parsing: flag(opts, "strict_parsing", &mut errors),
+            top_deployments: number(opts, "top_deployments", 0..=1000, &mut errors),
+            week_to_date: flag(opts, "week_to_date", &mut errors),
+            quarter_to_date: flag(opts, "quarter_to_date", &mut errors),
+            topology: flag(opts, "topology", &mut errors),
+            cost_by_kind: flag(opts, "cost_by_kind", &mut errors),
+            invoices: number(opts, "invoices", 0..=120, &mut errors),
+            redis_url: opts.value_of("redis_url").and_then(|url| parse_url("--redis-url", url, &mut errors)),
+            redis_key: opts.value_of("redis_key").unwrap_or_default().to_string(),
+            worker_threads: match opts.is_present("worker_threads") {
+                true => Some(number(opts, "worker_threads", 1..=1024, &mut errors)),
+                false => None,
+            },
+            graphql: flag(opts, "graphql", &mut errors),
+            pprof: flag(opts, "pprof", &mut errors),
+            ..Settings::default()
+        };
+
+        if let Some(sub) = opts.subcommand_matches("gen-rules") {
+            settings.rules_stale_after = number(sub, "stale_after", 60..=2678400, &mut errors);
+            settings.rules_spike_ratio = ratio(sub, "spike_ratio", 1.0..=1000.0, &mut errors);
+        }
+
+        if settings.grpc_port.is_some() && !cfg!(feature = "grpc") {
+            errors.push("--grpc-port: requires building with the grpc feature".to_string());
+        }
+
+        if settings.graphql && !cfg!(feature = "graphql") {
+            errors.push("--graphql: requires building with the graphql feature".to_string());
+        }
+
+        if let Some(secret) = &settings.k8s_secret {
+            if secret.splitn(3, '/').filter(|part| !part.is_empty()).count() != 3 {
+                errors.push(format!("--k8s-secret \"{}\": expected namespace/name/key", secret));
+            }
+        }
+
+        if let Some(lease) = &settings.leader_election {
+            if lease.split_once('/').is_none_or(|(namespace, name)| namespace.is_empty() || name.is_empty()) {
+                errors.push(format!("--leader-election \"{}\": expected namespace/name", lease));
+            }
+        }
+
+        // A cpu profile can keep a core busy for minutes, so the endpoints are never served without a token
+        if settings.pprof && settings.admin_token.is_none() {
+            errors.push("--pprof: requires --admin-token".to_string());
+        }
+
+        if settings.pprof && !cfg!(feature = "profiling") {
+            errors.push("--pprof: requires building with the profiling feature".to_string());
+        }
+
+        match errors.is_empty() {
+            true => Ok(settings),
+            false => Err(SettingsError(errors)),
+        }
+    }
+
+    // Cors headers for the json api, preflight requests are answered by the layer itself
+    pub fn cors_layer(&self) -> CorsLayer {
+        let layer = CorsLayer::new().allow_methods(self.cors_methods.clone());
+        match self.cors_origins.iter().any(|origin| origin == "*") {
+            true => layer.allow_origin(cors::any()),
+            false => layer.allow_origin(Origin::list(self.cors_origins.iter().filter_map(|origin| HeaderValue::from_str(origin).ok()))),
+        }
+    }
+}
+
+// Parse a numeric option, recording an error naming the flag and its accepted range when invalid
+fn number<T>(opts: &ArgMatches, name: &str, range: RangeInclusive<T>, errors: &mut Vec<String>) -> T
+where
+    T: FromStr + PartialOrd + Default + fmt::Display,
+{
+    let flag = format!("--{}", name.replace('_', "-"));
+    let value = opts.value_of(name).unwrap_or_default();
+    match value.parse::<T>() {
+        Ok(number) if range.contains(&number) => number,
+        _ => {
+            errors.push(format!("{} \"{}\": expected a whole number from {} to {}", flag, value, range.start(), range.end()));
+            T::default()
+        }
+    }
+}
+
+// Clap ignores the environment for options without a value, so switches read their ECBE_ variable here
+fn flag(opts: &ArgMatches, name: &str, errors: &mut Vec<String>) -> bool {
+    if opts.is_present(name) {
+        return true;
+    }
+    let var = format!("ECBE_{}", name.to_uppercase());
+    match std::env::var(&var).as_deref() {
+        Err(_) | Ok("") => false,
+        Ok("1") | Ok("true") => true,
+        Ok("0") | Ok("false") => false,
+        Ok(value) => {
+            errors.push(format!("{} \"{}\": expected 1, true, 0 or false", var, value));
+            false
+        }
+    }
+}
+
+fn ratio(opts: &ArgMatches, name: &str, range: RangeInclusive<f64>, errors: &mut Vec<String>) -> f64 {
+    let flag = format!("--{}", name.replace('_', "-"));
+    let value = opts.value_of(name).unwrap_or_default();
+    match value.parse::<f64>() {
+        Ok(ratio) if range.contains(&ratio) => ratio,
+        _ => {
+            errors.push(format!("{} \"{}\": expected a number from {} to {}", flag, value, range.start(), range.end()));
+            *range.start()
+        }
+    }
+}
+
+fn parse_url(flag: &str, url: &str, errors: &mut Vec<String>) -> Option<Url> {
+    match url.parse() {
+        Ok(url) => Some(url),
+        Err(e) => {
+            errors.push(format!("{} \"{}\": {}", flag, url, e));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switches_read_their_environment_variable() {
+        let args = ["elastic-cloud-billing-exporter", "--url", "http://127.0.0.1:9111"];
+
+        std::env::set_var("ECBE_PPROF", "true");
+        let error = Settings::from_matches(&crate::cli::app().get_matches_from(args)).unwrap_err();
+        assert!(error.0.contains(&"--pprof: requires --admin-token".to_string()));
+
+        std::env::set_var("ECBE_PPROF", "yes");
+        let error = Settings::from_matches(&crate::cli::app().get_matches_from(args)).unwrap_err();
+        assert!(error.0.contains(&"ECBE_PPROF \"yes\": expected 1, true, 0 or false".to_string()));
+
+        std::env::set_var("ECBE_PPROF", "0");
+        let settings = Settings::from_matches(&crate::cli::app().get_matches_from(args)).unwrap();
+        assert!(!settings.pprof);
+        std::env::remove_var("ECBE_PPROF");
+    }
+}
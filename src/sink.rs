@@ -0,0 +1,425 @@
+use chrono::Utc;
+use metrics::Label;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+
+#[cfg(feature = "bigquery")]
+use crate::bigquery::BigQuerySink;
+#[cfg(feature = "kafka")]
+use crate::kafka::KafkaSink;
+use crate::redact;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Sinks enabled in the config file, prometheus alone when none are listed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkConfig {
+    Prometheus,
+    Statsd {
+        address: String,
+        #[serde(default)]
+        prefix: Option<String>,
+    },
+    File {
+        path: String,
+    },
+    Elasticsearch {
+        #[serde(serialize_with = "redact::serialize_url")]
+        url: Url,
+        #[serde(default = "default_index")]
+        index: String,
+        #[serde(default, serialize_with = "redact::serialize_secret")]
+        api_key: Option<String>,
+    },
+    Kafka {
+        // Comma separated host:port of the bootstrap brokers
+        brokers: String,
+        topic: String,
+        #[serde(default)]
+        auth: Option<KafkaAuth>,
+    },
+    Bigquery {
+        project: String,
+        dataset: String,
+        table: String,
+        #[serde(default)]
+        credentials: Option<String>,
+        #[serde(default)]
+        endpoint: Option<String>,
+    },
+}
+
+// Sasl credentials for the kafka brokers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KafkaAuth {
+    // sasl_ssl unless given, or sasl_plaintext
+    #[serde(default)]
+    pub security_protocol: Option<String>,
+    // PLAIN unless given, or SCRAM-SHA-256 and SCRAM-SHA-512
+    #[serde(default)]
+    pub mechanism: Option<String>,
+    pub username: String,
+    #[serde(serialize_with = "redact::serialize_password")]
+    pub password: String,
+}
+
+fn default_index() -> String {
+    "elastic-billing".to_string()
+}
+
+// Destination for the billing series built from a snapshot, so other backends can be fed
+// the same gauges and counters as the prometheus endpoint
+pub trait Sink: Debug + Send + Sync {
+    fn gauge(&self, name: &'static str, value: f64, labels: &[Label]);
+    fn counter(&self, name: &'static str, value: u64, labels: &[Label]);
+
+    // Called once all series of a poll were recorded, for sinks that batch
+    fn flush(&self) {}
+
+    // Whether the series leave the exporter, so they are only sent once per successful poll
+    fn is_push(&self) -> bool {
+        true
+    }
+}
+
+// Records into the global metrics recorder rendered at /metrics
+#[derive(Debug, Clone, Default)]
+pub struct PrometheusSink;
+
+impl Sink for PrometheusSink {
+    fn gauge(&self, name: &'static str, value: f64, labels: &[Label]) {
+        metrics::gauge!(name, value, labels.iter());
+    }
+
+    fn counter(&self, name: &'static str, value: u64, labels: &[Label]) {
+        metrics::counter!(name, value, labels.iter());
+    }
+
+    fn is_push(&self) -> bool {
+        false
+    }
+}
+
+// Sends dogstatsd style datagrams, with labels as tags
+#[derive(Debug)]
+pub struct StatsdSink {
+    socket: UdpSocket,
+    address: String,
+    prefix: Option<String>,
+}
+
+impl StatsdSink {
+    pub fn new(address: &str, prefix: Option<String>) -> BoxResult<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        Ok(StatsdSink {
+            socket,
+            address: address.to_string(),
+            prefix,
+        })
+    }
+
+    fn send(&self, name: &str, value: String, kind: &str, labels: &[Label]) {
+        let mut line = match &self.prefix {
+            Some(prefix) => format!("{}.{}:{}|{}", prefix, name, value, kind),
+            None => format!("{}:{}|{}", name, value, kind),
+        };
+        if !labels.is_empty() {
+            let tags: Vec<String> = labels.iter().map(|l| format!("{}:{}", l.key(), l.value().replace(['|', ',', '#'], "_"))).collect();
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+        if let Err(e) = self.socket.send_to(line.as_bytes(), &self.address) {
+            log::debug!("{{\"fn\": \"send\", \"sink\": \"statsd\", \"error\": \"{}\"}}", e);
+            metrics::increment_counter!("elastic_billing_sink_errors_total", "sink" => "statsd");
+        }
+    }
+}
+
+impl Sink for StatsdSink {
+    fn gauge(&self, name: &'static str, value: f64, labels: &[Label]) {
+        self.send(name, value.to_string(), "g", labels);
+    }
+
+    fn counter(&self, name: &'static str, value: u64, labels: &[Label]) {
+        self.send(name, value.to_string(), "c", labels);
+    }
+}
+
+// Appends every series as a json line, buffered until the poll is flushed
+#[derive(Debug)]
+pub struct FileSink {
+    path: Arc<String>,
+    buffer: Mutex<Vec<u8>>,
+    // Keeps two flushes from interleaving their lines
+    writer: Arc<Mutex<()>>,
+}
+
+#[derive(Serialize)]
+struct FileSeries<'a> {
+    timestamp: i64,
+    name: &'a str,
+    kind: &'a str,
+    value: f64,
+    labels: BTreeMap<&'a str, &'a str>,
+}
+
+impl FileSink {
+    pub fn new(path: &str) -> Self {
+        FileSink {
+            path: Arc::new(path.to_string()),
+            buffer: Mutex::new(Vec::new()),
+            writer: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn push(&self, name: &str, kind: &str, value: f64, labels: &[Label]) {
+        let series = FileSeries {
+            timestamp: Utc::now().timestamp(),
+            name,
+            kind,
+            value,
+            labels: labels.iter().map(|l| (l.key(), l.value())).collect(),
+        };
+        let mut buffer = self.buffer.lock().unwrap();
+        if serde_json::to_writer(&mut *buffer, &series).is_ok() {
+            buffer.push(b'\n');
+        }
+    }
+}
+
+impl Sink for FileSink {
+    fn gauge(&self, name: &'static str, value: f64, labels: &[Label]) {
+        self.push(name, "gauge", value, labels);
+    }
+
+    fn counter(&self, name: &'static str, value: u64, labels: &[Label]) {
+        self.push(name, "counter", value as f64, labels);
+    }
+
+    // The write runs on a blocking thread, so a slow disk never holds up polling or scrapes
+    fn flush(&self) {
+        let buffer = std::mem::take(&mut *self.buffer.lock().unwrap());
+        if buffer.is_empty() {
+            return;
+        }
+        let (path, writer) = (self.path.clone(), self.writer.clone());
+        tokio::task::spawn_blocking(move || {
+            let _writer = writer.lock().unwrap();
+            let result = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path.as_str())
+                .and_then(|mut file| file.write_all(&buffer));
+            if let Err(e) = result {
+                log::error!("{{\"fn\": \"flush\", \"sink\": \"file\", \"path\": \"{}\", \"error\": \"{}\"}}", path, e);
+                metrics::increment_counter!("elastic_billing_sink_errors_total", "sink" => "file");
+            }
+        });
+    }
+}
+
+// Bulk indexes every series of a poll as a document, for analysis in kibana
+pub struct ElasticsearchSink {
+    url: Url,
+    index: String,
+    api_key: Option<String>,
+    client: reqwest::Client,
+    buffer: Mutex<Vec<u8>>,
+}
+
+impl fmt::Debug for ElasticsearchSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ElasticsearchSink")
+            .field("url", &redact::url(&self.url))
+            .field("index", &self.index)
+            .finish()
+    }
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    #[serde(rename = "@timestamp")]
+    timestamp: String,
+    name: &'a str,
+    kind: &'a str,
+    value: f64,
+    labels: BTreeMap<&'a str, &'a str>,
+}
+
+#[derive(Deserialize)]
+struct BulkResponse {
+    errors: bool,
+}
+
+impl ElasticsearchSink {
+    pub fn new(url: &Url, index: &str, api_key: Option<String>, timeout: u64) -> BoxResult<Self> {
+        Ok(ElasticsearchSink {
+            url: url.clone(),
+            index: index.to_string(),
+            api_key,
+            client: reqwest::Client::builder().timeout(Duration::from_secs(timeout)).build()?,
+            buffer: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn push(&self, name: &str, kind: &str, value: f64, labels: &[Label]) {
+        let document = Document {
+            timestamp: Utc::now().to_rfc3339(),
+            name,
+            kind,
+            value,
+            labels: labels.iter().map(|l| (l.key(), l.value())).collect(),
+        };
+        // Create works for plain indices as well as data streams
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.extend_from_slice(b"{\"create\":{}}\n");
+        if serde_json::to_writer(&mut *buffer, &document).is_ok() {
+            buffer.push(b'\n');
+        }
+    }
+
+    async fn bulk(request: reqwest::RequestBuilder) -> BoxResult<()> {
+        let response: BulkResponse = request
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.without_url())?
+            .json()
+            .await?;
+        match response.errors {
+            true => Err("some documents were rejected".into()),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Sink for ElasticsearchSink {
+    fn gauge(&self, name: &'static str, value: f64, labels: &[Label]) {
+        self.push(name, "gauge", value, labels);
+    }
+
+    fn counter(&self, name: &'static str, value: u64, labels: &[Label]) {
+        self.push(name, "counter", value as f64, labels);
+    }
+
+    // Post in the background, so a slow cluster never holds up polling
+    fn flush(&self) {
+        let buffer = std::mem::take(&mut *self.buffer.lock().unwrap());
+        if buffer.is_empty() {
+            return;
+        }
+
+        // Credentials in the url are sent as basic auth, unless there is an api key
+        let url = format!("{}/{}/_bulk", without_credentials(&self.url).as_str().trim_end_matches('/'), self.index);
+        let mut request = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
+            .body(buffer);
+        if let Some(api_key) = &self.api_key {
+            request = request.header(reqwest::header::AUTHORIZATION, format!("ApiKey {}", api_key));
+        } else if !self.url.username().is_empty() {
+            request = request.basic_auth(self.url.username(), self.url.password());
+        }
+
+        let index = self.index.clone();
+        tokio::spawn(async move {
+            if let Err(e) = Self::bulk(request).await {
+                log::error!("{{\"fn\": \"flush\", \"sink\": \"elasticsearch\", \"index\": \"{}\", \"error\": \"{}\"}}", index, e);
+                metrics::increment_counter!("elastic_billing_sink_errors_total", "sink" => "elasticsearch");
+            }
+        });
+    }
+}
+
+// Credentials are sent as basic auth instead, so they do not end up in error messages
+pub fn without_credentials(url: &Url) -> Url {
+    let mut url = url.clone();
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url
+}
+
+// Every enabled sink, each series is handed to all of them
+#[derive(Debug, Clone)]
+pub struct Sinks {
+    sinks: Vec<Arc<dyn Sink>>,
+}
+
+impl Default for Sinks {
+    fn default() -> Self {
+        Sinks {
+            sinks: vec![Arc::new(PrometheusSink)],
+        }
+    }
+}
+
+impl Sinks {
+    pub fn from_config(configs: &[SinkConfig], timeout: u64) -> BoxResult<Self> {
+        if configs.is_empty() {
+            return Ok(Sinks::default());
+        }
+
+        let mut sinks: Vec<Arc<dyn Sink>> = Vec::new();
+        for config in configs {
+            match config {
+                SinkConfig::Prometheus => sinks.push(Arc::new(PrometheusSink)),
+                SinkConfig::Statsd { address, prefix } => sinks.push(Arc::new(StatsdSink::new(address, prefix.clone())?)),
+                SinkConfig::File { path } => sinks.push(Arc::new(FileSink::new(path))),
+                SinkConfig::Elasticsearch { url, index, api_key } => sinks.push(Arc::new(ElasticsearchSink::new(url, index, api_key.clone(), timeout)?)),
+                #[cfg(feature = "kafka")]
+                SinkConfig::Kafka { brokers, topic, auth } => sinks.push(Arc::new(KafkaSink::new(brokers, topic, auth.as_ref(), timeout)?)),
+                #[cfg(feature = "bigquery")]
+                SinkConfig::Bigquery { project, dataset, table, credentials, endpoint } => sinks.push(Arc::new(BigQuerySink::new(
+                    project,
+                    dataset,
+                    table,
+                    credentials.as_deref(),
+                    endpoint.as_deref(),
+                    timeout,
+                )?)),
+                // Still parsed when compiled out, so the config file fails loudly instead of being ignored
+                #[cfg(not(feature = "kafka"))]
+                SinkConfig::Kafka { .. } => return Err("the kafka sink requires building with the kafka feature".into()),
+                #[cfg(not(feature = "bigquery"))]
+                SinkConfig::Bigquery { .. } => return Err("the bigquery sink requires building with the bigquery feature".into()),
+            }
+        }
+        Ok(Sinks { sinks })
+    }
+
+    // Only the sinks rendered by the exporter itself, for scrapes that do not bring a new poll
+    pub fn pull(&self) -> Sinks {
+        Sinks {
+            sinks: self.sinks.iter().filter(|sink| !sink.is_push()).cloned().collect(),
+        }
+    }
+
+    pub fn gauge(&self, name: &'static str, value: f64, labels: &[Label]) {
+        for sink in &self.sinks {
+            sink.gauge(name, value, labels);
+        }
+    }
+
+    pub fn counter(&self, name: &'static str, value: u64, labels: &[Label]) {
+        for sink in &self.sinks {
+            sink.counter(name, value, labels);
+        }
+    }
+
+    pub fn flush(&self) {
+        for sink in &self.sinks {
+            sink.flush();
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::config::Organization;
+use crate::money;
+use crate::schema::{Data, DataV2, Invoice, OrganizationInfo, Overview};
+use crate::topology::{Location, Tier};
+
+// Billing data gathered by a single poll, swapped in whole once every organization succeeded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub timestamp: i64,
+    pub organizations: Vec<OrganizationSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OrganizationSnapshot {
+    pub organization: Organization,
+    pub day: DataV2,
+    pub month: DataV2,
+    #[serde(default)]
+    pub week: Option<DataV2>,
+    #[serde(default)]
+    pub quarter: Option<DataV2>,
+    pub charts: Data,
+    #[serde(default)]
+    pub charts_missing: u64,
+    // Change in monthly cost per hour since the previous poll, by deployment id
+    #[serde(default)]
+    pub cost_rates: BTreeMap<String, Decimal>,
+    // Tiers of every deployment by id, when --topology is set
+    #[serde(default)]
+    pub topology: BTreeMap<String, Vec<Tier>>,
+    // Region and provider of every deployment by id, when --topology is set
+    #[serde(default)]
+    pub locations: BTreeMap<String, Location>,
+    // Monthly cost of every deployment by product kind, when --cost-by-kind is set
+    #[serde(default)]
+    pub cost_by_kind: BTreeMap<String, BTreeMap<String, Decimal>>,
+    #[serde(default)]
+    pub overview: Option<Overview>,
+    #[serde(default)]
+    pub invoices: Vec<Invoice>,
+    #[serde(default)]
+    pub info: Option<OrganizationInfo>,
+}
+
+// Current costs of a deployment with a forecast for the end of the month
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeploymentCosts {
+    pub org_id: Option<String>,
+    pub id: String,
+    pub name: String,
+    pub daily_cost: Decimal,
+    pub monthly_cost: Decimal,
+    pub hourly_rate: Decimal,
+    pub forecast: Decimal,
+}
+
+// Cost changes of a deployment, since the previous poll, midnight and the start of the month.
+// The changes since the previous poll are null when it is from another billing month
+#[derive(Serialize, Debug, Clone)]
+pub struct DeploymentDiff {
+    pub org_id: Option<String>,
+    pub id: String,
+    pub name: String,
+    pub since_last_poll: Option<Decimal>,
+    pub since_midnight: Decimal,
+    pub since_month_start: Decimal,
+    pub hourly_rate: Decimal,
+    pub hourly_rate_change: Option<Decimal>,
+}
+
+impl OrganizationSnapshot {
+    // Monthly cost summed by provider and region, with deployments the lookup missed under unknown.
+    // Empty unless --topology looked up the locations
+    pub fn cost_by_region(&self) -> BTreeMap<(String, String), Decimal> {
+        let mut regions = BTreeMap::new();
+        if self.locations.is_empty() {
+            return regions;
+        }
+        for deployment in &self.month.deployments {
+            let key = match self.locations.get(&deployment.deployment_id) {
+                Some(location) => (location.provider.clone(), location.region.clone()),
+                None => ("unknown".to_string(), "unknown".to_string()),
+            };
+            *regions.entry(key).or_insert(Decimal::ZERO) += deployment.costs.total;
+        }
+        regions
+    }
+
+    // Deployments of the month still running up cost, leaving out deleted ones that stay listed until
+    // the month ends. The charts window would flap with every gap in its buckets. None when the
+    // month has nothing billed yet, as right after it rolls over
+    pub fn active_deployments(&self) -> Option<BTreeSet<&str>> {
+        match self.month.deployments.is_empty() {
+            true => None,
+            false => Some(
+                self.month
+                    .deployments
+                    .iter()
+                    .filter(|d| d.hourly_rate > Decimal::ZERO)
+                    .map(|d| d.deployment_id.as_str())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl Snapshot {
+    // Time of the poll, None when a snapshot loaded from disk or redis holds an out of range timestamp
+    pub fn time(&self) -> Option<DateTime<Utc>> {
+        Utc.timestamp_opt(self.timestamp, 0).single()
+    }
+
+    // Whether the snapshot is from the same billing month as another time
+    pub fn same_month(&self, other: DateTime<Utc>) -> bool {
+        self.time().is_some_and(|time| (time.year(), time.month()) == (other.year(), other.month()))
+    }
+
+    // Hours left in the month of the snapshot
+    pub fn remaining_hours(&self) -> Decimal {
+        let now = match self.time() {
+            Some(now) => now,
+            None => return Decimal::ZERO,
+        };
+        let (year, month) = match now.month() {
+            12 => (now.year() + 1, 1),
+            month => (now.year(), month + 1),
+        };
+        let end = Utc.ymd(year, month, 1).and_hms(0, 0, 0);
+        money::hours((end - now).num_seconds())
+    }
+
+    // Deltas of every deployment against the previous poll, biggest increase first.
+    // Deployments new since then count from zero
+    pub fn diff(&self, previous: Option<&Snapshot>) -> Vec<DeploymentDiff> {
+        let previous = previous.filter(|previous| self.time().is_some_and(|now| previous.same_month(now)));
+
+        let mut diffs: Vec<DeploymentDiff> = self
+            .costs()
+            .into_iter()
+            .map(|costs| {
+                let before = previous.and_then(|p| p.organizations.iter().find(|o| o.organization.id == costs.org_id));
+                let old = before.and_then(|b| b.month.deployments.iter().find(|d| d.deployment_id == costs.id));
+                DeploymentDiff {
+                    since_last_poll: before.map(|_| costs.monthly_cost - old.map(|d| d.costs.total).unwrap_or_default()),
+                    hourly_rate_change: old.map(|d| costs.hourly_rate - d.hourly_rate),
+                    org_id: costs.org_id,
+                    id: costs.id,
+                    name: costs.name,
+                    since_midnight: costs.daily_cost,
+                    since_month_start: costs.monthly_cost,
+                    hourly_rate: costs.hourly_rate,
+                }
+            })
+            .collect();
+        diffs.sort_by_key(|d| std::cmp::Reverse(d.since_last_poll.unwrap_or_default()));
+        diffs
+    }
+
+    // Derive how fast the monthly cost of every deployment grew since the previous poll.
+    // Polls from another billing month are skipped, as the totals started over
+    pub fn derive_cost_rates(&mut self, previous: &Snapshot) {
+        let hours = money::hours(self.timestamp - previous.timestamp);
+        if hours <= Decimal::ZERO || !self.time().is_some_and(|now| previous.same_month(now)) {
+            return;
+        }
+
+        for organization in self.organizations.iter_mut() {
+            let before = match previous.organizations.iter().find(|o| o.organization.id == organization.organization.id) {
+                Some(before) => before,
+                None => continue,
+            };
+            for deployment in &organization.month.deployments {
+                if let Some(old) = before.month.deployments.iter().find(|d| d.deployment_id == deployment.deployment_id) {
+                    let rate = (deployment.costs.total - old.costs.total) / hours;
+                    organization.cost_rates.insert(deployment.deployment_id.clone(), rate);
+                }
+            }
+        }
+    }
+
+    pub fn costs(&self) -> Vec<DeploymentCosts> {
+        let remaining_hours = self.remaining_hours();
+        let mut costs = Vec::new();
+        for organization in &self.organizations {
+            for deployment in &organization.month.deployments {
+                let daily_cost = organization
+                    .day
+                    .deployments
+                    .iter()
+                    .find(|d| d.deployment_id == deployment.deployment_id)
+                    .map(|d| d.costs.total)
+                    .unwrap_or_default();
+
+                costs.push(DeploymentCosts {
+                    org_id: organization.organization.id.clone(),
+                    id: deployment.deployment_id.clone(),
+                    name: deployment.deployment_name.clone(),
+                    daily_cost,
+                    monthly_cost: deployment.costs.total,
+                    hourly_rate: deployment.hourly_rate,
+                    forecast: deployment.costs.total + deployment.hourly_rate * remaining_hours,
+                });
+            }
+        }
+        costs
+    }
+}
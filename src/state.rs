@@ -1,228 +1,1362 @@
-use crate::https::HttpsClient;
-use clap::ArgMatches;
+use crate::https::{create_https_client, HttpsClient};
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use futures::stream::{self, StreamExt};
+use tokio::sync::broadcast;
+use tokio::time::MissedTickBehavior;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::body::HttpBody;
 use hyper::{Body, Request, Response};
+use metrics::Label;
+use metrics_exporter_prometheus::PrometheusHandle;
 use url::Url;
-use serde::{Deserialize, Serialize};
-use chrono::{Utc, SecondsFormat};
+use chrono::{DateTime, Utc, SecondsFormat};
 use chrono::Datelike;
 use chrono::TimeZone;
+use serde_json::json;
 
-use crate::create_https_client;
-use crate::error::Error as RestError;
+use crate::audit::{AuditEntry, JsonLog};
+use crate::client;
+use crate::collector;
+use crate::config::{Aggregation, ApiVersion, ChartsBucket, Config, NameMask, Organization};
+use crate::cost_centers::CostCenters;
+use crate::metadata::Metadata;
+use crate::error::{Context, Error as RestError};
+use crate::events::Event;
+use crate::secret::K8sSecret;
+use crate::schema::{parse_charts, parse_deployments, Data, DataV2, DeploymentItems, Invoice, Invoices, OrganizationInfo, Overview};
+use crate::redact;
+use crate::redis::Redis;
+use crate::settings::{Settings, SettingsError};
+use crate::sink::Sinks;
+use crate::snapshot::{OrganizationSnapshot, Snapshot};
+use crate::topology;
+use crate::history::{HistoryEntry, HistoryPage, HistoryQuery};
+use crate::hooks::ThresholdHook;
+use crate::metrics::record_build_info;
+use crate::store::Store;
+use crate::systemd;
+use crate::webhook::{PollSummary, Webhook};
+use crate::ytd::YearToDate;
 
 type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct DataV2 {
-    total_cost: f64,
-    deployments: Vec<Deployment>
+// Which of an organization's urls is in use, and how many requests failed against it in a row
+#[derive(Clone, Debug, Default)]
+pub struct Endpoint {
+    pub active: usize,
+    pub failures: u32,
+    pub last_error: Option<ApiError>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Deployment {
-    deployment_id: String,
-    deployment_name: String,
-    costs: Cost,
-    hourly_rate: f64,
-    period: Period
+// The last failed billing api call, with our request id and elastic's trace id
+#[derive(Clone, Debug)]
+pub struct ApiError {
+    pub timestamp: i64,
+    pub endpoint: String,
+    pub reason: &'static str,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Cost {
-    total: f64,
-    dimensions: Vec<Item>
-}
+// Held for the duration of a poll, released even when the poll fails or is cancelled
+struct BusyGuard<'a>(&'a AtomicBool);
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Item {
-    r#type: String,
-    cost: f64
+impl<'a> BusyGuard<'a> {
+    fn acquire(busy: &'a AtomicBool) -> Option<Self> {
+        busy.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).ok()?;
+        Some(BusyGuard(busy))
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Period {
-    start: String,
-    end: String
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Data{
-    data: Vec<Inner>
-}
+// Sent on every billing api call, and returned by elastic as their own id for it
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const TRACE_ID_HEADER: &str = "x-cloud-request-id";
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Inner {
-    pub timestamp: u64,
-    pub values: Vec<Cluster>
-}
+// Backoff for throttled calls without a Retry-After header, doubling on every attempt
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Cluster {
-    id: String,
-    name: String,
-    value: f64
-}
+// Calls per organization in flight at once when fetching something for every deployment
+const CONCURRENT_REQUESTS: usize = 4;
+
+// Plans rarely change, so a deployment's topology is looked up again only after an hour
+const TOPOLOGY_TTL: Duration = Duration::from_secs(3600);
 
 #[derive(Clone, Debug)]
 pub struct State {
     pub client: HttpsClient,
-    pub url: Url
+    pub user_agent: HeaderValue,
+    pub headers: HeaderMap,
+    pub organizations: Vec<Organization>,
+    pub config: Config,
+    pub api_version: ApiVersion,
+    pub week_to_date: bool,
+    pub top_deployments: usize,
+    pub quarter_to_date: bool,
+    pub topology: bool,
+    pub cost_by_kind: bool,
+    pub invoices: usize,
+    pub detected_versions: Arc<Mutex<HashMap<String, ApiVersion>>>,
+    pub topology_cache: Arc<Mutex<HashMap<String, (Instant, topology::Deployment)>>>,
+    // End of the charts window last counted for gaps, so overlapping windows count a bucket once
+    pub gaps_counted: Arc<Mutex<HashMap<String, i64>>>,
+    pub failover_after: u32,
+    pub charts_aggregation: Aggregation,
+    pub charts_window: i64,
+    pub charts_bucket: Option<ChartsBucket>,
+    pub max_body_size: u64,
+    pub audit_log: Option<JsonLog>,
+    pub max_retries: u32,
+    pub retry_statuses: Vec<u16>,
+    pub strict_parsing: bool,
+    pub fail_on_empty: bool,
+    pub endpoints: Arc<Mutex<HashMap<String, Endpoint>>>,
+    pub interval: u64,
+    pub timeout: u64,
+    pub stale_intervals: u64,
+    pub last_success: Arc<AtomicI64>,
+    pub consecutive_failures: Arc<AtomicU64>,
+    pub leader: Arc<AtomicBool>,
+    pub paused: Arc<AtomicBool>,
+    pub busy: Arc<AtomicBool>,
+    pub rendered: Arc<RwLock<Option<String>>>,
+    pub snapshot: Arc<RwLock<Option<Snapshot>>>,
+    pub previous: Arc<RwLock<Option<Snapshot>>>,
+    // The costs of every new snapshot as json, for /api/v1/stream
+    pub updates: broadcast::Sender<Arc<String>>,
+    // Budget breaches and anomalies as json, for /ws/events
+    pub events: broadcast::Sender<Arc<String>>,
+    pub anomaly_ratio: f64,
+    pub stale: Arc<AtomicBool>,
+    pub redis: Option<Redis>,
+    pub redis_key: String,
+    pub store: Option<Store>,
+    pub cost_centers: Option<CostCenters>,
+    pub metadata: Option<Metadata>,
+    pub k8s_secret: Option<K8sSecret>,
+    pub webhook: Option<Webhook>,
+    pub threshold_hook: Option<ThresholdHook>,
+    pub sinks: Sinks,
+    // Set by a successful poll, so the push sinks get its series once
+    pub pending_flush: Arc<AtomicBool>,
+    pub ytd: Arc<Mutex<YearToDate>>
+}
+
+// Periods the deployments api is asked for, each running from its start in utc until now
+#[derive(Clone, Copy)]
+enum ToDate {
+    Day,
+    Week,
+    Month,
+    Quarter,
+}
+
+impl ToDate {
+    fn start(self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let day = Utc.ymd(now.year(), now.month(), now.day()).and_hms(0, 0, 0);
+        match self {
+            ToDate::Day => day,
+            ToDate::Week => day - chrono::Duration::days(now.weekday().num_days_from_monday() as i64),
+            ToDate::Month => Utc.ymd(now.year(), now.month(), 1).and_hms(0, 0, 0),
+            ToDate::Quarter => Utc.ymd(now.year(), (now.month() - 1) / 3 * 3 + 1, 1).and_hms(0, 0, 0),
+        }
+    }
 }
 
 impl State {
-    pub async fn new(opts: ArgMatches<'_>) -> BoxResult<Self> {
-        // Set timeout
-        let timeout: u64 = opts
-            .value_of("timeout")
-            .unwrap()
-            .parse()
-            .unwrap_or_else(|_| {
-                eprintln!("Supplied timeout not in range, defaulting to 60");
-                60
+    pub fn builder() -> StateBuilder {
+        StateBuilder::default()
+    }
+
+    pub async fn new(settings: &Settings) -> BoxResult<Self> {
+        let client = create_https_client(settings.timeout)?;
+
+        // Read organizations from the config file, falling back to a single url
+        let mut config = match &settings.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.stable_ids |= settings.stable_ids;
+        config.mask_names = settings.mask_names.or(config.mask_names);
+        config.mask_secret = settings.mask_secret.clone().or(config.mask_secret);
+        if config.mask_names == Some(NameMask::Hash) && config.mask_secret.as_deref().unwrap_or_default().is_empty() {
+            return Err("hashing names requires --mask-secret or mask_secret in the config file".into());
+        }
+        config.round_costs = settings.round_costs.or(config.round_costs);
+        config.rounding = settings.rounding.or(config.rounding);
+
+        let mut organizations = config.organizations.clone();
+        if let Some(url) = &settings.url {
+            organizations.push(Organization {
+                id: None,
+                name: None,
+                url: url.clone(),
+                fallback_urls: settings.fallback_urls.clone(),
+                headers: HashMap::new(),
+                header_map: HeaderMap::new(),
+                account_name: Default::default(),
             });
+        }
+
+        // The v2 endpoints are keyed by organization, so pinning v2 requires every id
+        if settings.api_version == ApiVersion::V2 && organizations.iter().any(|o| o.id.is_none()) {
+            return Err("api version v2 requires an id for every organization".into());
+        }
+
+        // Load persisted year to date totals from the data directory
+        let store = match &settings.data_dir {
+            Some(dir) => Some(Store::new(dir)?),
+            None => None,
+        };
+        let ytd = match &store {
+            Some(store) => store.load("ytd.json")?.unwrap_or_default(),
+            None => YearToDate::default(),
+        };
 
-        let client = create_https_client(timeout)?;
-        let url = opts.value_of("url").unwrap().parse().expect("Could not parse url");
+        // Serve the last snapshot saved to the data directory until the first poll succeeds,
+        // as long as it is from the current billing month
+        let now = Utc::now();
+        let cached: Option<Snapshot> = match &store {
+            Some(store) => store.load("snapshot.json").unwrap_or_else(|e| {
+                log::error!("{{\"fn\": \"new\", \"msg\": \"ignoring saved snapshot\", \"error\": \"{}\"}}", e);
+                None
+            }),
+            None => None,
+        };
+        let cached = cached.filter(|snapshot| snapshot.same_month(now));
+        if let Some(snapshot) = &cached {
+            log::info!("{{\"fn\": \"new\", \"msg\": \"loaded saved snapshot\", \"timestamp\": {}}}", snapshot.timestamp);
+        }
+
+        // Feed billing series to the sinks from the config file
+        let sinks = Sinks::from_config(&config.sinks, settings.timeout)?;
+
+        // Run the threshold command from the config file when budgets are crossed
+        let threshold_hook = config.threshold_command.clone().map(ThresholdHook::new);
+
+        // Load the deployment to cost center mapping
+        let cost_centers = match &settings.cost_centers {
+            Some(path) => Some(CostCenters::new(path)?),
+            None => None,
+        };
+
+        // Load the extra labels per deployment
+        let metadata = match &settings.metadata {
+            Some(path) => Some(Metadata::new(path)?),
+            None => None,
+        };
+
+        // Read the api key from a kubernetes secret, failing right away when it can not be read
+        let k8s_secret = match &settings.k8s_secret {
+            Some(secret) => Some(K8sSecret::new(secret).await?),
+            None => None,
+        };
+
+        // Record every billing api call for auditing
+        let audit_log = match &settings.audit_log {
+            Some(target) => Some(JsonLog::audit(target)?),
+            None => None,
+        };
+
+        // Start the clock at startup, so the first poll gets a grace period
+        let last_success = Arc::new(AtomicI64::new(Utc::now().timestamp()));
+
+        // The flag overrides the bucketing in the config file
+        let charts_bucket = settings.charts_bucket.or(config.charts_bucket);
 
         Ok(State {
             client,
-            url
+            user_agent: settings.user_agent.clone(),
+            headers: settings.headers.clone(),
+            organizations,
+            config,
+            api_version: settings.api_version,
+            week_to_date: settings.week_to_date,
+            quarter_to_date: settings.quarter_to_date,
+            topology: settings.topology,
+            cost_by_kind: settings.cost_by_kind,
+            invoices: settings.invoices,
+            top_deployments: settings.top_deployments,
+            detected_versions: Arc::new(Mutex::new(HashMap::new())),
+            topology_cache: Arc::new(Mutex::new(HashMap::new())),
+            gaps_counted: Arc::new(Mutex::new(HashMap::new())),
+            failover_after: settings.failover_after,
+            charts_aggregation: settings.charts_aggregation,
+            charts_window: settings.charts_window,
+            charts_bucket,
+            max_body_size: settings.max_body_size,
+            audit_log,
+            max_retries: settings.max_retries,
+            retry_statuses: settings.retry_statuses.clone(),
+            strict_parsing: settings.strict_parsing,
+            fail_on_empty: settings.fail_on_empty,
+            endpoints: Arc::new(Mutex::new(HashMap::new())),
+            interval: settings.interval,
+            timeout: settings.timeout,
+            stale_intervals: settings.stale_intervals,
+            last_success,
+            consecutive_failures: Arc::new(AtomicU64::new(0)),
+            leader: Arc::new(AtomicBool::new(true)),
+            paused: Arc::new(AtomicBool::new(false)),
+            busy: Arc::new(AtomicBool::new(false)),
+            rendered: Arc::new(RwLock::new(None)),
+            stale: Arc::new(AtomicBool::new(cached.is_some())),
+            snapshot: Arc::new(RwLock::new(cached)),
+            previous: Arc::new(RwLock::new(None)),
+            updates: broadcast::channel(16).0,
+            events: broadcast::channel(64).0,
+            anomaly_ratio: settings.anomaly_ratio,
+            redis: settings.redis_url.clone().map(|url| Redis::new(url, settings.timeout)).transpose()?,
+            redis_key: settings.redis_key.clone(),
+            store,
+            cost_centers,
+            metadata,
+            k8s_secret,
+            webhook: settings.poll_webhook.as_deref().map(|url| Webhook::new(url, settings.timeout)).transpose()?,
+            threshold_hook,
+            sinks,
+            pending_flush: Arc::new(AtomicBool::new(false)),
+            ytd: Arc::new(Mutex::new(ytd))
         })
     }
 
-    pub async fn get_deployments_by_day(&self) -> Result<DataV2, RestError> {
-        let now = Utc::now();
-        let start = Utc.ymd(now.year(), now.month(), now.day()).and_hms(0,0,0);
+    // Costs of every deployment from a point in time until now
+    pub async fn get_deployments_since(&self, organization: &Organization, version: ApiVersion, from: DateTime<Utc>) -> Result<DataV2, RestError> {
+        let path = format!("{}?from={}", Self::endpoint(organization, version, "deployments"), from.to_rfc3339_opts(SecondsFormat::Secs, true));
+        let bytes = self.get_bytes(organization, &path).await?;
+        decoded(&path, parse_deployments(version, &bytes, self.strict_parsing))
+    }
 
-        let path = format!("deployments?from={}", start.to_rfc3339_opts(SecondsFormat::Secs, true));
-        let body = self.get(&path).await?;
-        let bytes = hyper::body::to_bytes(body.into_body()).await?;
-        let value: DataV2 = serde_json::from_slice(&bytes)?;
+    pub async fn get_charts(&self, organization: &Organization, version: ApiVersion, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Data, RestError> {
+
+        let mut path = format!(
+            "{}?from={}&to={}",
+            Self::endpoint(organization, version, "charts"),
+            start.to_rfc3339_opts(SecondsFormat::Secs, true),
+            end.to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        if let Some(bucket) = &self.charts_bucket {
+            path.push_str(&format!("&bucketing_strategy={}", bucket));
+        }
+        let bytes = self.get_bytes(organization, &path).await?;
+        let value = decoded(&path, parse_charts(version, &bytes, self.strict_parsing))?;
         Ok(value)
     }
 
-    pub async fn get_deployments_by_month(&self) -> Result<DataV2, RestError> {
+    // Seconds covered by one charts bucket
+    pub fn charts_bucket_seconds(&self) -> i64 {
+        self.bucket().seconds()
+    }
+
+    // The api buckets hourly unless asked otherwise
+    pub fn bucket(&self) -> ChartsBucket {
+        self.charts_bucket.unwrap_or(ChartsBucket::Hourly)
+    }
+
+    // Align the charts window to bucket boundaries, so only complete buckets are requested
+    pub fn charts_range(&self, now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let bucket = self.charts_bucket_seconds();
+        let end = now.timestamp() - now.timestamp().rem_euclid(bucket);
+        let window = std::cmp::max(bucket, self.charts_window - self.charts_window % bucket);
+        (Utc.timestamp(end - window, 0), Utc.timestamp(end, 0))
+    }
+
+    // Costs overview of the organization, which the legacy api does not have
+    pub async fn get_overview(&self, organization: &Organization, version: ApiVersion) -> Result<Option<Overview>, RestError> {
+        match (version, &organization.id) {
+            (ApiVersion::V2, Some(id)) => {
+                let path = format!("billing/costs/{}", id);
+                let bytes = self.get_bytes(organization, &path).await?;
+                Ok(Some(decoded(&path, serde_json::from_slice(&bytes))?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Itemized costs of a deployment this month, only served by the organization costs api
+    pub async fn get_deployment_items(&self, organization: &Organization, version: ApiVersion, id: &str) -> Result<Option<DeploymentItems>, RestError> {
+        if version != ApiVersion::V2 || organization.id.is_none() {
+            return Ok(None);
+        }
         let now = Utc::now();
         let start = Utc.ymd(now.year(), now.month(), 1).and_hms(0,0,0);
 
-        let path = format!("deployments?from={}", start.to_rfc3339_opts(SecondsFormat::Secs, true));
-        let body = self.get(&path).await?;
-        let bytes = hyper::body::to_bytes(body.into_body()).await?;
-        let value: DataV2 = serde_json::from_slice(&bytes)?;
-        Ok(value)
+        let path = format!(
+            "{}?from={}",
+            Self::endpoint(organization, version, &format!("deployments/{}/items", id)),
+            start.to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        let bytes = self.get_bytes(organization, &path).await?;
+        Ok(Some(decoded(&path, serde_json::from_slice(&bytes))?))
     }
 
-//    pub async fn get_charts(&self) -> Result<Data, RestError> {
-//        let now = Utc::now();
-//        let day_start = Utc.ymd(now.year(), now.month(), now.day()).and_hms(0,0,0);
-//        let path = format!("charts?from={}", day_start.to_rfc3339_opts(SecondsFormat::Secs, true));
-//        let body = self.get(&path).await?;
-//        let bytes = hyper::body::to_bytes(body.into_body()).await?;
-//        let value: Data = serde_json::from_slice(&bytes)?;
-//        Ok(value)
-//    }
-
-    pub async fn get(&self, path: &str) -> Result<Response<Body>, RestError> {
-        let uri = format!("{}/{}", &self.url, path);
-        log::debug!("getting url {}", &uri);
-        let req = Request::builder()
+    // Account details of the organization, from the organizations api next to the billing api
+    pub async fn get_organization_info(&self, organization: &Organization, id: &str) -> Result<OrganizationInfo, RestError> {
+        let path = format!("organizations/{}", id);
+        let bytes = self.get_bytes(organization, &path).await?;
+        decoded(&path, serde_json::from_slice(&bytes))
+    }
+
+    // Most recent invoices of the organization, newest first
+    pub async fn get_invoices(&self, organization: &Organization, version: ApiVersion) -> Result<Vec<Invoice>, RestError> {
+        let id = match (version, &organization.id) {
+            (ApiVersion::V2, Some(id)) => id,
+            _ => return Ok(Vec::new()),
+        };
+        let path = format!("billing/invoices/{}", id);
+        let bytes = self.get_bytes(organization, &path).await?;
+        let mut invoices = decoded(&path, serde_json::from_slice::<Invoices>(&bytes))?.invoices;
+        invoices.sort_by(|a, b| b.period.end.cmp(&a.period.end));
+        invoices.truncate(self.invoices);
+        Ok(invoices)
+    }
+
+    // Region and plan of a deployment, from the deployments api next to the billing api
+    pub async fn get_topology(&self, organization: &Organization, id: &str) -> Result<topology::Deployment, RestError> {
+        let path = format!("deployments/{}", id);
+        let bytes = self.get_bytes(organization, &path).await?;
+        decoded(&path, serde_json::from_slice(&bytes))
+    }
+
+    // The topology from the last hour, or a fresh lookup
+    async fn get_cached_topology(&self, organization: &Organization, id: &str) -> Result<topology::Deployment, RestError> {
+        if let Some((fetched, details)) = self.topology_cache.lock().unwrap().get(id) {
+            if fetched.elapsed() < TOPOLOGY_TTL {
+                return Ok(details.clone());
+            }
+        }
+        let details = self.get_topology(organization, id).await?;
+        self.topology_cache.lock().unwrap().insert(id.to_string(), (Instant::now(), details.clone()));
+        Ok(details)
+    }
+
+    // Build the path to an endpoint for the api version the organization speaks
+    fn endpoint(organization: &Organization, version: ApiVersion, endpoint: &str) -> String {
+        client::endpoint(organization.id.as_deref(), version, endpoint)
+    }
+
+    // Return the pinned api version, or probe the organization costs endpoint once to detect it
+    pub async fn get_api_version(&self, organization: &Organization) -> Result<ApiVersion, RestError> {
+        if self.api_version != ApiVersion::Auto {
+            return Ok(self.api_version);
+        }
+
+        let id = match &organization.id {
+            Some(id) => id,
+            None => return Ok(ApiVersion::V1),
+        };
+
+        if let Some(version) = self.detected_versions.lock().unwrap().get(organization.url.as_str()) {
+            return Ok(*version);
+        }
+
+        let version = match self.get(organization, &format!("billing/costs/{}", id)).await {
+            Ok(_) => ApiVersion::V2,
+            Err(RestError::NotFound(_)) => ApiVersion::V1,
+            Err(e) => return Err(e),
+        };
+
+        log::info!("{{\"fn\": \"get_api_version\", \"url\": \"{}\", \"version\": \"{}\"}}", redact::url(&organization.url), version);
+        self.detected_versions.lock().unwrap().insert(organization.url.to_string(), version);
+        Ok(version)
+    }
+
+    // Returns the url currently in use for the organization
+    pub fn active_url<'a>(&self, organization: &'a Organization) -> &'a Url {
+        let urls = organization.urls();
+        let endpoints = self.endpoints.lock().unwrap();
+        let active = endpoints.get(organization.url.as_str()).map(|e| e.active).unwrap_or(0);
+        urls[active % urls.len()]
+    }
+
+    // Track consecutive failures, moving on to the next url once there are too many
+    fn record_request(&self, organization: &Organization, success: bool) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let endpoint = endpoints.entry(organization.url.to_string()).or_default();
+        if success {
+            endpoint.failures = 0;
+            return;
+        }
+
+        endpoint.failures += 1;
+        let urls = organization.urls();
+        if endpoint.failures >= self.failover_after && urls.len() > 1 {
+            endpoint.active = (endpoint.active + 1) % urls.len();
+            endpoint.failures = 0;
+            log::warn!("{{\"fn\": \"record_request\", \"msg\": \"failing over\", \"url\": \"{}\"}}", redact::url(urls[endpoint.active]));
+            metrics::increment_counter!("elastic_billing_endpoint_failovers_total", &organization.labels());
+        }
+    }
+
+    // Flag which url every organization is using
+    pub fn record_endpoints(&self) {
+        for organization in &self.organizations {
+            let active = self.active_url(organization);
+            for url in organization.urls() {
+                let mut labels = organization.labels();
+                labels.push(("url", redact::url(url)));
+                let value = if url == active { 1.0 } else { 0.0 };
+                metrics::gauge!("elastic_billing_active_endpoint", value, &labels);
+            }
+
+            let endpoints = self.endpoints.lock().unwrap();
+            if let Some(error) = endpoints.get(organization.url.as_str()).and_then(|e| e.last_error.as_ref()) {
+                let mut labels = organization.labels();
+                labels.push(("endpoint", error.endpoint.clone()));
+                labels.push(("reason", error.reason.to_string()));
+                metrics::gauge!("elastic_billing_api_last_error_timestamp_seconds", error.timestamp as f64, &labels);
+            }
+        }
+    }
+
+    // Read the response body, giving up once it grows over the size limit
+    pub async fn get_bytes(&self, organization: &Organization, path: &str) -> Result<Vec<u8>, RestError> {
+        let mut body = self.get(organization, path).await?.into_body();
+
+        if let Some(size) = body.size_hint().upper() {
+            if size > self.max_body_size {
+                return Err(self.body_too_large(path));
+            }
+        }
+
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| RestError::from(e).at(path))?;
+            if (bytes.len() + chunk.len()) as u64 > self.max_body_size {
+                return Err(self.body_too_large(path));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+
+    fn body_too_large(&self, path: &str) -> RestError {
+        log::error!("{{\"fn\": \"get_bytes\", \"path\": \"{}\", \"msg\": \"response body over {} bytes\"}}", path, self.max_body_size);
+        metrics::increment_counter!("elastic_billing_response_too_large_total");
+        RestError::BodyTooLarge(Box::new(Context::new(path)), self.max_body_size)
+    }
+
+    // Retry throttled calls and transient server errors with exponential backoff, or as long as elastic asks us to wait,
+    // up to the poll interval, or the longest backoff when polling on every scrape
+    pub async fn get(&self, organization: &Organization, path: &str) -> Result<Response<Body>, RestError> {
+        let mut attempt = 0;
+        loop {
+            let result = self.send(organization, path).await;
+            let retry_after = match &result {
+                Err(RestError::TooManyRequests(_, retry_after)) | Err(RestError::Transient(_, retry_after)) if attempt < self.max_retries => *retry_after,
+                _ => {
+                    if attempt > 0 {
+                        metrics::gauge!("elastic_billing_api_backoff_seconds", 0.0, &organization.labels());
+                    }
+                    return result;
+                }
+            };
+
+            attempt += 1;
+            let delay = match retry_after {
+                Some(seconds) => Duration::from_secs(seconds).min(match self.interval {
+                    0 => MAX_RETRY_BACKOFF,
+                    interval => Duration::from_secs(interval),
+                }),
+                None => (RETRY_BACKOFF * 2u32.pow(attempt - 1)).min(MAX_RETRY_BACKOFF),
+            };
+            let error = result.err().map(|e| e.json()).unwrap_or_default();
+            log::warn!("{{\"fn\": \"get\", \"msg\": \"retrying\", \"path\": \"{}\", \"attempt\": {}, \"delay\": {}, \"error\": {}}}", path, attempt, delay.as_secs_f64(), error);
+
+            let mut labels = organization.labels();
+            labels.push(("endpoint", path.split('?').next().unwrap_or_default().to_string()));
+            metrics::increment_counter!("elastic_billing_api_retries_total", &labels);
+            metrics::gauge!("elastic_billing_api_backoff_seconds", delay.as_secs_f64(), &organization.labels());
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn send(&self, organization: &Organization, path: &str) -> Result<Response<Body>, RestError> {
+        let url = self.active_url(organization);
+        let uri = format!("{}/{}", url.as_str().trim_end_matches('/'), path);
+        let request_id = request_id();
+        log::debug!("getting url {}, request id {}", redact::url_str(&uri), request_id);
+        let mut req = Request::builder()
             .method("GET")
             .uri(&uri)
+            .header(header::USER_AGENT, self.user_agent.clone())
+            .header(REQUEST_ID_HEADER, &request_id)
             .body(Body::empty())
             .expect("request builder");
 
+        // Organization headers override the global ones, and the key from the secret
+        req.headers_mut().extend(self.headers.clone());
+        if let Some(authorization) = self.k8s_secret.as_ref().and_then(|s| s.authorization()) {
+            req.headers_mut().insert(header::AUTHORIZATION, authorization);
+        }
+        req.headers_mut().extend(organization.header_map.clone());
+
         // Send initial request
+        let started = Instant::now();
         let response = match self.client.request(req).await {
             Ok(s) => s,
             Err(e) => {
-                log::error!("{{\"error\":\"{}\"", e);
-                return Err(RestError::Hyper(e));
+                let context = Context { request_id: Some(request_id.clone()), ..Context::new(path) };
+                let error = RestError::Hyper(Box::new(context), e);
+                log::error!("{{\"fn\": \"get\", \"path\": \"{}\", \"request_id\": \"{}\", \"error\": {}}}", path, request_id, error.json());
+                self.audit(organization, path, &request_id, None, Err(error.to_string()), started);
+                self.record_error(organization, path, "error", error.reason());
+                self.record_request(organization, false);
+                return Err(error);
             }
         };
 
-        match response.status().as_u16() {
-            404 => return Err(RestError::NotFound),
-            403 => return Err(RestError::Forbidden),
-            401 => return Err(RestError::Unauthorized),
-            200 => {
-                Ok(response)
+        // Elastic's own id for the request, which their support can look up
+        let trace_id = response
+            .headers()
+            .get(TRACE_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let status = response.status().as_u16();
+        self.audit(organization, path, &request_id, trace_id.as_deref(), Ok(status), started);
+
+        // Only server side errors count towards failing over
+        self.record_request(organization, !response.status().is_server_error());
+
+        if status == 200 {
+            return Ok(response);
+        }
+
+        let retry_after = retry_after(&response);
+        let context = Context {
+            status: Some(status),
+            request_id: Some(request_id.clone()),
+            trace_id: trace_id.clone(),
+            ..Context::new(path)
+        }
+        .excerpt(response.into_body())
+        .await;
+        let context = Box::new(context);
+        let error = match status {
+            404 => RestError::NotFound(context),
+            403 => RestError::Forbidden(context),
+            401 => RestError::Unauthorized(context),
+            429 => RestError::TooManyRequests(context, retry_after),
+            status if self.retry_statuses.contains(&status) => RestError::Transient(context, retry_after),
+            _ => RestError::UnknownCode(context),
+        };
+
+        // Not found is expected while detecting the api version
+        if status != 404 {
+            log::error!("{{\"fn\": \"get\", \"path\": \"{}\", \"error\": {}}}", path, error.json());
+            self.record_error(organization, path, &status.to_string(), error.reason());
+        }
+        Err(error)
+    }
+
+    fn audit(&self, organization: &Organization, path: &str, request_id: &str, trace_id: Option<&str>, status: Result<u16, String>, started: Instant) {
+        if let Some(audit_log) = &self.audit_log {
+            let (status, error) = match status {
+                Ok(status) => (Some(status), None),
+                Err(e) => (None, Some(e)),
+            };
+            audit_log.record(&AuditEntry {
+                timestamp: Utc::now().to_rfc3339(),
+                org_id: organization.id.as_deref(),
+                endpoint: path,
+                request_id,
+                trace_id,
+                status,
+                error,
+                latency_ms: started.elapsed().as_millis(),
+            });
+        }
+    }
+
+    // Keep the last failed call of every organization, its ids are in the error and the audit log
+    fn record_error(&self, organization: &Organization, path: &str, status: &str, reason: &'static str) {
+        let name = path.split('?').next().unwrap_or_default();
+        let mut labels = organization.labels();
+        labels.push(("endpoint", name.to_string()));
+        labels.push(("status", status.to_string()));
+        labels.push(("reason", reason.to_string()));
+        metrics::increment_counter!("elastic_billing_api_errors_total", &labels);
+
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let endpoint = endpoints.entry(organization.url.to_string()).or_default();
+        endpoint.last_error = Some(ApiError {
+            timestamp: Utc::now().timestamp(),
+            endpoint: name.to_string(),
+            reason,
+        });
+    }
+
+    // Replicas without leader election are always the leader
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Relaxed)
+    }
+
+    // Paused by an operator, polling stops while the last snapshot keeps being served
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        if self.paused.swap(paused, Ordering::Relaxed) != paused {
+            log::warn!("{{\"fn\": \"set_paused\", \"msg\": \"polling {}\"}}", if paused { "paused" } else { "resumed" });
+        }
+    }
+
+    // The options this instance is running with, after merging the flags into the config file,
+    // with urls, headers and webhooks redacted
+    pub fn effective_config(&self) -> serde_json::Value {
+        let mut config = serde_json::to_value(&self.config).unwrap_or_default();
+        config["organizations"] = serde_json::to_value(&self.organizations).unwrap_or_default();
+        config["charts_bucket"] = serde_json::to_value(self.bucket()).unwrap_or_default();
+
+        let headers: Vec<&str> = self.headers.keys().map(|name| name.as_str()).collect();
+        json!({
+            "config": config,
+            "interval": self.interval,
+            "stale_intervals": self.stale_intervals,
+            "api_version": self.api_version,
+            "charts_aggregation": self.charts_aggregation,
+            "charts_window": self.charts_window,
+            "top_deployments": self.top_deployments,
+            "week_to_date": self.week_to_date,
+            "quarter_to_date": self.quarter_to_date,
+            "topology": self.topology,
+            "cost_by_kind": self.cost_by_kind,
+            "invoices": self.invoices,
+            "failover_after": self.failover_after,
+            "max_body_size": self.max_body_size,
+            "max_retries": self.max_retries,
+            "retry_statuses": self.retry_statuses,
+            "strict_parsing": self.strict_parsing,
+            "fail_on_empty": self.fail_on_empty,
+            "anomaly_ratio": self.anomaly_ratio,
+            "headers": headers,
+            "data_dir": self.store.is_some(),
+            "audit_log": self.audit_log.is_some(),
+            "cost_centers": self.cost_centers.is_some(),
+            "metadata": self.metadata.is_some(),
+            "k8s_secret": self.k8s_secret.is_some(),
+            "poll_webhook": self.webhook.as_ref().map(|webhook| webhook.redacted_url()),
+            "redis": self.redis.as_ref().map(|redis| redis.redacted_url()),
+            "redis_key": self.redis_key,
+        })
+    }
+
+    // Returns false when the background poller has not succeeded within the allowed number of intervals
+    pub fn is_healthy(&self) -> bool {
+        if self.interval == 0 || !self.is_leader() || self.is_paused() {
+            return true;
+        }
+
+        let elapsed = Utc::now().timestamp() - self.last_success.load(Ordering::Relaxed);
+        elapsed <= (self.interval * self.stale_intervals) as i64
+    }
+
+    // Poll the billing api every interval, rendering the metrics once per poll for scrapers
+    pub async fn poll(&self, recorder_handle: PrometheusHandle) {
+        // Ticks missed while a slow poll ran are skipped rather than fired back to back
+        let period = Duration::from_secs(self.interval);
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            let started = interval.tick().await;
+            if !self.is_leader() {
+                log::debug!("{{\"fn\": \"poll\", \"msg\": \"skipping poll, not the leader\"}}");
+                self.load_shared_snapshot().await;
+            } else if self.is_paused() {
+                log::debug!("{{\"fn\": \"poll\", \"msg\": \"skipping poll, polling is paused\"}}");
+            } else if let Err(e) = self.get_metrics().await {
+                log::error!("{{\"fn\": \"poll\", \"error\": {}}}", e.json());
             }
-            _ => {
-                log::error!(
-                    "Got bad status code getting config: {}",
-                    response.status().as_u16()
-                );
-                return Err(RestError::UnknownCode)
+
+            let skipped = started.elapsed().as_secs() / self.interval;
+            if skipped > 0 {
+                log::warn!("{{\"fn\": \"poll\", \"msg\": \"poll took longer than the interval, skipping {} polls\"}}", skipped);
+                metrics::counter!("elastic_billing_polls_skipped_total", skipped);
             }
+
+            self.publish(&recorder_handle);
         }
     }
 
+    // Poll once within the deadline before serving, or load the leader's snapshot on a replica,
+    // returning whether the metrics were rendered. Serving starts either way
+    pub async fn warm_up(&self, deadline: Duration, recorder_handle: &PrometheusHandle) -> bool {
+        let poll = async {
+            match self.is_leader() {
+                true => self.get_metrics().await.map(|()| true),
+                false => Ok(self.load_shared_snapshot().await),
+            }
+        };
+        match tokio::time::timeout(deadline, poll).await {
+            Ok(Ok(true)) => {
+                log::info!("{{\"fn\": \"warm_up\", \"msg\": \"polled before serving\"}}");
+                self.publish(recorder_handle);
+                true
+            }
+            Ok(Ok(false)) => {
+                log::warn!("{{\"fn\": \"warm_up\", \"msg\": \"no shared snapshot to load, serving anyway\"}}");
+                false
+            }
+            Ok(Err(e)) => {
+                log::error!("{{\"fn\": \"warm_up\", \"msg\": \"warm up poll failed, serving anyway\", \"error\": {}}}", e.json());
+                false
+            }
+            Err(_) => {
+                log::warn!("{{\"fn\": \"warm_up\", \"msg\": \"warm up poll timed out after {}s, serving anyway\"}}", deadline.as_secs());
+                false
+            }
+        }
+    }
+
+    // Render the metrics of the current snapshot for scrapers
+    pub fn publish(&self, recorder_handle: &PrometheusHandle) {
+        self.record_metrics();
+        record_build_info();
+        *self.rendered.write().unwrap() = Some(recorder_handle.render());
+    }
+
+    // Make an authenticated request against every organization, to catch bad urls and credentials
+    pub async fn check(&self) -> Result<(), RestError> {
+        for organization in &self.organizations {
+            let version = self.get_api_version(organization).await?;
+            let deployments = self.get_deployments_since(organization, version, ToDate::Month.start(Utc::now())).await?;
+            self.require_deployments(organization, &deployments)?;
+        }
+        Ok(())
+    }
+
+    // With fail_on_empty, a month without deployments at startup means a wrong organization id or api key.
+    // Polls do not check it, since the month list can be empty in the first hours of a month
+    fn require_deployments(&self, organization: &Organization, deployments: &DataV2) -> Result<(), RestError> {
+        match self.fail_on_empty && deployments.deployments.is_empty() {
+            true => Err(RestError::Empty(organization.id.clone())),
+            false => Ok(()),
+        }
+    }
+
+    // Fetch every organization into a new snapshot
+    pub async fn get_snapshot(&self) -> Result<Snapshot, RestError> {
+        let mut organizations = Vec::new();
+        for organization in &self.organizations {
+            organizations.push(self.get_organization_snapshot(organization).await?);
+        }
+
+        Ok(Snapshot {
+            timestamp: Utc::now().timestamp(),
+            organizations,
+        })
+    }
+
+    // Poll a new snapshot, unless another poll is still running
     pub async fn get_metrics(&self) -> Result<(), RestError> {
-        let deployments_day = self.get_deployments_by_day().await?;
+        let _guard = match BusyGuard::acquire(&self.busy) {
+            Some(guard) => guard,
+            None => {
+                log::warn!("{{\"fn\": \"get_metrics\", \"msg\": \"skipping poll, another one is still running\"}}");
+                metrics::increment_counter!("elastic_billing_polls_skipped_total");
+                return Err(RestError::Busy);
+            }
+        };
+        self.update_snapshot().await
+    }
+
+    // Only replace the current snapshot when every organization succeeded
+    async fn update_snapshot(&self) -> Result<(), RestError> {
+        let mut snapshot = match self.get_snapshot().await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if let Some(webhook) = &self.webhook {
+                    webhook.send(PollSummary::failure(e.to_string(), failures));
+                }
+                return Err(e);
+            }
+        };
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        if let Some(previous) = self.snapshot.read().unwrap().as_ref() {
+            snapshot.derive_cost_rates(previous);
+            collector::record_lifecycle(&self.sinks, previous, &snapshot);
+        }
+        let now = Utc.timestamp(snapshot.timestamp, 0);
+
+        // Accumulate year to date totals, persisting them when we have a data directory
+        let ytd = {
+            let mut ytd = self.ytd.lock().unwrap();
+            ytd.update(&snapshot, now.year(), now.month());
+            ytd.clone()
+        };
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save("ytd.json", &ytd) {
+                log::error!("{{\"fn\": \"get_metrics\", \"error\": \"{}\"}}", e);
+            }
+        }
+
+        // Keep every poll in the history file
+        if let Some(store) = &self.store {
+            if let Err(e) = store.append("history.jsonl", &HistoryEntry::from_snapshot(&snapshot)) {
+                log::error!("{{\"fn\": \"get_metrics\", \"error\": \"{}\"}}", e);
+            }
+        }
+
+        // Save the snapshot, to be served right away after a restart
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save("snapshot.json", &snapshot) {
+                log::error!("{{\"fn\": \"get_metrics\", \"error\": \"{}\"}}", e);
+            }
+        }
+
+        if let Some(hook) = &self.threshold_hook {
+            hook.check(&snapshot, &self.config);
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.send(PollSummary::success(&snapshot, self.snapshot.read().unwrap().as_ref()));
+        }
+
+        // Share the snapshot with the other replicas
+        if let Some(redis) = &self.redis {
+            let result = match serde_json::to_vec(&snapshot) {
+                Ok(bytes) => redis.set(&self.redis_key, &bytes).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                log::error!("{{\"fn\": \"get_metrics\", \"msg\": \"failed sharing snapshot\", \"error\": \"{}\"}}", e);
+                metrics::increment_counter!("elastic_billing_redis_errors_total");
+            }
+        }
+
+        self.replace_snapshot(snapshot);
+        self.pending_flush.store(true, Ordering::Relaxed);
+        self.last_success.store(now.timestamp(), Ordering::Relaxed);
+        self.stale.store(false, Ordering::Relaxed);
+        systemd::notify_ready();
+        Ok(())
+    }
+
+    fn replace_snapshot(&self, snapshot: Snapshot) {
+        let now = Utc.timestamp(snapshot.timestamp, 0);
+        let events = Event::detect(&snapshot, self.snapshot.read().unwrap().as_ref(), &self.config, self.anomaly_ratio);
+        let previous = self.snapshot.write().unwrap().replace(snapshot);
+
+        // Broadcast once the snapshot is in place, so a stream subscribing in between reads the new one
+        if self.updates.receiver_count() > 0 {
+            if let Some(snapshot) = self.snapshot.read().unwrap().as_ref() {
+                let costs = json!({ "timestamp": snapshot.timestamp, "deployments": snapshot.costs() });
+                let _ = self.updates.send(Arc::new(costs.to_string()));
+            }
+        }
+        for event in events {
+            event.publish(self);
+        }
+
+        // Clear out last month when the billing month changed since the previous poll
+        if let Some(previous) = &previous {
+            let previous_month = Utc.timestamp(previous.timestamp, 0);
+            if (previous_month.year(), previous_month.month()) != (now.year(), now.month()) {
+                log::info!("{{\"fn\": \"get_metrics\", \"msg\": \"billing month rolled over\"}}");
+                metrics::increment_counter!("elastic_billing_month_rollovers_total");
+                collector::reset_monthly(&self.sinks, previous, &self.config, self.cost_centers.as_ref(), self.metadata.as_ref());
+            }
+        }
+
+        // Kept for the poll over poll diff
+        *self.previous.write().unwrap() = previous;
+    }
+
+    // Replicas that are not the leader serve the leader's latest snapshot from redis,
+    // returning whether a newer snapshot was installed
+    pub async fn load_shared_snapshot(&self) -> bool {
+        let redis = match &self.redis {
+            Some(redis) => redis,
+            None => return false,
+        };
+
+        let snapshot: Snapshot = match redis.get(&self.redis_key).await {
+            Ok(Some(bytes)) => match serde_json::from_slice(&bytes) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    log::error!("{{\"fn\": \"load_shared_snapshot\", \"error\": \"{}\"}}", e);
+                    metrics::increment_counter!("elastic_billing_redis_errors_total");
+                    return false;
+                }
+            },
+            Ok(None) => return false,
+            Err(e) => {
+                log::error!("{{\"fn\": \"load_shared_snapshot\", \"error\": \"{}\"}}", e);
+                metrics::increment_counter!("elastic_billing_redis_errors_total");
+                return false;
+            }
+        };
+        if snapshot.time().is_none() {
+            log::error!("{{\"fn\": \"load_shared_snapshot\", \"msg\": \"ignoring shared snapshot with an invalid timestamp\", \"timestamp\": {}}}", snapshot.timestamp);
+            return false;
+        }
+
+        let current = self.snapshot.read().unwrap().as_ref().map(|s| s.timestamp);
+        if current.is_none_or(|timestamp| snapshot.timestamp > timestamp) {
+            log::debug!("{{\"fn\": \"load_shared_snapshot\", \"timestamp\": {}}}", snapshot.timestamp);
+            self.replace_snapshot(snapshot);
+            self.stale.store(false, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    // Read history entries matching the query, None when there is no data directory
+    pub async fn get_history(&self, query: &HistoryQuery) -> Result<Option<HistoryPage>, RestError> {
+        match &self.store {
+            Some(store) => {
+                let filter = query.clone();
+                store
+                    .read_lines("history.jsonl", move |entry: &HistoryEntry| entry.matches(&filter))
+                    .await
+                    .map(|entries| Some(HistoryPage::new(entries, query)))
+                    .map_err(RestError::Store)
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Record the current snapshot as gauges, sending them to the push sinks only after a new poll,
+    // so scrapes and replicas loading the shared snapshot never send the same series twice
+    pub fn record_metrics(&self) {
+        let push = self.pending_flush.swap(false, Ordering::Relaxed);
+        let pull = self.sinks.pull();
+        let sinks = if push { &self.sinks } else { &pull };
+        if let Some(snapshot) = self.snapshot.read().unwrap().as_ref() {
+            collector::record(
+                sinks,
+                snapshot,
+                &self.config,
+                self.charts_aggregation,
+                self.bucket(),
+                self.cost_centers.as_ref(),
+                self.metadata.as_ref(),
+            );
+            let previous = self.previous.read().unwrap();
+            collector::record_top(sinks, snapshot, previous.as_ref(), &self.config, self.top_deployments);
+        }
+        self.ytd.lock().unwrap().record(sinks, &self.config);
+        if push {
+            self.sinks.flush();
+        }
+        self.record_endpoints();
+        metrics::gauge!("elastic_billing_polling_paused", if self.is_paused() { 1.0 } else { 0.0 });
+        metrics::gauge!("elastic_billing_snapshot_stale", if self.stale.load(Ordering::Relaxed) { 1.0 } else { 0.0 });
+    }
+
+    pub async fn get_organization_snapshot(&self, organization: &Organization) -> Result<OrganizationSnapshot, RestError> {
+        let version = self.get_api_version(organization).await?;
+
+        let deployments_day = self.get_deployments_since(organization, version, ToDate::Day.start(Utc::now())).await?;
         log::debug!("day deployments: {:?}", deployments_day);
-        let deployments_month = self.get_deployments_by_month().await?;
+        let deployments_month = self.get_deployments_since(organization, version, ToDate::Month.start(Utc::now())).await?;
         log::debug!("monthly deployments: {:?}", deployments_month);
+        let (start, end) = self.charts_range(Utc::now());
+        let charts = self.get_charts(organization, version, start, end).await?;
+        log::debug!("charts: {:?}", charts);
 
-//        let charts = self.get_charts().await?;
-//        log::debug!("charts: {:?}", charts);
-//
-//        // Get hourly data
-//        for cluster in &charts.data[0].values {
-//            let labels = [
-//                ("id", cluster.id.clone()),
-//                ("name", cluster.name.clone()),
-//            ];
-//            log::debug!("Adding metric: elastic_billing_daily_cost_total, labels: {:?}, value: {}", &labels, cluster.value.clone());
-//            metrics::gauge!("elastic_billing_daily_cost_total", cluster.value.clone(), &labels);
-//        }
-
-        // Get daily data
-        for deployment in &deployments_day.deployments {
-            let labels = [
-                ("id", deployment.deployment_id.clone()),
-                ("name", deployment.deployment_name.clone()),
-            ];
-            log::debug!("Adding metric: elastic_billing_daily_cost_total, labels: {:?}, value: {}", &labels, deployment.costs.total.clone());
-            metrics::gauge!("elastic_billing_daily_cost_total", deployment.costs.total.clone(), &labels);
-
-            log::debug!("Adding metric: elastic_billing_hourly_rate, labels: {:?}, value: {}", &labels, deployment.hourly_rate.clone());
-            metrics::gauge!("elastic_billing_hourly_rate", deployment.hourly_rate.clone(), &labels);
-
-            for item in &deployment.costs.dimensions {
-                let labels = [
-                    ("id", deployment.deployment_id.clone()),
-                    ("name", deployment.deployment_name.clone()),
-                    ("item", item.r#type.clone()),
-                ];
-                log::debug!("Adding metric: elastic_billing_itemized_daily_cost_total, labels: {:?}, value: {}", &labels, item.cost.clone());
-                metrics::gauge!("elastic_billing_itemized_daily_cost_total", item.cost.clone(), &labels);
-            }
-        }
-
-        // Get monthly data
-        for deployment in &deployments_month.deployments {
-            let labels = [
-                ("id", deployment.deployment_id.clone()),
-                ("name", deployment.deployment_name.clone()),
-            ];
-            log::debug!("Adding metric: elastic_billing_monthly_cost_total, labels: {:?}, value: {}", &labels, deployment.costs.total.clone());
-            metrics::gauge!("elastic_billing_monthly_cost_total", deployment.costs.total.clone(), &labels);
-
-            log::debug!("Adding metric: elastic_billing_monthly_hourly_rate, labels: {:?}, value: {}", &labels, deployment.hourly_rate.clone());
-            metrics::gauge!("elastic_billing_monthly_hourly_rate", deployment.hourly_rate.clone(), &labels);
-
-            for item in &deployment.costs.dimensions {
-                let labels = [
-                    ("id", deployment.deployment_id.clone()),
-                    ("name", deployment.deployment_name.clone()),
-                    ("item", item.r#type.clone()),
-                ];
-                log::debug!("Adding metric: elastic_billing_itemized_monthly_cost_total, labels: {:?}, value: {}", &labels, item.cost.clone());
-                metrics::gauge!("elastic_billing_itemized_monthly_cost_total", item.cost.clone(), &labels);
+        let mut cost_by_kind = BTreeMap::new();
+        if self.cost_by_kind {
+            let results: Vec<_> = stream::iter(deployment_ids(&deployments_month))
+                .map(|id| async move {
+                    let result = self.get_deployment_items(organization, version, &id).await;
+                    (id, result)
+                })
+                .buffer_unordered(CONCURRENT_REQUESTS)
+                .collect()
+                .await;
+            for (id, result) in results {
+                match result {
+                    Ok(Some(items)) => {
+                        cost_by_kind.insert(id, items.cost_by_kind());
+                    }
+                    Ok(None) => (),
+                    Err(e) => log::warn!("{{\"fn\": \"get_organization_snapshot\", \"msg\": \"failed getting items\", \"id\": \"{}\", \"error\": {}}}", id, e.json()),
+                }
             }
+        }
+
+        let overview = self.get_overview(organization, version).await?;
+        log::debug!("overview: {:?}", overview);
+
+        let invoices = match self.invoices {
+            0 => Vec::new(),
+            _ => self.get_invoices(organization, version).await?,
+        };
+
+        // Organization details are only metadata, so they do not fail the poll
+        let info = match &organization.id {
+            Some(id) => match self.get_organization_info(organization, id).await {
+                Ok(info) => {
+                    *organization.account_name.write().unwrap() = info.name.clone();
+                    Some(info)
+                }
+                Err(e) => {
+                    log::warn!("{{\"fn\": \"get_organization_snapshot\", \"msg\": \"failed getting organization\", \"error\": {}}}", e.json());
+                    None
+                }
+            },
+            None => None,
+        };
+
+        // Week and quarter to date are optional, since each costs another api call
+        let week = match self.week_to_date {
+            true => Some(self.get_deployments_since(organization, version, ToDate::Week.start(Utc::now())).await?),
+            false => None,
+        };
+        let quarter = match self.quarter_to_date {
+            true => Some(self.get_deployments_since(organization, version, ToDate::Quarter.start(Utc::now())).await?),
+            false => None,
+        };
 
+        // Deployments that cannot be looked up, such as ones deleted this month, are left out
+        let (mut topology, mut locations) = (BTreeMap::new(), BTreeMap::new());
+        if self.topology {
+            let results: Vec<_> = stream::iter(deployment_ids(&deployments_month))
+                .map(|id| async move {
+                    let result = self.get_cached_topology(organization, &id).await;
+                    (id, result)
+                })
+                .buffer_unordered(CONCURRENT_REQUESTS)
+                .collect()
+                .await;
+            for (id, result) in results {
+                match result {
+                    Ok(details) => {
+                        if let Some(location) = details.location() {
+                            locations.insert(id.clone(), location);
+                        }
+                        topology.insert(id, details.tiers());
+                    }
+                    Err(e) => log::warn!("{{\"fn\": \"get_organization_snapshot\", \"msg\": \"failed getting topology\", \"id\": \"{}\", \"error\": {}}}", id, e.json()),
+                }
+            }
+
+            // Forget deployments that dropped out of the month
+            let ids: HashSet<String> = deployment_ids(&deployments_month).collect();
+            self.topology_cache.lock().unwrap().retain(|id, _| ids.contains(id));
         }
-        Ok(())
+
+        // The charts api returns no buckets right after the top of the hour
+        if charts.data.is_empty() {
+            log::info!("{{\"fn\": \"get_organization_snapshot\", \"msg\": \"charts api returned no data\"}}");
+            let labels: Vec<Label> = organization.labels().iter().map(Label::from).collect();
+            self.sinks.counter("elastic_billing_charts_empty_total", 1, &labels);
+        }
+
+        // Compare the buckets in the requested window against the ones that came back
+        let missing = charts.missing_buckets(start.timestamp(), end.timestamp(), self.charts_bucket_seconds());
+        let charts_missing = missing.len() as u64;
+        if charts_missing > 0 {
+            log::info!("{{\"fn\": \"get_organization_snapshot\", \"msg\": \"charts api is missing {} buckets\"}}", charts_missing);
+        }
+        let counted = self.gaps_counted.lock().unwrap().insert(organization.url.to_string(), end.timestamp()).unwrap_or(i64::MIN);
+        let gaps = missing.iter().filter(|timestamp| **timestamp >= counted).count() as u64;
+        if gaps > 0 {
+            let labels: Vec<Label> = organization.labels().iter().map(Label::from).collect();
+            self.sinks.counter("elastic_billing_chart_gaps_total", gaps, &labels);
+        }
+
+        Ok(OrganizationSnapshot {
+            organization: organization.clone(),
+            day: deployments_day,
+            month: deployments_month,
+            week,
+            quarter,
+            charts,
+            charts_missing,
+            cost_rates: BTreeMap::new(),
+            topology,
+            locations,
+            cost_by_kind,
+            overview,
+            invoices,
+            info,
+        })
+    }
+}
+
+// Builds a State from options set in code instead of the command line, starting from the
+// command line defaults. Invalid options are reported together by build, like bad flags
+#[derive(Debug, Default)]
+pub struct StateBuilder {
+    settings: Settings,
+    errors: Vec<String>,
+}
+
+impl StateBuilder {
+    pub fn url(mut self, url: &str) -> Self {
+        match url.parse() {
+            Ok(url) => self.settings.url = Some(url),
+            Err(e) => self.errors.push(format!("url \"{}\": {}", url, e)),
+        }
+        self
+    }
+
+    pub fn timeout(mut self, seconds: u64) -> Self {
+        self.settings.timeout = seconds;
+        self
+    }
+
+    pub fn api_key(self, api_key: &str) -> Self {
+        self.header(header::AUTHORIZATION.as_str(), &format!("ApiKey {}", api_key))
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        match (HeaderName::from_str(name), HeaderValue::from_str(value)) {
+            (Ok(name), Ok(mut value)) => {
+                value.set_sensitive(true);
+                self.settings.headers.insert(name, value);
+            }
+            _ => self.errors.push(format!("header \"{}\": not a valid header", name)),
+        }
+        self
+    }
+
+    pub fn api_version(mut self, version: ApiVersion) -> Self {
+        self.settings.api_version = version;
+        self
+    }
+
+    pub fn config(mut self, path: &str) -> Self {
+        self.settings.config = Some(path.to_string());
+        self
+    }
+
+    pub fn data_dir(mut self, path: &str) -> Self {
+        self.settings.data_dir = Some(path.to_string());
+        self
+    }
+
+    pub fn interval(mut self, seconds: u64) -> Self {
+        self.settings.interval = seconds;
+        self
+    }
+
+    // Start from parsed command line options instead of the defaults
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    // A url, or a config file listing organizations, is needed to have anything to poll
+    pub async fn build(mut self) -> BoxResult<State> {
+        if self.settings.url.is_none() && self.settings.config.is_none() {
+            self.errors.push("url: required without a config file".to_string());
+        }
+        if !self.errors.is_empty() {
+            return Err(SettingsError(self.errors).into());
+        }
+        State::new(&self.settings).await
+    }
+}
+
+// Name the endpoint in errors from decoding its response
+fn decoded<T, E: Into<RestError>>(path: &str, result: Result<T, E>) -> Result<T, RestError> {
+    result.map_err(|e| e.into().at(path))
+}
+
+// Ids as owned strings, since futures borrowing into the deployments are not Send enough for spawned polls
+fn deployment_ids(deployments: &DataV2) -> impl Iterator<Item = String> + '_ {
+    deployments.deployments.iter().map(|d| d.deployment_id.clone())
+}
+
+// Seconds to wait from a Retry-After header, http dates are not used by the billing api
+fn retry_after(response: &Response<Body>) -> Option<u64> {
+    response.headers().get(header::RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()
+}
+
+// Random version 4 uuid, falling back to the clock if the os has no randomness to offer
+fn request_id() -> String {
+    let mut bytes = [0u8; 16];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        bytes = (Utc::now().timestamp_nanos() as u128).to_be_bytes();
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use rust_decimal::Decimal;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    // Answers the deployments and charts calls of a poll the way the v1 billing api does
+    async fn billing_api(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let body = match req.uri().path() {
+            "/deployments" => {
+                r#"{"total_cost": 12.5, "deployments": [{"deployment_id": "abc", "deployment_name": "prod",
+                "costs": {"total": 12.5, "dimensions": [{"type": "capacity", "cost": 12.5}]},
+                "hourly_rate": 0.5, "period": {"start": "2026-10-01T00:00:00Z", "end": "2026-10-15T00:00:00Z"}}]}"#
+            }
+            "/charts" => r#"{"data": [{"timestamp": 1760000000, "values": [{"id": "abc", "name": "prod", "value": 0.5}]}]}"#,
+            _ => return Ok(Response::builder().status(404).body(Body::empty()).unwrap()),
+        };
+        Ok(Response::new(Body::from(body)))
+    }
+
+    fn serve() -> SocketAddr {
+        let server = hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(billing_api)) }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn get_metrics_replaces_the_snapshot() {
+        let addr = serve();
+        let state = State::builder()
+            .url(&format!("http://{}", addr))
+            .api_key("key")
+            .api_version(ApiVersion::V1)
+            .build()
+            .await
+            .unwrap();
+        state.get_metrics().await.unwrap();
+
+        let snapshot = state.snapshot.read().unwrap();
+        let organization = &snapshot.as_ref().unwrap().organizations[0];
+        assert_eq!(organization.month.total_cost, Decimal::new(125, 1));
+        assert_eq!(organization.month.deployments[0].deployment_id, "abc");
+        assert_eq!(organization.charts.data.len(), 1);
+        assert!(state.last_success.load(Ordering::Relaxed) > 0);
+    }
+
+    #[tokio::test]
+    async fn get_metrics_keeps_the_snapshot_when_the_api_fails() {
+        let addr = serve();
+        let state = State::builder()
+            .url(&format!("http://{}/missing", addr))
+            .api_version(ApiVersion::V1)
+            .build()
+            .await
+            .unwrap();
+
+        assert!(state.get_metrics().await.is_err());
+        assert!(state.snapshot.read().unwrap().is_none());
+        assert_eq!(state.consecutive_failures.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn build_requires_a_url_or_config() {
+        let error = State::builder().build().await.unwrap_err();
+        assert!(error.to_string().contains("url"), "{}", error);
     }
 }
@@ -0,0 +1,121 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Json files kept in the data directory, so state survives exporter restarts
+#[derive(Clone, Debug)]
+pub struct Store {
+    dir: PathBuf,
+    // Held by appends and the swap at the end of a prune, so no appended line is lost
+    lock: Arc<Mutex<()>>,
+}
+
+impl Store {
+    pub fn new(dir: &str) -> BoxResult<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Store { dir: PathBuf::from(dir), lock: Arc::new(Mutex::new(())) })
+    }
+
+    pub fn load<T: DeserializeOwned>(&self, name: &str) -> BoxResult<Option<T>> {
+        let path = self.dir.join(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bytes = fs::read(path)?;
+        Ok(Some(serde_json::from_slice(&bytes)?))
+    }
+
+    // Write to a temporary file first, so a crash never leaves a half written file behind
+    pub fn save<T: Serialize>(&self, name: &str, value: &T) -> BoxResult<()> {
+        let path = self.dir.join(name);
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        fs::write(&tmp, serde_json::to_vec(value)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    // Append values as json lines
+    pub fn append<T: Serialize>(&self, name: &str, values: &[T]) -> BoxResult<()> {
+        let mut buffer = Vec::new();
+        for value in values {
+            serde_json::to_writer(&mut buffer, value)?;
+            buffer.push(b'\n');
+        }
+
+        let _lock = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(name))?;
+        file.write_all(&buffer)?;
+        Ok(())
+    }
+
+    // Read json lines matching the filter on a blocking thread, since the file grows with every poll
+    pub async fn read_lines<T, F>(&self, name: &str, filter: F) -> BoxResult<Vec<T>>
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(&T) -> bool + Send + 'static,
+    {
+        let (store, name) = (self.clone(), name.to_string());
+        tokio::task::spawn_blocking(move || store.read_lines_blocking(&name, filter)).await?
+    }
+
+    // Read json lines matching the filter, skipping lines that fail to parse
+    fn read_lines_blocking<T: DeserializeOwned>(&self, name: &str, filter: impl Fn(&T) -> bool) -> BoxResult<Vec<T>> {
+        let path = self.dir.join(name);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let reader = BufReader::new(fs::File::open(path)?);
+        let mut values = Vec::new();
+        for line in reader.lines() {
+            match serde_json::from_str(&line?) {
+                Ok(value) if filter(&value) => values.push(value),
+                Ok(_) => (),
+                Err(e) => log::debug!("{{\"fn\": \"read_lines\", \"error\": \"{}\"}}", e),
+            }
+        }
+        Ok(values)
+    }
+
+    // Drop the json lines not matching keep, and those failing to parse, returning how many were dropped.
+    // Lines appended while the file is rewritten are carried over before the swap
+    pub fn prune<T: DeserializeOwned>(&self, name: &str, keep: impl Fn(&T) -> bool) -> BoxResult<usize> {
+        let path = self.dir.join(name);
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let mut file = fs::File::open(&path)?;
+        // Taken under the lock so the copy never ends halfway through an appended line
+        let length = {
+            let _lock = self.lock.lock().unwrap();
+            file.metadata()?.len()
+        };
+        let mut output = fs::File::create(&tmp)?;
+        let mut dropped = 0;
+        for line in BufReader::new((&mut file).take(length)).lines() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(value) if keep(&value) => writeln!(output, "{}", line)?,
+                _ => dropped += 1,
+            }
+        }
+
+        let _lock = self.lock.lock().unwrap();
+        file.seek(SeekFrom::Start(length))?;
+        std::io::copy(&mut file, &mut output)?;
+        output.sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(dropped)
+    }
+}
@@ -0,0 +1,62 @@
+use std::env;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::Once;
+use std::time::Duration;
+
+static READY: Once = Once::new();
+
+// Send a state string to the systemd notify socket, doing nothing when not run under systemd
+#[cfg(unix)]
+fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::error!("{{\"fn\": \"notify\", \"error\": \"{}\"}}", e);
+            return;
+        }
+    };
+
+    let result = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    };
+
+    if let Err(e) = result {
+        log::error!("{{\"fn\": \"notify\", \"error\": \"{}\"}}", e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
+
+// Tell systemd we are up, only the first call is sent
+pub fn notify_ready() {
+    READY.call_once(|| {
+        log::info!("{{\"fn\": \"notify_ready\", \"msg\": \"notifying systemd\"}}");
+        notify("READY=1");
+    });
+}
+
+pub fn notify_watchdog() {
+    notify("WATCHDOG=1");
+}
+
+// Returns how often the watchdog should be pet, which is half of the configured WatchdogSec
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
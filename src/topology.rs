@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+
+// Capacity of one elasticsearch tier of a deployment, summed over its zones
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tier {
+    pub tier: String,
+    pub ram_gb: f64,
+    pub storage_gb: f64,
+}
+
+// Where a deployment runs, from the region of its elasticsearch resource
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Location {
+    pub region: String,
+    pub provider: String,
+}
+
+// The parts of a deployment from the deployments api describing its region and current plan
+#[derive(Deserialize, Debug, Clone)]
+pub struct Deployment {
+    resources: Resources,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Resources {
+    #[serde(default)]
+    elasticsearch: Vec<Resource>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Resource {
+    region: Option<String>,
+    info: Info,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Info {
+    plan_info: PlanInfo,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct PlanInfo {
+    current: Option<CurrentPlan>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct CurrentPlan {
+    plan: Plan,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Plan {
+    #[serde(default)]
+    cluster_topology: Vec<Topology>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Topology {
+    id: String,
+    #[serde(default = "default_zone_count")]
+    zone_count: u32,
+    size: Option<Size>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Size {
+    value: f64,
+    resource: String,
+}
+
+fn default_zone_count() -> u32 {
+    1
+}
+
+impl Deployment {
+    // Regions are prefixed with their cloud provider, as in gcp-us-central1,
+    // except for the original aws regions such as us-east-1
+    pub fn location(&self) -> Option<Location> {
+        let region = self.resources.elasticsearch.iter().find_map(|r| r.region.clone())?;
+        let provider = match region.split_once('-') {
+            Some((provider, _)) if ["aws", "azure", "gcp"].contains(&provider) => provider,
+            _ => "aws",
+        };
+        Some(Location { provider: provider.to_string(), region })
+    }
+
+    // Tiers with any capacity, sizes are reported in megabytes per zone
+    pub fn tiers(&self) -> Vec<Tier> {
+        let mut tiers: Vec<Tier> = Vec::new();
+        for resource in &self.resources.elasticsearch {
+            let plan = match &resource.info.plan_info.current {
+                Some(current) => &current.plan,
+                None => continue,
+            };
+            for topology in &plan.cluster_topology {
+                let size = match &topology.size {
+                    Some(size) if size.value > 0.0 => size,
+                    _ => continue,
+                };
+                let gb = size.value * topology.zone_count as f64 / 1024.0;
+
+                let tier = match tiers.iter_mut().find(|t| t.tier == topology.id) {
+                    Some(tier) => tier,
+                    None => {
+                        tiers.push(Tier { tier: topology.id.clone(), ram_gb: 0.0, storage_gb: 0.0 });
+                        tiers.last_mut().unwrap()
+                    }
+                };
+                match size.resource.as_str() {
+                    "storage" => tier.storage_gb += gb,
+                    _ => tier.ram_gb += gb,
+                }
+            }
+        }
+        tiers
+    }
+}
@@ -0,0 +1,123 @@
+use chrono::{Datelike, TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use crate::redact;
+use crate::snapshot::Snapshot;
+
+// Summary of a single poll, posted to the webhook after every poll
+#[derive(Serialize, Debug, Clone)]
+pub struct PollSummary {
+    pub timestamp: i64,
+    pub success: bool,
+    pub error: Option<String>,
+    pub consecutive_failures: u64,
+    pub daily_cost: Decimal,
+    pub monthly_cost: Decimal,
+    pub monthly_cost_delta: Option<Decimal>,
+    pub hourly_rate: Decimal,
+    pub deployments: usize,
+    pub organizations: Vec<OrganizationSummary>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OrganizationSummary {
+    pub org_id: Option<String>,
+    pub daily_cost: Decimal,
+    pub monthly_cost: Decimal,
+    pub monthly_cost_delta: Option<Decimal>,
+}
+
+impl PollSummary {
+    // Deltas are against the previous poll, and left out when it was in another month
+    pub fn success(snapshot: &Snapshot, previous: Option<&Snapshot>) -> Self {
+        let previous = previous.filter(|p| same_month(p, snapshot));
+
+        let organizations: Vec<OrganizationSummary> = snapshot
+            .organizations
+            .iter()
+            .map(|o| OrganizationSummary {
+                org_id: o.organization.id.clone(),
+                daily_cost: o.day.total_cost,
+                monthly_cost: o.month.total_cost,
+                monthly_cost_delta: previous
+                    .and_then(|p| p.organizations.iter().find(|po| po.organization.id == o.organization.id))
+                    .map(|po| o.month.total_cost - po.month.total_cost),
+            })
+            .collect();
+
+        let monthly_cost = organizations.iter().map(|o| o.monthly_cost).sum();
+        PollSummary {
+            timestamp: snapshot.timestamp,
+            success: true,
+            error: None,
+            consecutive_failures: 0,
+            daily_cost: organizations.iter().map(|o| o.daily_cost).sum(),
+            monthly_cost,
+            monthly_cost_delta: previous.map(|p| monthly_cost - p.organizations.iter().map(|o| o.month.total_cost).sum::<Decimal>()),
+            hourly_rate: snapshot.organizations.iter().flat_map(|o| &o.month.deployments).map(|d| d.hourly_rate).sum(),
+            deployments: snapshot.organizations.iter().map(|o| o.month.deployments.len()).sum(),
+            organizations,
+        }
+    }
+
+    pub fn failure(error: String, consecutive_failures: u64) -> Self {
+        PollSummary {
+            timestamp: Utc::now().timestamp(),
+            success: false,
+            error: Some(error),
+            consecutive_failures,
+            daily_cost: Decimal::ZERO,
+            monthly_cost: Decimal::ZERO,
+            monthly_cost_delta: None,
+            hourly_rate: Decimal::ZERO,
+            deployments: 0,
+            organizations: Vec::new(),
+        }
+    }
+}
+
+fn same_month(a: &Snapshot, b: &Snapshot) -> bool {
+    let (a, b) = (Utc.timestamp(a.timestamp, 0), Utc.timestamp(b.timestamp, 0));
+    (a.year(), a.month()) == (b.year(), b.month())
+}
+
+#[derive(Clone)]
+pub struct Webhook {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Webhook").field("url", &redact::url_str(&self.url)).finish()
+    }
+}
+
+impl Webhook {
+    pub fn new(url: &str, timeout: u64) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(Webhook {
+            url: url.to_string(),
+            client: reqwest::Client::builder().timeout(Duration::from_secs(timeout)).build()?,
+        })
+    }
+
+    pub fn redacted_url(&self) -> String {
+        redact::webhook(&self.url)
+    }
+
+    // Post in the background, so a slow webhook never holds up polling
+    pub fn send(&self, summary: PollSummary) {
+        let webhook = self.clone();
+        tokio::spawn(async move {
+            let result = webhook.client.post(&webhook.url).json(&summary).send().await.and_then(|r| r.error_for_status());
+            if let Err(e) = result.map_err(|e| e.without_url()) {
+                log::error!("{{\"fn\": \"send\", \"msg\": \"poll webhook failed\", \"error\": \"{}\"}}", e);
+                metrics::increment_counter!("elastic_billing_webhook_errors_total");
+            }
+        });
+    }
+}
@@ -0,0 +1,68 @@
+use metrics::Label;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::config::Config;
+use crate::sink::Sinks;
+use crate::snapshot::Snapshot;
+
+// Year to date spend, built from the month to date totals of every poll
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct YearToDate {
+    pub year: i32,
+    pub deployments: HashMap<String, DeploymentYearToDate>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeploymentYearToDate {
+    pub name: String,
+    pub month: u32,
+    pub month_to_date: Decimal,
+    pub completed: Decimal,
+}
+
+impl YearToDate {
+    // Fold a poll into the totals, the last month to date seen in a month counts as that month's total
+    pub fn update(&mut self, snapshot: &Snapshot, year: i32, month: u32) {
+        if self.year != year {
+            self.year = year;
+            self.deployments.clear();
+        }
+
+        for deployment in snapshot.organizations.iter().flat_map(|o| &o.month.deployments) {
+            let entry = self
+                .deployments
+                .entry(deployment.deployment_id.clone())
+                .or_insert_with(|| DeploymentYearToDate { month, ..Default::default() });
+
+            if entry.month != month {
+                entry.completed += entry.month_to_date;
+                entry.month = month;
+            }
+            entry.name = deployment.deployment_name.clone();
+            entry.month_to_date = deployment.costs.total;
+        }
+
+        // Deployments missing from this month still have their earlier months counted
+        for entry in self.deployments.values_mut() {
+            if entry.month != month {
+                entry.completed += entry.month_to_date;
+                entry.month_to_date = Decimal::ZERO;
+                entry.month = month;
+            }
+        }
+    }
+
+    pub fn record(&self, sinks: &Sinks, config: &Config) {
+        for (id, deployment) in &self.deployments {
+            let mut labels = vec![Label::new("id", id.clone())];
+            if !config.stable_ids {
+                labels.push(Label::new("name", config.label_name(id, &deployment.name)));
+            }
+            let total = deployment.completed + deployment.month_to_date;
+            log::debug!("Adding metric: elastic_billing_ytd_cost_total, labels: {:?}, value: {}", &labels, total);
+            sinks.gauge("elastic_billing_ytd_cost_total", config.cost(total), &labels);
+        }
+    }
+}